@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use elementtree::Element;
+use migrate_itunes_to_rhythmbox::fixture::{self, FixtureSize};
+use migrate_itunes_to_rhythmbox::itunes_library::{ItunesLibrary, ReadOptions};
+use migrate_itunes_to_rhythmbox::xml::{self, OriginalXml};
+use std::fs;
+
+fn bench_library(c: &mut Criterion) {
+    let size = FixtureSize::for_bench();
+    let dir = tempfile::tempdir().unwrap();
+    let library_path = dir.path().join(fixture::ITUNES_LIBRARY_FILENAME);
+    fs::write(&library_path, fixture::itunes_library_xml(&size)).unwrap();
+    let options = ReadOptions::default();
+    c.bench_function("parse library", |b| {
+        b.iter(|| ItunesLibrary::read(&library_path, &options).unwrap())
+    });
+
+    let mut rhythmdb_xml = Vec::new();
+    fixture::rhythmdb(&size)
+        .to_writer(&mut rhythmdb_xml)
+        .unwrap();
+    c.bench_function("parse database", |b| {
+        b.iter(|| Element::from_reader(&rhythmdb_xml[..]).unwrap())
+    });
+
+    let rhythmdb = Element::from_reader(&rhythmdb_xml[..]).unwrap();
+    c.bench_function("serialize database", |b| {
+        b.iter(|| {
+            let mut output = Vec::with_capacity(rhythmdb_xml.len());
+            rhythmdb.to_writer(&mut output).unwrap();
+            output
+        })
+    });
+
+    // Writing with the original text preserved only rewrites modified entries.
+    let text = String::from_utf8(rhythmdb_xml).unwrap();
+    let original = OriginalXml::new(text, &rhythmdb);
+    let path = dir.path().join("rhythmdb.xml");
+    c.bench_function("write database preserving", |b| {
+        b.iter(|| xml::write_xml_preserving(&path, &rhythmdb, &original).unwrap())
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_library
+}
+criterion_main!(benches);
@@ -0,0 +1,93 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use elementtree::Element;
+use migrate_itunes_to_rhythmbox::fixture::{self, FixtureSize};
+use migrate_itunes_to_rhythmbox::itunes_library::ItunesLibrary;
+use migrate_itunes_to_rhythmbox::matching::{self, DurationMismatchAction, MatchOptions};
+use migrate_itunes_to_rhythmbox::playlists::{self, PlaylistFilter};
+use migrate_itunes_to_rhythmbox::rhythmdb::{self, ConflictPolicy, Field, SyncOptions, SCHEMA};
+use migrate_itunes_to_rhythmbox::track_key::ArtistCanonicalizer;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+fn match_options() -> MatchOptions {
+    MatchOptions {
+        unknown_artists: vec!["未知".to_owned()],
+        sanitizer: None,
+        canonicalizer: ArtistCanonicalizer::default(),
+        repair_encoding: false,
+        manual_matches: HashMap::new(),
+        duration_tolerance: Some(5),
+        duration_mismatch: DurationMismatchAction::Warn,
+    }
+}
+
+fn bench_matching(c: &mut Criterion) {
+    let size = FixtureSize::for_bench();
+    let library: ItunesLibrary = plist::from_bytes(&fixture::itunes_library_xml(&size)).unwrap();
+    c.bench_function("build track map", |b| {
+        b.iter(|| matching::build_track_map(&library).unwrap())
+    });
+
+    let track_map = matching::build_track_map(&library).unwrap();
+    let rhythmdb = fixture::rhythmdb(&size);
+    let options = match_options();
+    let entries = rhythmdb::read_entries(&rhythmdb, &options).unwrap();
+    c.bench_function("match entries", |b| {
+        b.iter(|| matching::match_entries(&entries, &track_map))
+    });
+    c.bench_function("match database", |b| {
+        b.iter(|| rhythmdb::match_database(&rhythmdb, &track_map, &options).unwrap())
+    });
+
+    let database_match = rhythmdb::match_database(&rhythmdb, &track_map, &options).unwrap();
+    let schema = &SCHEMA;
+    let sync_options = SyncOptions {
+        fields: Field::DEFAULT.to_vec(),
+        loved_rating: Some(5),
+        disliked_rating: Some(1),
+        computed_ratings: false,
+        album_ratings: false,
+        hide_disabled: false,
+        conflict_policy: ConflictPolicy::Overwrite,
+    };
+    c.bench_function("sync to database", |b| {
+        b.iter_batched_ref(
+            || rhythmdb.clone(),
+            |rhythmdb| {
+                rhythmdb::sync_to_database(rhythmdb, &database_match.entries, schema, &sync_options)
+                    .unwrap()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    let track_locations = rhythmdb::track_locations(&database_match.entries);
+    let filter = PlaylistFilter {
+        include: Vec::new(),
+        exclude: Vec::new(),
+        exclude_tracks: HashSet::new(),
+        dedupe_items: None,
+        queue_playlist: None,
+    };
+    c.bench_function("migrate playlists", |b| {
+        b.iter_batched_ref(
+            || (Element::new("rhythmdb-playlists"), BTreeSet::new()),
+            |(playlists, migrated_playlists)| {
+                playlists::migrate_playlists(
+                    playlists,
+                    &library,
+                    &track_locations,
+                    &filter,
+                    migrated_playlists,
+                )
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_matching
+}
+criterion_main!(benches);
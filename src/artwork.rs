@@ -0,0 +1,243 @@
+use crate::location;
+use crate::rhythmdb::MatchedEntry;
+use crate::track_key::TrackKey;
+use crate::xml;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Names of image files Rhythmbox picks up as album art from the album directory.
+const COVER_NAMES: [&str; 4] = ["cover", "folder", "album", "front"];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ImageFormat {
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\xFF\xD8\xFF") {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1A\n") {
+            Some(ImageFormat::Png)
+        } else {
+            None
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// Write album art of matched tracks as cover images into the directories of their files,
+/// where Rhythmbox picks them up.
+///
+/// Art is taken from the iTunes `Album Artwork` cache when available,
+/// and extracted from the audio file otherwise.
+/// Directories which already contain a cover image are left untouched.
+pub fn write_cover_images(
+    matched_entries: &[MatchedEntry<'_>],
+    artwork_dirs: &[PathBuf],
+) -> Result<()> {
+    info!("Writing cover images...");
+    let mut cache = HashMap::new();
+    for dir in artwork_dirs {
+        index_artwork_cache(dir, &mut cache).context("failed to read iTunes artwork cache")?;
+    }
+    let mut visited_dirs = HashSet::new();
+    let mut written_count = 0;
+    for matched in matched_entries {
+        let path = match location::uri_to_path(&matched.location) {
+            Some(path) => path,
+            None => continue,
+        };
+        let dir = match path.parent() {
+            Some(dir) => dir.to_owned(),
+            None => continue,
+        };
+        if !visited_dirs.insert(dir.clone()) || has_cover(&dir) {
+            continue;
+        }
+        let key = TrackKey::from(matched.track);
+        let cached = matched
+            .track
+            .persistent_id
+            .as_ref()
+            .and_then(|id| cache.get(&id.to_uppercase()));
+        let image = match cached {
+            Some(itc_path) => fs::read(itc_path).ok().and_then(|data| read_itc(&data)),
+            None => None,
+        };
+        let image = match image.or_else(|| read_embedded(&path)) {
+            Some(image) => image,
+            None => {
+                debug!("no album art found for {}", key);
+                continue;
+            }
+        };
+        let format = match ImageFormat::detect(&image) {
+            Some(format) => format,
+            None => {
+                warn!("album art of {} is in unknown format", key);
+                continue;
+            }
+        };
+        let cover_path = dir.join(format!("cover.{}", format.extension()));
+        // A partially written cover would keep the directory from getting a complete one later.
+        let written = xml::write_atomically(&cover_path, |writer| {
+            writer.write_all(&image)?;
+            Ok(())
+        });
+        match written {
+            Ok(()) => {
+                debug!("wrote album art of {} to {}", key, cover_path.display());
+                written_count += 1;
+            }
+            Err(e) => warn!("failed to write {}: {:#}", cover_path.display(), e),
+        }
+    }
+    info!("{} album covers written", written_count);
+    Ok(())
+}
+
+fn has_cover(dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|entry| entry.ok()).any(|entry| {
+        let path = entry.path();
+        let stem = path.file_stem().and_then(|s| s.to_str());
+        let extension = path.extension().and_then(|s| s.to_str());
+        match (stem, extension) {
+            (Some(stem), Some(extension)) => {
+                COVER_NAMES.contains(&stem.to_lowercase().as_str())
+                    && ["jpg", "jpeg", "png"].contains(&extension.to_lowercase().as_str())
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Index `.itc` files in the iTunes `Album Artwork` directory by track persistent ID.
+///
+/// The files are named `<library persistent ID>-<track persistent ID>.itc`.
+fn index_artwork_cache(dir: &Path, index: &mut HashMap<String, PathBuf>) -> Result<()> {
+    let mut pending = vec![dir.to_owned()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            if path.extension().and_then(|s| s.to_str()) != Some("itc") {
+                continue;
+            }
+            let stem = path.file_stem().and_then(|s| s.to_str());
+            if let Some((_, track_id)) = stem.and_then(|stem| stem.split_once('-')) {
+                index.insert(track_id.to_uppercase(), path.clone());
+            }
+        }
+    }
+    debug!("{} artwork files found in iTunes cache", index.len());
+    Ok(())
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<usize> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// Extract the image from an iTunes `.itc` artwork file.
+///
+/// The file starts with an `itch` box, followed by `item` boxes,
+/// each of which has the offset of its image data right after its type.
+fn read_itc(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(4..8)? != b"itch" {
+        return None;
+    }
+    let mut pos = read_u32(data, 0)?;
+    while let Some(size) = read_u32(data, pos) {
+        let item = data.get(pos..pos + size)?;
+        if item.get(4..8)? == b"item" {
+            let offset = read_u32(item, 8)?;
+            let image = item.get(offset..)?;
+            if ImageFormat::detect(image).is_some() {
+                return Some(image.to_vec());
+            }
+        }
+        if size == 0 {
+            break;
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Extract the embedded album art from an audio file.
+fn read_embedded(path: &Path) -> Option<Vec<u8>> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "mp3" => {
+            let tag = id3::Tag::read_from_path(path).ok()?;
+            let mut pictures = tag.pictures().collect::<Vec<_>>();
+            // Prefer the front cover.
+            pictures
+                .sort_by_key(|picture| picture.picture_type != id3::frame::PictureType::CoverFront);
+            pictures.first().map(|picture| picture.data.clone())
+        }
+        "m4a" | "m4b" | "m4p" | "mp4" => {
+            let data = fs::read(path).ok()?;
+            read_mp4_cover(&data)
+        }
+        _ => None,
+    }
+}
+
+/// Find the `covr` atom in the `moov.udta.meta.ilst` path of an MP4 file.
+fn read_mp4_cover(data: &[u8]) -> Option<Vec<u8>> {
+    let moov = find_atom(data, b"moov")?;
+    let udta = find_atom(moov, b"udta")?;
+    // `meta` is a full atom, with 4 bytes of version and flags before children.
+    let meta = find_atom(udta, b"meta")?.get(4..)?;
+    let ilst = find_atom(meta, b"ilst")?;
+    let covr = find_atom(ilst, b"covr")?;
+    // `data` atom has 4 bytes of type and 4 bytes of locale before the content.
+    let content = find_atom(covr, b"data")?.get(8..)?;
+    Some(content.to_vec())
+}
+
+/// Find the content of the child atom with the given type.
+fn find_atom<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let (header, size) = match read_u32(data, pos)? {
+            // Extended 64-bit size.
+            1 => {
+                let high = read_u32(data, pos + 8)?;
+                let low = read_u32(data, pos + 12)?;
+                (16, (high << 32) | low)
+            }
+            // Atom extends to the end.
+            0 => (8, data.len() - pos),
+            size => (8, size),
+        };
+        if size < header {
+            return None;
+        }
+        if &data[pos + 4..pos + 8] == kind {
+            return data.get(pos + header..pos + size);
+        }
+        pos += size;
+    }
+    None
+}
@@ -0,0 +1,109 @@
+use crate::playlists::PLAYLISTS_FILENAME;
+use crate::rhythmdb::RHYTHMDB_FILENAME;
+use anyhow::{ensure, Result};
+use log::info;
+use std::fs;
+use std::path::Path;
+
+/// Rhythmbox file which gets backed up before being modified.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RhythmboxFile {
+    Database,
+    Playlists,
+}
+
+impl RhythmboxFile {
+    pub const ALL: [RhythmboxFile; 2] = [RhythmboxFile::Database, RhythmboxFile::Playlists];
+
+    pub fn filename(self) -> &'static str {
+        match self {
+            RhythmboxFile::Database => RHYTHMDB_FILENAME,
+            RhythmboxFile::Playlists => PLAYLISTS_FILENAME,
+        }
+    }
+
+    pub fn backup_filename(self) -> &'static str {
+        match self {
+            RhythmboxFile::Database => "rhythmdb.xml.bak",
+            RhythmboxFile::Playlists => "playlists.xml.bak",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            RhythmboxFile::Database => "database",
+            RhythmboxFile::Playlists => "playlists",
+        }
+    }
+
+    /// Content of the file without any song or playlist.
+    pub fn skeleton(self) -> &'static str {
+        match self {
+            RhythmboxFile::Database => {
+                "<?xml version=\"1.0\" standalone=\"yes\"?>\n<rhythmdb version=\"2.0\">\n</rhythmdb>\n"
+            }
+            RhythmboxFile::Playlists => {
+                "<?xml version=\"1.0\"?>\n<rhythmdb-playlists>\n</rhythmdb-playlists>\n"
+            }
+        }
+    }
+}
+
+/// Create Rhythmbox files which don't exist yet, e.g. on a fresh installation,
+/// without any song or playlist.
+pub fn create_missing_files(rhythmbox_path: &Path) -> Result<()> {
+    fs::create_dir_all(rhythmbox_path)?;
+    for file in RhythmboxFile::ALL.iter() {
+        let path = rhythmbox_path.join(file.filename());
+        if !path.exists() {
+            info!(
+                "Creating empty {} {}...",
+                file.description(),
+                path.display()
+            );
+            fs::write(&path, file.skeleton())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn backup_rhythmbox_files(rhythmbox_path: &Path, files: &[RhythmboxFile]) -> Result<()> {
+    info!("Backing up existing Rhythmbox files...");
+    for file in files {
+        let path = rhythmbox_path.join(file.filename());
+        let backup = rhythmbox_path.join(file.backup_filename());
+        ensure!(
+            path.exists(),
+            "{} doesn't exist: {}",
+            file.description(),
+            path.display(),
+        );
+        ensure!(
+            !backup.exists(),
+            "backup of {} already exists: {}",
+            file.description(),
+            backup.display(),
+        );
+        fs::copy(&path, &backup)?;
+    }
+    Ok(())
+}
+
+/// Restore Rhythmbox files from the existing backups, removing the backups.
+pub fn restore_rhythmbox_files(rhythmbox_path: &Path) -> Result<()> {
+    let files = RhythmboxFile::ALL
+        .iter()
+        .map(|file| {
+            let path = rhythmbox_path.join(file.filename());
+            let backup = rhythmbox_path.join(file.backup_filename());
+            (path, backup)
+        })
+        .filter(|(_, backup)| backup.exists())
+        .collect::<Vec<_>>();
+    ensure!(!files.is_empty(), "no backup exists to restore");
+    for (path, backup) in files.iter() {
+        info!("Restoring {}...", path.display());
+        fs::rename(backup, path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression format of a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Compression format implied by the extension of the path, e.g. `.gz` or `.zst`.
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    fn from_magic(magic: &[u8]) -> Self {
+        if magic.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Open the file for reading, decompressing it transparently if it's compressed.
+///
+/// Compression is detected from the magic bytes, so it doesn't depend on the extension.
+pub fn open(path: &Path) -> Result<(Compression, Box<dyn Read>)> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; 4];
+    let len = read_prefix(&mut file, &mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    let compression = Compression::from_magic(&magic[..len]);
+    let file = BufReader::new(file);
+    let reader: Box<dyn Read> = match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(GzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+    };
+    Ok((compression, reader))
+}
+
+fn read_prefix(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/// Create the file for writing, compressing it according to its extension.
+///
+/// The returned writer must be finished via `finish` to complete the compressed stream,
+/// and to put the file in place.
+pub fn create(path: &Path) -> Result<CompressedWriter> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let file = BufWriter::new(
+        File::create(&temp_path).with_context(|| format!("failed to create {}", path.display()))?,
+    );
+    let encoder = match Compression::from_extension(path) {
+        Compression::None => Encoder::None(file),
+        Compression::Gzip => Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default())),
+        Compression::Zstd => match zstd::Encoder::new(file, 0) {
+            Ok(encoder) => Encoder::Zstd(encoder),
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e.into());
+            }
+        },
+    };
+    Ok(CompressedWriter {
+        encoder: Some(encoder),
+        temp_path,
+        path: path.to_owned(),
+    })
+}
+
+/// Writer which compresses data written into a file.
+///
+/// Data is written into a temporary file next to the destination,
+/// which only replaces the destination when finished,
+/// and is removed if the writer is dropped before, e.g. on error,
+/// so that no partially written file is left.
+pub struct CompressedWriter {
+    /// The encoder, until finished.
+    encoder: Option<Encoder>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+enum Encoder {
+    None(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl CompressedWriter {
+    /// Complete the compressed stream, flush it into the file, and move it into place.
+    pub fn finish(mut self) -> io::Result<()> {
+        let encoder = self.encoder.take().unwrap();
+        let result = (|| {
+            let mut file = match encoder {
+                Encoder::None(file) => file,
+                Encoder::Gzip(encoder) => encoder.finish()?,
+                Encoder::Zstd(encoder) => encoder.finish()?,
+            };
+            file.flush()?;
+            fs::rename(&self.temp_path, &self.path)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+        result
+    }
+
+    fn encoder(&mut self) -> &mut dyn Write {
+        match self.encoder.as_mut().unwrap() {
+            Encoder::None(file) => file,
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Zstd(encoder) => encoder,
+        }
+    }
+}
+
+impl Drop for CompressedWriter {
+    fn drop(&mut self) {
+        // Not finished, e.g. on error.
+        if self.encoder.is_some() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"<?xml version=\"1.0\"?><plist/>";
+
+    fn read(path: &Path) -> (Compression, Vec<u8>) {
+        let (compression, mut reader) = open(path).unwrap();
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        (compression, content)
+    }
+
+    #[test]
+    fn detects_compression_from_magic() {
+        assert_eq!(
+            Compression::from_magic(&[0x1f, 0x8b, 0x08, 0x00]),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_magic(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::from_magic(b"<?xm"), Compression::None);
+        assert_eq!(Compression::from_magic(&[0x28, 0xb5]), Compression::None);
+        assert_eq!(Compression::from_magic(&[]), Compression::None);
+    }
+
+    #[test]
+    fn reads_files_written_compressed() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, expected) in [
+            ("library.xml", Compression::None),
+            ("library.xml.gz", Compression::Gzip),
+            ("library.xml.zst", Compression::Zstd),
+        ] {
+            let path = dir.path().join(name);
+            let mut writer = create(&path).unwrap();
+            writer.write_all(CONTENT).unwrap();
+            writer.finish().unwrap();
+            assert_eq!(read(&path), (expected, CONTENT.to_vec()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn detects_compression_regardless_of_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.xml.gz");
+        let mut writer = create(&path).unwrap();
+        writer.write_all(CONTENT).unwrap();
+        writer.finish().unwrap();
+        let renamed = dir.path().join("library.xml");
+        fs::rename(&path, &renamed).unwrap();
+        assert_eq!(read(&renamed), (Compression::Gzip, CONTENT.to_vec()));
+
+        let short = dir.path().join("short.xml");
+        fs::write(&short, b"<a").unwrap();
+        assert_eq!(read(&short), (Compression::None, b"<a".to_vec()));
+    }
+
+    #[test]
+    fn leaves_no_file_when_not_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.xml.gz");
+        let mut writer = create(&path).unwrap();
+        writer.write_all(CONTENT).unwrap();
+        drop(writer);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::Opt;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, CommandFactory};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+pub const CONFIG_FILENAME: &str = "migrate-itunes-to-rhythmbox.toml";
+
+/// Append options from the config file to the command line arguments,
+/// for those not given on the command line.
+///
+/// The config file is a TOML table keyed by long option names, e.g.
+///
+/// ```toml
+/// map-location = ["file://localhost/Users/me=file:///home/me"]
+/// merge = "max"
+/// ```
+///
+/// Options which don't apply to the subcommand are ignored.
+pub fn apply_config(mut args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let command = Opt::command();
+    let matches = command.clone().get_matches_from(&args);
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None => match dirs::config_dir().map(|dir| dir.join(CONFIG_FILENAME)) {
+            Some(path) if path.is_file() => path,
+            _ => return Ok(args),
+        },
+    };
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let config = content
+        .parse::<Table>()
+        .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+    let (subcommand_name, subcommand_matches) = match matches.subcommand() {
+        Some(subcommand) => subcommand,
+        None => return Ok(args),
+    };
+    let subcommand = command.find_subcommand(subcommand_name).unwrap();
+    for (key, value) in config.iter() {
+        let (arg, arg_matches) = match find_option(command.get_arguments(), key) {
+            Some(arg) => (arg, &matches),
+            None => match find_option(subcommand.get_arguments(), key) {
+                Some(arg) => (arg, subcommand_matches),
+                None if is_known_option(key) => continue,
+                None => bail!("unknown option {} in config file", key),
+            },
+        };
+        if given_on_command_line(arg_matches, arg) {
+            continue;
+        }
+        let values = scalar_values(value).ok_or_else(|| anyhow!("invalid value of {}", key))?;
+        if arg.get_action().takes_values() {
+            for value in values {
+                args.push(format!("--{}={}", key, value).into());
+            }
+            continue;
+        }
+        // Flags are given as booleans, or as numbers for repeatable flags like `verbose`.
+        let count = match value {
+            Value::Boolean(flag) => usize::from(*flag),
+            Value::Integer(count) => usize::try_from(*count)?,
+            _ => bail!("invalid value of {}", key),
+        };
+        for _ in 0..count {
+            args.push(format!("--{}", key).into());
+        }
+    }
+    Ok(args)
+}
+
+fn find_option<'a>(mut args: impl Iterator<Item = &'a Arg>, key: &str) -> Option<&'a Arg> {
+    args.find(|arg| arg.get_long() == Some(key) && !arg.is_positional())
+}
+
+fn given_on_command_line(matches: &ArgMatches, arg: &Arg) -> bool {
+    matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+}
+
+/// Whether the option is defined by any subcommand.
+fn is_known_option(key: &str) -> bool {
+    Opt::command()
+        .get_subcommands()
+        .any(|subcommand| find_option(subcommand.get_arguments(), key).is_some())
+}
+
+fn scalar_values(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(s) => Some(vec![s.clone()]),
+        Value::Integer(n) => Some(vec![n.to_string()]),
+        Value::Float(n) => Some(vec![n.to_string()]),
+        Value::Boolean(b) => Some(vec![b.to_string()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match scalar_values(value)?.as_slice() {
+                [value] => Some(value.clone()),
+                _ => None,
+            })
+            .collect(),
+        Value::Datetime(_) | Value::Table(_) => None,
+    }
+}
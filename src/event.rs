@@ -0,0 +1,213 @@
+use crate::track_key::TrackKey;
+use log::Level;
+use serde::Serialize;
+use std::fmt;
+
+/// Notable event happening during migration.
+///
+/// Events are logged as human-readable messages,
+/// and carry structured data for machine-readable output.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    SongSkipped {
+        #[serde(flatten)]
+        song: TrackKey<'a>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        persistent_id: Option<&'a str>,
+        reason: SkipReason,
+    },
+    SongNotFound {
+        #[serde(flatten)]
+        song: TrackKey<'a>,
+    },
+    SongUnused {
+        #[serde(flatten)]
+        song: TrackKey<'a>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        persistent_id: Option<&'a str>,
+    },
+    DurationMismatch {
+        #[serde(flatten)]
+        song: TrackKey<'a>,
+        /// Duration in Rhythmbox in seconds.
+        rhythmbox: u64,
+        /// Duration in iTunes in seconds.
+        itunes: u64,
+    },
+    FileMissing {
+        #[serde(flatten)]
+        song: TrackKey<'a>,
+        location: &'a str,
+    },
+    FieldOverridden {
+        #[serde(flatten)]
+        song: TrackKey<'a>,
+        field: &'a str,
+        old: &'a str,
+        new: &'a str,
+    },
+    FieldMismatch {
+        #[serde(flatten)]
+        song: TrackKey<'a>,
+        field: &'a str,
+        expected: &'a str,
+        actual: Option<&'a str>,
+    },
+    PlaylistSkipped {
+        playlist: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        persistent_id: Option<&'a str>,
+        reason: SkipReason,
+    },
+    PlaylistItemsNotFound {
+        playlist: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        persistent_id: Option<&'a str>,
+        count: usize,
+    },
+    PlaylistItemsMissingFromLibrary {
+        playlist: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        persistent_id: Option<&'a str>,
+        count: usize,
+    },
+    PlaylistItemsDeduplicated {
+        playlist: &'a str,
+        count: usize,
+    },
+    PlaylistMissing {
+        playlist: &'a str,
+    },
+    PlaylistItemCountMismatch {
+        playlist: &'a str,
+        expected: usize,
+        actual: usize,
+    },
+    DanglingLocation {
+        playlist: &'a str,
+        location: &'a str,
+    },
+    RecordSkipped {
+        record: &'a str,
+        error: &'a str,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    CloudOnly,
+    Url,
+    NoLocation,
+    Disabled,
+    Protected,
+    Smart,
+}
+
+impl Event<'_> {
+    /// Log the event with the given level.
+    pub fn log(&self, level: Level) {
+        log::log!(level, event:serde = self; "{}", self);
+    }
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Event::SongSkipped { song, reason, .. } => {
+                write!(f, "song {} is skipped because {}", song, reason)
+            }
+            Event::SongNotFound { song } => write!(f, "song {} not found", song),
+            Event::SongUnused { song, .. } => write!(f, "song {} unused", song),
+            Event::DurationMismatch {
+                song,
+                rhythmbox,
+                itunes,
+            } => write!(
+                f,
+                "song {} lasts {}s in Rhythmbox but {}s in iTunes",
+                song, rhythmbox, itunes,
+            ),
+            Event::FileMissing { song, location } => {
+                write!(f, "file of song {} is missing: {}", song, location)
+            }
+            Event::FieldOverridden {
+                song,
+                field,
+                old,
+                new,
+            } => write!(f, "overriding {} of {}: {} -> {}", field, song, old, new),
+            Event::FieldMismatch {
+                song,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} of {} is {}, but expected {}",
+                field,
+                song,
+                actual.unwrap_or("missing"),
+                expected,
+            ),
+            Event::PlaylistSkipped {
+                playlist, reason, ..
+            } => {
+                write!(f, "playlist {} is skipped because {}", playlist, reason)
+            }
+            Event::PlaylistItemsNotFound {
+                playlist, count, ..
+            } => {
+                write!(
+                    f,
+                    "{} items in playlist {} are not found in Rhythmbox",
+                    count, playlist
+                )
+            }
+            Event::PlaylistItemsMissingFromLibrary {
+                playlist, count, ..
+            } => write!(
+                f,
+                "{} items in playlist {} reference tracks missing from the iTunes library",
+                count, playlist,
+            ),
+            Event::PlaylistItemsDeduplicated { playlist, count } => write!(
+                f,
+                "{} duplicate items are removed from playlist {}",
+                count, playlist,
+            ),
+            Event::PlaylistMissing { playlist } => write!(f, "playlist {} is missing", playlist),
+            Event::PlaylistItemCountMismatch {
+                playlist,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "playlist {} has {} items, but expected {}",
+                playlist, actual, expected,
+            ),
+            Event::DanglingLocation { playlist, location } => write!(
+                f,
+                "playlist {} references {} which is not in the database",
+                playlist, location,
+            ),
+            Event::RecordSkipped { record, error } => {
+                write!(f, "{} is skipped because it's malformed: {}", record, error)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str(match self {
+            SkipReason::CloudOnly => "it's only in the cloud",
+            SkipReason::Url => "it's a URL track",
+            SkipReason::NoLocation => "it has no location",
+            SkipReason::Disabled => "it's disabled",
+            SkipReason::Protected => "it's DRM-protected",
+            SkipReason::Smart => "it's smart",
+        })
+    }
+}
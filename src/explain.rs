@@ -0,0 +1,365 @@
+use crate::itunes_library::Track;
+use crate::matching::{self, EntryMetadata, MatchOptions, TrackMap};
+use crate::track_key::{self, SanitizedKey, TrackKey};
+use std::fmt;
+
+/// Explanation of how an iTunes track matches, or fails to match, Rhythmbox songs.
+#[derive(Debug)]
+pub struct Explanation<'a> {
+    pub track: &'a Track,
+    /// Keys of the track after each normalization enabled.
+    pub normalized_keys: Vec<(MatchTier, SanitizedKey)>,
+    /// Rhythmbox songs most similar to the track, the most similar first.
+    pub candidates: Vec<Candidate>,
+}
+
+/// A Rhythmbox song considered for matching an iTunes track.
+#[derive(Debug)]
+pub struct Candidate {
+    pub entry: EntryMetadata,
+    /// Fields differing between the song and the track.
+    pub diffs: Vec<FieldDiff>,
+    /// Outcome of each matching tier, in the order they are tried.
+    pub tiers: Vec<(MatchTier, TierOutcome)>,
+    /// Durations in Rhythmbox and iTunes in seconds, if they differ beyond the tolerance.
+    pub duration_mismatch: Option<(u64, u64)>,
+}
+
+#[derive(Debug)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub rhythmbox: String,
+    pub itunes: String,
+}
+
+/// A tier of matching Rhythmbox songs with iTunes tracks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MatchTier {
+    Exact,
+    Work,
+    Sanitized,
+    Canonical,
+    Encoding,
+    Manual,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TierOutcome {
+    Matched,
+    /// The normalized key is shared by multiple iTunes tracks.
+    Ambiguous,
+    Failed,
+    /// The tier is not enabled by the options.
+    Disabled,
+}
+
+/// Explain the matching of the iTunes track with songs in Rhythmbox,
+/// listing at most `limit` candidates.
+pub fn explain<'a>(
+    track: &'a Track,
+    entries: &[EntryMetadata],
+    track_map: &TrackMap<'a>,
+    options: &MatchOptions,
+    limit: usize,
+) -> Explanation<'a> {
+    let key = TrackKey::from(track);
+    let mut normalized_keys = Vec::new();
+    if let Some(sanitizer) = &options.sanitizer {
+        normalized_keys.push((MatchTier::Sanitized, sanitizer.key(&key)));
+    }
+    normalized_keys.push((MatchTier::Canonical, options.canonicalizer.key(&key)));
+    if options.repair_encoding {
+        normalized_keys.push((MatchTier::Encoding, track_key::repaired_key(&key)));
+    }
+
+    let mut scored = entries
+        .iter()
+        .map(|entry| (similarity(&entry.key(), &key), entry))
+        .filter(|&(score, _)| score > 0)
+        .collect::<Vec<_>>();
+    scored.sort_by(|(a, a_entry), (b, b_entry)| {
+        b.cmp(a)
+            .then_with(|| a_entry.location.cmp(&b_entry.location))
+    });
+    let candidates = scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, entry)| Candidate {
+            entry: entry.clone(),
+            diffs: diff_fields(&entry.key(), &key),
+            tiers: MatchTier::ALL
+                .iter()
+                .map(|&tier| (tier, tier.outcome(entry, track, track_map, options)))
+                .collect(),
+            duration_mismatch: duration_mismatch(entry, track, options),
+        })
+        .collect();
+    Explanation {
+        track,
+        normalized_keys,
+        candidates,
+    }
+}
+
+impl MatchTier {
+    const ALL: [MatchTier; 6] = [
+        MatchTier::Exact,
+        MatchTier::Work,
+        MatchTier::Sanitized,
+        MatchTier::Canonical,
+        MatchTier::Encoding,
+        MatchTier::Manual,
+    ];
+
+    fn outcome(
+        self,
+        entry: &EntryMetadata,
+        track: &Track,
+        track_map: &TrackMap<'_>,
+        options: &MatchOptions,
+    ) -> TierOutcome {
+        let key = entry.key();
+        match self {
+            MatchTier::Exact => TierOutcome::from_matches(key == TrackKey::from(track), 1),
+            MatchTier::Work => {
+                let count = track_map
+                    .values()
+                    .filter(|&&other| {
+                        matching::work_names(other).iter().any(|name| {
+                            key == TrackKey {
+                                name,
+                                ..TrackKey::from(other)
+                            }
+                        })
+                    })
+                    .count();
+                let matches = matching::work_names(track).iter().any(|name| {
+                    key == TrackKey {
+                        name,
+                        ..TrackKey::from(track)
+                    }
+                });
+                TierOutcome::from_matches(matches, count)
+            }
+            MatchTier::Sanitized => match &options.sanitizer {
+                Some(sanitizer) => {
+                    normalized_outcome(&key, track, track_map, |key| sanitizer.key(key))
+                }
+                None => TierOutcome::Disabled,
+            },
+            MatchTier::Canonical => {
+                normalized_outcome(&key, track, track_map, |key| options.canonicalizer.key(key))
+            }
+            MatchTier::Encoding if options.repair_encoding => {
+                normalized_outcome(&key, track, track_map, track_key::repaired_key)
+            }
+            MatchTier::Encoding => TierOutcome::Disabled,
+            MatchTier::Manual => match options.manual_matches.get(&entry.location) {
+                Some(persistent_id) => TierOutcome::from_matches(
+                    track.persistent_id.as_ref() == Some(persistent_id),
+                    1,
+                ),
+                None if options.manual_matches.is_empty() => TierOutcome::Disabled,
+                None => TierOutcome::Failed,
+            },
+        }
+    }
+}
+
+impl TierOutcome {
+    /// Outcome of a tier given whether the track matches,
+    /// and how many iTunes tracks match the same song in the tier.
+    fn from_matches(matches: bool, count: usize) -> Self {
+        match (matches, count) {
+            (false, _) => TierOutcome::Failed,
+            (true, 0..=1) => TierOutcome::Matched,
+            (true, _) => TierOutcome::Ambiguous,
+        }
+    }
+}
+
+fn normalized_outcome(
+    key: &TrackKey<'_>,
+    track: &Track,
+    track_map: &TrackMap<'_>,
+    normalize: impl Fn(&TrackKey<'_>) -> SanitizedKey,
+) -> TierOutcome {
+    let normalized = normalize(key);
+    let count = track_map
+        .keys()
+        .filter(|other| normalize(other) == normalized)
+        .count();
+    TierOutcome::from_matches(normalize(&TrackKey::from(track)) == normalized, count)
+}
+
+fn duration_mismatch(
+    entry: &EntryMetadata,
+    track: &Track,
+    options: &MatchOptions,
+) -> Option<(u64, u64)> {
+    let tolerance = options.duration_tolerance?;
+    let rhythmbox = entry.duration?;
+    let itunes = (track.total_time? + 500) / 1000;
+    Some((rhythmbox, itunes)).filter(|_| rhythmbox.abs_diff(itunes) > tolerance)
+}
+
+/// Similarity of two keys, where 0 means nothing in common.
+///
+/// Names count more than numbers, and equal names more than names differing in case.
+fn similarity(a: &TrackKey<'_>, b: &TrackKey<'_>) -> usize {
+    let text = |a: Option<&str>, b: Option<&str>| match (a, b) {
+        (Some(a), Some(b)) if a == b => 4,
+        (Some(a), Some(b)) => {
+            let (a, b) = (a.trim().to_lowercase(), b.trim().to_lowercase());
+            if a == b {
+                3
+            } else if !a.is_empty() && !b.is_empty() && (a.contains(&b) || b.contains(&a)) {
+                1
+            } else {
+                0
+            }
+        }
+        _ => 0,
+    };
+    let title = text(Some(a.name), Some(b.name));
+    let artist = text(a.artist, b.artist);
+    let album = text(a.album, b.album);
+    if title + artist + album == 0 {
+        return 0;
+    }
+    let number = |a: Option<usize>, b: Option<usize>| usize::from(a.is_some() && a == b);
+    title * 2
+        + artist
+        + album
+        + number(a.disc_number, b.disc_number)
+        + number(a.track_number, b.track_number)
+}
+
+fn diff_fields(rhythmbox: &TrackKey<'_>, itunes: &TrackKey<'_>) -> Vec<FieldDiff> {
+    let text = |value: Option<&str>| match value {
+        Some(value) => format!("{:?}", value),
+        None => "none".to_owned(),
+    };
+    let number = |value: Option<usize>| match value {
+        Some(value) => value.to_string(),
+        None => "none".to_owned(),
+    };
+    let fields = vec![
+        ("title", text(Some(rhythmbox.name)), text(Some(itunes.name))),
+        ("artist", text(rhythmbox.artist), text(itunes.artist)),
+        ("album", text(rhythmbox.album), text(itunes.album)),
+        (
+            "disc number",
+            number(rhythmbox.disc_number),
+            number(itunes.disc_number),
+        ),
+        (
+            "track number",
+            number(rhythmbox.track_number),
+            number(itunes.track_number),
+        ),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, rhythmbox, itunes)| rhythmbox != itunes)
+        .map(|(field, rhythmbox, itunes)| FieldDiff {
+            field,
+            rhythmbox,
+            itunes,
+        })
+        .collect()
+}
+
+impl fmt::Display for MatchTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str(match self {
+            MatchTier::Exact => "exact",
+            MatchTier::Work => "work",
+            MatchTier::Sanitized => "sanitized",
+            MatchTier::Canonical => "canonical",
+            MatchTier::Encoding => "encoding",
+            MatchTier::Manual => "manual",
+        })
+    }
+}
+
+impl fmt::Display for TierOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str(match self {
+            TierOutcome::Matched => "matched",
+            TierOutcome::Ambiguous => "ambiguous",
+            TierOutcome::Failed => "failed",
+            TierOutcome::Disabled => "disabled",
+        })
+    }
+}
+
+impl fmt::Display for Explanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let key = TrackKey::from(self.track);
+        writeln!(f, "iTunes track {}", key)?;
+        if let Some(persistent_id) = &self.track.persistent_id {
+            writeln!(f, "  persistent ID:  {}", persistent_id)?;
+        }
+        write_key(f, "key", &key)?;
+        for (tier, normalized) in self.normalized_keys.iter() {
+            let key = TrackKey {
+                name: &normalized.name,
+                artist: normalized.artist.as_deref(),
+                album: normalized.album.as_deref(),
+                disc_number: normalized.disc_number,
+                track_number: normalized.track_number,
+            };
+            write_key(f, &tier.to_string(), &key)?;
+        }
+        if self.candidates.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "No similar song in Rhythmbox")?;
+        }
+        for candidate in self.candidates.iter() {
+            writeln!(f)?;
+            writeln!(f, "Rhythmbox song {}", candidate.entry.location)?;
+            for diff in candidate.diffs.iter() {
+                writeln!(
+                    f,
+                    "  {:<14}  {} (iTunes: {})",
+                    format!("{}:", diff.field),
+                    diff.rhythmbox,
+                    diff.itunes,
+                )?;
+            }
+            if let Some((rhythmbox, itunes)) = candidate.duration_mismatch {
+                writeln!(
+                    f,
+                    "  {:<14}  {}s (iTunes: {}s)",
+                    "duration:", rhythmbox, itunes
+                )?;
+            }
+            let tiers = candidate
+                .tiers
+                .iter()
+                .map(|(tier, outcome)| format!("{} {}", tier, outcome))
+                .collect::<Vec<_>>();
+            writeln!(f, "  {:<14}  {}", "tiers:", tiers.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_key(
+    f: &mut fmt::Formatter<'_>,
+    label: &str,
+    key: &TrackKey<'_>,
+) -> Result<(), fmt::Error> {
+    let number = |n: Option<usize>| n.map_or_else(|| "none".to_owned(), |n| n.to_string());
+    writeln!(
+        f,
+        "  {:<14}  title {:?}, artist {:?}, album {:?}, disc {}, track {}",
+        format!("{}:", label),
+        key.name,
+        key.artist.unwrap_or_default(),
+        key.album.unwrap_or_default(),
+        number(key.disc_number),
+        number(key.track_number),
+    )
+}
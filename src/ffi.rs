@@ -0,0 +1,214 @@
+//! C-compatible interface for running the migration from other languages,
+//! e.g. from Python via `ctypes`.
+//!
+//! Options and results are passed as JSON strings,
+//! so that they can evolve without breaking the interface.
+
+use crate::itunes_library::{self, ReadOptions};
+use crate::matching::{DurationMismatchAction, MatchOptions};
+use crate::migration::{self, DisabledAction, DislikedAction, MigrationOptions};
+use crate::missing_files::CopyMode;
+use crate::playlists::PlaylistFilter;
+use crate::report::MigrationSummary;
+use crate::rhythmdb::{ConflictPolicy, SyncOptions};
+use crate::track_key::{ArtistCanonicalizer, Sanitizer};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic;
+use std::path::Path;
+use std::time::Instant;
+
+/// Options of the migration, mirroring the command line options of the same names.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MigrateOptions {
+    pub fields: Vec<String>,
+    pub conflict_policy: String,
+    pub loved_rating: u8,
+    pub disliked: String,
+    pub disabled: String,
+    pub include_videos: bool,
+    pub include_protected: bool,
+    pub skip_db: bool,
+    pub skip_playlists: bool,
+    pub strict: bool,
+    pub sanitize: bool,
+    pub fix_encoding: bool,
+    pub unknown_artist: Vec<String>,
+    pub duration_tolerance: u64,
+    pub assume_compatible: bool,
+    /// Whether to back up Rhythmbox files before modifying them.
+    pub backup: bool,
+}
+
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        MigrateOptions {
+            fields: vec![
+                "first-seen".to_owned(),
+                "last-played".to_owned(),
+                "play-count".to_owned(),
+            ],
+            conflict_policy: "overwrite".to_owned(),
+            loved_rating: 5,
+            disliked: "rating".to_owned(),
+            disabled: "keep".to_owned(),
+            include_videos: false,
+            include_protected: false,
+            skip_db: false,
+            skip_playlists: false,
+            strict: false,
+            sanitize: false,
+            fix_encoding: false,
+            unknown_artist: vec!["未知".to_owned()],
+            duration_tolerance: 5,
+            assume_compatible: false,
+            backup: true,
+        }
+    }
+}
+
+/// Migrate the iTunes library into Rhythmbox like the `migrate` command,
+/// without any interactive prompt.
+pub fn migrate(
+    itunes_path: &Path,
+    rhythmbox_path: &Path,
+    options: &MigrateOptions,
+) -> Result<MigrationSummary> {
+    let disliked: DislikedAction = parse_value(&options.disliked)?;
+    let disabled: DisabledAction = parse_value(&options.disabled)?;
+    ensure!(
+        options.loved_rating <= 5,
+        "invalid loved rating {}",
+        options.loved_rating,
+    );
+    let mut summary = MigrationSummary::default();
+    let start = Instant::now();
+    let read_options = ReadOptions {
+        strict: options.strict,
+        include_videos: options.include_videos,
+        skip_disabled: disabled == DisabledAction::Skip,
+        ..ReadOptions::default()
+    };
+    let itunes_library =
+        itunes_library::read_libraries(&[itunes_path.to_owned()], &[], &read_options)?;
+    summary.record_phase("reading iTunes", start);
+    let match_options = MatchOptions {
+        unknown_artists: options.unknown_artist.clone(),
+        sanitizer: Some(Sanitizer::default()).filter(|_| options.sanitize),
+        canonicalizer: ArtistCanonicalizer::default(),
+        repair_encoding: options.fix_encoding,
+        manual_matches: HashMap::new(),
+        duration_tolerance: Some(options.duration_tolerance).filter(|&t| t > 0),
+        duration_mismatch: DurationMismatchAction::Warn,
+    };
+    let sync_options = SyncOptions {
+        fields: options
+            .fields
+            .iter()
+            .map(|field| parse_value(field))
+            .collect::<Result<_>>()?,
+        loved_rating: Some(options.loved_rating).filter(|&rating| rating > 0),
+        disliked_rating: disliked.rating(),
+        computed_ratings: false,
+        album_ratings: false,
+        hide_disabled: disabled == DisabledAction::Hide,
+        conflict_policy: match parse_value(&options.conflict_policy)? {
+            ConflictPolicy::Prompt => bail!("conflict policy prompt is unsupported"),
+            policy => policy,
+        },
+    };
+    let migration_options = MigrationOptions {
+        match_options,
+        sync_options,
+        disliked,
+        disabled,
+        playlist_filter: PlaylistFilter::default(),
+        exclude_protected: !options.include_protected,
+        skip_db: options.skip_db,
+        skip_playlists: options.skip_playlists,
+        incremental: false,
+        backup: options.backup,
+        check_running: true,
+        assume_compatible: options.assume_compatible,
+        output_dir: None,
+        prune_db: None,
+        copy_missing_files: None,
+        copy_mode: CopyMode::Copy,
+        location_mappings: Vec::new(),
+        check_files: None,
+        trim_script: None,
+        write_cover_images: None,
+    };
+    migration::migrate(
+        &itunes_library,
+        rhythmbox_path,
+        &migration_options,
+        |_, _| Ok(None),
+        &mut summary,
+    )?;
+    Ok(summary)
+}
+
+fn parse_value<T: ValueEnum>(value: &str) -> Result<T> {
+    T::from_str(value, false).map_err(|_| anyhow!("invalid value {}", value))
+}
+
+/// Migrate the iTunes library at `itunes_path` into the Rhythmbox data directory at `rhythmbox_path`.
+///
+/// `options` is a JSON object of [`MigrateOptions`], or null for the defaults.
+/// The result is a JSON object with either a `summary` of the migration or an `error` message,
+/// which must be freed with [`itunes_to_rhythmbox_free`].
+///
+/// # Safety
+///
+/// Arguments must be NUL-terminated UTF-8 strings, except that `options` may be null.
+#[no_mangle]
+pub unsafe extern "C" fn itunes_to_rhythmbox_migrate(
+    itunes_path: *const c_char,
+    rhythmbox_path: *const c_char,
+    options: *const c_char,
+) -> *mut c_char {
+    let to_str = |s: *const c_char| CStr::from_ptr(s).to_str().context("invalid UTF-8");
+    let (itunes_path, rhythmbox_path) = (to_str(itunes_path), to_str(rhythmbox_path));
+    let options = if options.is_null() {
+        Ok(None)
+    } else {
+        to_str(options).map(Some)
+    };
+    let result = panic::catch_unwind(|| {
+        let options = match options? {
+            Some(options) => serde_json::from_str(options).context("invalid options")?,
+            None => MigrateOptions::default(),
+        };
+        migrate(
+            Path::new(itunes_path?),
+            Path::new(rhythmbox_path?),
+            &options,
+        )
+    });
+    let response = match result {
+        Ok(Ok(summary)) => json!({ "summary": summary }),
+        Ok(Err(e)) => json!({ "error": format!("{:#}", e) }),
+        Err(_) => json!({ "error": "migration panicked" }),
+    };
+    // Control characters are escaped in JSON, so there is no NUL in the middle.
+    CString::new(response.to_string()).unwrap().into_raw()
+}
+
+/// Free a string returned by [`itunes_to_rhythmbox_migrate`].
+///
+/// # Safety
+///
+/// The string must have been returned by this library, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn itunes_to_rhythmbox_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
@@ -0,0 +1,164 @@
+//! Synthetic libraries of configurable sizes, for evaluating performance with huge libraries.
+
+use crate::backup::RhythmboxFile;
+use crate::xml::write_xml;
+use anyhow::{Context, Result};
+use elementtree::Element;
+use log::info;
+use plist::{Date, Dictionary, Value};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Name of the iTunes Library file, as iTunes names it.
+pub const ITUNES_LIBRARY_FILENAME: &str = "iTunes Music Library.xml";
+
+/// Sizes of a synthetic library.
+#[derive(Clone, Copy, Debug)]
+pub struct FixtureSize {
+    pub track_count: usize,
+    pub playlist_count: usize,
+    /// Number of items in each playlist.
+    pub playlist_length: usize,
+}
+
+impl FixtureSize {
+    /// Size with the given number of tracks, and a playlist of 100 items per 100 tracks.
+    pub fn with_tracks(track_count: usize) -> Self {
+        FixtureSize {
+            track_count,
+            playlist_count: track_count / 100,
+            playlist_length: 100.min(track_count),
+        }
+    }
+
+    /// Size for benchmarks, with 100,000 tracks unless `BENCH_TRACK_COUNT` says otherwise.
+    pub fn for_bench() -> Self {
+        let track_count = env::var("BENCH_TRACK_COUNT")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(100_000);
+        FixtureSize::with_tracks(track_count)
+    }
+}
+
+fn track_dict(id: usize) -> Dictionary {
+    let mut track = Dictionary::new();
+    let integer = |value: usize| Value::Integer((value as u64).into());
+    track.insert("Track ID".into(), integer(id));
+    track.insert(
+        "Persistent ID".into(),
+        Value::String(format!("{:016X}", id)),
+    );
+    track.insert("Name".into(), Value::String(format!("Song {}", id)));
+    track.insert(
+        "Artist".into(),
+        Value::String(format!("Artist {}", id % 1000)),
+    );
+    track.insert(
+        "Album".into(),
+        Value::String(format!("Album {}", id % 10000)),
+    );
+    track.insert("Track Number".into(), integer(id % 10 + 1));
+    track.insert("Total Time".into(), integer(180_000 + id % 120 * 1000));
+    // 2019-10-01T00:00:00Z
+    let date = Value::Date(Date::from(UNIX_EPOCH + Duration::from_secs(1_569_888_000)));
+    track.insert("Date Modified".into(), date.clone());
+    track.insert("Date Added".into(), date.clone());
+    track.insert("Play Count".into(), integer(id % 50));
+    track.insert("Play Date UTC".into(), date);
+    track.insert("Rating".into(), integer(id % 6 * 20));
+    track.insert(
+        "Location".into(),
+        Value::String(format!("file:///Music/{}.mp3", id)),
+    );
+    track
+}
+
+fn playlist_dict(id: usize, size: &FixtureSize) -> Dictionary {
+    let mut playlist = Dictionary::new();
+    playlist.insert("Name".into(), Value::String(format!("Playlist {}", id)));
+    playlist.insert("Playlist ID".into(), Value::Integer((id as u64).into()));
+    let items = (0..size.playlist_length)
+        .map(|i| {
+            let mut item = Dictionary::new();
+            let track_id = (id * size.playlist_length + i * 7) % size.track_count;
+            item.insert("Track ID".into(), Value::Integer((track_id as u64).into()));
+            Value::Dictionary(item)
+        })
+        .collect();
+    playlist.insert("Playlist Items".into(), Value::Array(items));
+    playlist
+}
+
+/// Generate an iTunes library in XML format.
+pub fn itunes_library_xml(size: &FixtureSize) -> Vec<u8> {
+    let mut tracks = Dictionary::new();
+    for id in 0..size.track_count {
+        tracks.insert(id.to_string(), Value::Dictionary(track_dict(id)));
+    }
+    let playlists = (0..size.playlist_count)
+        .map(|id| Value::Dictionary(playlist_dict(id, size)))
+        .collect();
+    let mut library = Dictionary::new();
+    library.insert("Tracks".into(), Value::Dictionary(tracks));
+    library.insert("Playlists".into(), Value::Array(playlists));
+    let mut xml = Vec::new();
+    Value::Dictionary(library).to_writer_xml(&mut xml).unwrap();
+    xml
+}
+
+/// Generate a Rhythmbox database with a song for each track of the synthetic iTunes library,
+/// only half of which match, the others having different titles.
+pub fn rhythmdb(size: &FixtureSize) -> Element {
+    let mut rhythmdb = Element::new("rhythmdb");
+    rhythmdb.set_attr("version", "2.0");
+    for id in 0..size.track_count {
+        let title = if id % 2 == 0 {
+            format!("Song {}", id)
+        } else {
+            format!("Other Song {}", id)
+        };
+        let fields = vec![
+            ("title", title),
+            ("artist", format!("Artist {}", id % 1000)),
+            ("album", format!("Album {}", id % 10000)),
+            ("track-number", (id % 10 + 1).to_string()),
+            ("duration", (180 + id % 120).to_string()),
+            ("location", format!("file:///home/user/Music/{}.mp3", id)),
+        ];
+        let entry = rhythmdb.append_new_child("entry");
+        entry.set_attr("type", "song");
+        for (tag, text) in fields {
+            entry.append_new_child(tag).set_text(text);
+        }
+    }
+    rhythmdb
+}
+
+/// Write a synthetic iTunes Library file into the directory,
+/// along with a Rhythmbox data directory `rhythmbox` having the synthetic database.
+pub fn write_fixture(dir: &Path, size: &FixtureSize) -> Result<()> {
+    info!(
+        "Generating {} tracks and {} playlists into {}...",
+        size.track_count,
+        size.playlist_count,
+        dir.display(),
+    );
+    let rhythmbox_path = dir.join("rhythmbox");
+    fs::create_dir_all(&rhythmbox_path)
+        .with_context(|| format!("failed to create {}", rhythmbox_path.display()))?;
+    fs::write(dir.join(ITUNES_LIBRARY_FILENAME), itunes_library_xml(size))
+        .context("failed to write iTunes library")?;
+    let database = RhythmboxFile::Database;
+    write_xml(&rhythmbox_path.join(database.filename()), &rhythmdb(size))
+        .context("failed to write database")?;
+    let playlists = RhythmboxFile::Playlists;
+    fs::write(
+        rhythmbox_path.join(playlists.filename()),
+        playlists.skeleton(),
+    )
+    .context("failed to write playlists")?;
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// Kind of Rhythmbox installation, which determines where its data lives.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Installation {
+    Native,
+    Flatpak,
+    Snap,
+}
+
+impl Installation {
+    const ALL: [Installation; 3] = [
+        Installation::Native,
+        Installation::Flatpak,
+        Installation::Snap,
+    ];
+
+    /// Path of the Rhythmbox data directory for this kind of installation.
+    pub fn data_path(self) -> Option<PathBuf> {
+        let mut path = match self {
+            Installation::Native => dirs::data_dir()?,
+            Installation::Flatpak => {
+                let mut path = dirs::home_dir()?;
+                path.push(".var/app/org.gnome.Rhythmbox3/data");
+                path
+            }
+            Installation::Snap => {
+                let mut path = dirs::home_dir()?;
+                path.push("snap/rhythmbox/current/.local/share");
+                path
+            }
+        };
+        path.push("rhythmbox");
+        Some(path)
+    }
+}
+
+impl fmt::Display for Installation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str(match self {
+            Installation::Native => "native",
+            Installation::Flatpak => "Flatpak",
+            Installation::Snap => "Snap",
+        })
+    }
+}
+
+/// Detect the Rhythmbox installation in use.
+///
+/// An installation whose data directory contains a database is preferred,
+/// then one whose data directory exists, and the native one otherwise.
+pub fn detect() -> Option<(Installation, PathBuf)> {
+    let candidates = Installation::ALL
+        .iter()
+        .filter_map(|&installation| Some((installation, installation.data_path()?)))
+        .collect::<Vec<_>>();
+    let with_database = candidates
+        .iter()
+        .find(|(_, path)| path.join("rhythmdb.xml").is_file());
+    let existing = || candidates.iter().find(|(_, path)| path.is_dir());
+    with_database
+        .or_else(existing)
+        .or_else(|| candidates.first())
+        .cloned()
+}
+
+/// Whether Rhythmbox is running, in which case it may overwrite changes to its files.
+///
+/// Processes are looked up via `/proc`, so this always returns false on other systems.
+pub fn is_running() -> bool {
+    let processes = match fs::read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return false,
+    };
+    processes
+        .filter_map(|process| fs::read_to_string(process.ok()?.path().join("comm")).ok())
+        .any(|comm| comm.trim_end() == "rhythmbox")
+}
@@ -0,0 +1,158 @@
+use crate::compression;
+use crate::rhythmdb::{EntryType, Schema, SCHEMA};
+use anyhow::{Context, Result};
+use elementtree::Element;
+use log::{info, warn};
+use plist::{Date, Dictionary, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Statistics of exporting the Rhythmbox library.
+#[derive(Debug, Default)]
+pub struct ExportStats {
+    pub tracks: usize,
+    pub playlists: usize,
+}
+
+/// Export songs and static playlists of Rhythmbox as an iTunes Library XML file,
+/// so that they can be read by tools consuming the iTunes library format.
+///
+/// The file is compressed if the path ends with `.gz` or `.zst`.
+pub fn export_itunes_library(
+    rhythmdb: &Element,
+    playlists: &Element,
+    path: &Path,
+) -> Result<ExportStats> {
+    info!("Exporting Rhythmbox library to {}...", path.display());
+    let schema = &SCHEMA;
+    let mut stats = ExportStats::default();
+    let mut tracks = Dictionary::new();
+    let mut track_ids = HashMap::new();
+    for entry in rhythmdb.children() {
+        if EntryType::of(entry, schema) != EntryType::Song {
+            continue;
+        }
+        let id = track_ids.len() as u64 + 1;
+        let location = entry.find(schema.location).map(Element::text);
+        let (track, location) = match (track_dict(entry, schema, id), location) {
+            (Some(track), Some(location)) => (track, location),
+            _ => {
+                warn!("song without title or location is not exported");
+                continue;
+            }
+        };
+        track_ids.insert(location, id);
+        tracks.insert(id.to_string(), Value::Dictionary(track));
+        stats.tracks += 1;
+    }
+
+    let mut playlist_values = Vec::new();
+    for playlist in playlists.find_all("playlist") {
+        if playlist.get_attr("type") != Some("static") {
+            continue;
+        }
+        let name = match playlist.get_attr("name") {
+            Some(name) => name,
+            None => continue,
+        };
+        let items = playlist
+            .find_all("location")
+            .filter_map(|location| track_ids.get(location.text()))
+            .map(|&id| {
+                let mut item = Dictionary::new();
+                item.insert("Track ID".into(), Value::Integer(id.into()));
+                Value::Dictionary(item)
+            })
+            .collect();
+        let mut dict = Dictionary::new();
+        dict.insert("Name".into(), Value::String(name.to_owned()));
+        dict.insert(
+            "Playlist ID".into(),
+            Value::Integer((playlist_values.len() as u64 + 1).into()),
+        );
+        dict.insert("All Items".into(), Value::Boolean(true));
+        dict.insert("Playlist Items".into(), Value::Array(items));
+        playlist_values.push(Value::Dictionary(dict));
+        stats.playlists += 1;
+    }
+
+    let mut library = Dictionary::new();
+    library.insert("Major Version".into(), Value::Integer(1.into()));
+    library.insert("Minor Version".into(), Value::Integer(1.into()));
+    library.insert(
+        "Application Version".into(),
+        Value::String(concat!("migrate-itunes-to-rhythmbox ", env!("CARGO_PKG_VERSION")).into()),
+    );
+    library.insert("Date".into(), Value::Date(SystemTime::now().into()));
+    library.insert("Tracks".into(), Value::Dictionary(tracks));
+    library.insert("Playlists".into(), Value::Array(playlist_values));
+    let mut writer = compression::create(path)?;
+    Value::Dictionary(library)
+        .to_writer_xml(&mut writer)
+        .context("failed to write iTunes library")?;
+    writer.finish().context("failed to write iTunes library")?;
+    info!(
+        "{} tracks and {} playlists exported",
+        stats.tracks, stats.playlists
+    );
+    Ok(stats)
+}
+
+/// Build the iTunes track dictionary of a song entry, if it has title and location.
+fn track_dict(entry: &Element, schema: &Schema, id: u64) -> Option<Dictionary> {
+    let text = |tag: &'static str| entry.find(tag).map(Element::text).filter(|t| !t.is_empty());
+    let number = |tag: &'static str| text(tag).and_then(|t| t.parse::<u64>().ok());
+    let date = |tag: &'static str| number(tag).filter(|&t| t > 0).map(timestamp_to_date);
+
+    let mut track = Dictionary::new();
+    let mut insert = |key: &str, value: Option<Value>| {
+        if let Some(value) = value {
+            track.insert(key.into(), value);
+        }
+    };
+    let string = |t: &str| Value::String(t.to_owned());
+    let integer = |n: u64| Value::Integer(n.into());
+    insert("Track ID", Some(integer(id)));
+    insert("Name", Some(string(text(schema.title)?)));
+    insert("Artist", text(schema.artist).map(string));
+    insert("Album", text(schema.album).map(string));
+    insert("Genre", text("genre").map(string));
+    insert("Disc Number", number(schema.disc_number).map(integer));
+    insert("Track Number", number(schema.track_number).map(integer));
+    insert(
+        "Total Time",
+        number(schema.duration).map(|d| integer(d * 1000)),
+    );
+    insert("BPM", number(schema.bpm).map(integer));
+    insert("Comments", text(schema.comment).map(string));
+    // iTunes requires both dates, fall back to each other or the epoch.
+    let first_seen = date(schema.first_seen);
+    let modified = date("mtime").or_else(|| first_seen.clone());
+    let epoch = || timestamp_to_date(0);
+    insert("Date Modified", Some(modified.unwrap_or_else(epoch)));
+    insert("Date Added", Some(first_seen.unwrap_or_else(epoch)));
+    insert(
+        "Play Count",
+        number(schema.play_count).filter(|&c| c > 0).map(integer),
+    );
+    insert("Play Date UTC", date(schema.last_played));
+    let rating = text(schema.rating).and_then(|r| r.parse::<f64>().ok());
+    insert(
+        "Rating",
+        rating
+            .filter(|&r| r > 0.)
+            // iTunes uses 0-100 for rating.
+            .map(|r| integer((r * 100. / f64::from(schema.max_rating)).round() as u64)),
+    );
+    if text(schema.hidden) == Some("1") {
+        insert("Disabled", Some(Value::Boolean(true)));
+    }
+    insert("Track Type", Some(string("File")));
+    insert("Location", Some(string(text(schema.location)?)));
+    Some(track)
+}
+
+fn timestamp_to_date(timestamp: u64) -> Value {
+    Value::Date(Date::from(UNIX_EPOCH + Duration::from_secs(timestamp)))
+}
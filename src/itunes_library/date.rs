@@ -0,0 +1,255 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use log::{debug, info};
+use plist::Value;
+use regex::bytes::Regex;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+
+/// Keys of dates in track dictionaries.
+const DATE_KEYS: [&str; 4] = ["Date Modified", "Date Added", "Play Date UTC", "Skip Date"];
+
+/// Formats of dates with timezone, other than RFC 3339 and RFC 2822.
+const ZONED_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S %z", "%Y-%m-%d %H:%M:%S%.f %z"];
+
+/// Formats of dates without timezone, as written by older iTunes and third-party tools.
+const NAIVE_FORMATS: [&str; 7] = [
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%m/%d/%Y %I:%M:%S %p",
+    "%m/%d/%Y %H:%M:%S",
+    "%d.%m.%Y %H:%M:%S",
+];
+
+/// Formats of dates without time, which are taken as midnight.
+const DATE_FORMATS: [&str; 3] = ["%Y-%m-%d", "%m/%d/%Y", "%d.%m.%Y"];
+
+/// Parse a UTC offset like `+08:00`, `-0500`, or `Z`.
+pub fn parse_utc_offset(s: &str) -> Result<FixedOffset, String> {
+    let invalid = || format!("invalid UTC offset {}, expected e.g. +08:00", s);
+    if s == "Z" || s.eq_ignore_ascii_case("utc") {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    let mut chars = s.chars();
+    let sign = match chars.next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return Err(invalid()),
+    };
+    let digits = chars.as_str().replace(':', "");
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let hours = digits[..2].parse::<i32>().unwrap();
+    let minutes = digits[2..].parse::<i32>().unwrap();
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+/// Rewrite dates of tracks without timezone in the plist as RFC 3339 dates in the given offset,
+/// so that they are deserialized as such.
+///
+/// Only dates turned into strings by [`dates_as_strings`] are rewritten.
+pub fn resolve_naive_dates(library: &mut Value, offset: FixedOffset) {
+    let tracks = library
+        .as_dictionary_mut()
+        .and_then(|library| library.get_mut("Tracks"))
+        .and_then(Value::as_dictionary_mut);
+    let tracks = match tracks {
+        Some(tracks) => tracks,
+        None => return,
+    };
+    let mut count = 0;
+    for track in tracks.values_mut().filter_map(Value::as_dictionary_mut) {
+        for key in DATE_KEYS {
+            if let Some(Value::String(s)) = track.get_mut(key) {
+                if parse_zoned(s).is_none() {
+                    if let Some(date) = parse_naive(s, offset) {
+                        debug!("date {} without timezone is assumed to be in {}", s, offset);
+                        *s = date.to_rfc3339();
+                        count += 1;
+                    }
+                }
+            }
+        }
+    }
+    if count > 0 {
+        info!(
+            "{} dates without timezone are assumed to be in {}",
+            count, offset
+        );
+    }
+}
+
+/// Turn `<date>` elements of a plist XML into `<string>` elements,
+/// so that dates in formats rejected by the plist parser reach [`deserialize`].
+pub fn dates_as_strings(data: &[u8]) -> Cow<'_, [u8]> {
+    let pattern = Regex::new(r"<(/?)date(\s*/?)>").unwrap();
+    pattern.replace_all(data, &b"<${1}string${2}>"[..])
+}
+
+/// Deserialize a date in any of the known formats.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse(&s).ok_or_else(|| D::Error::custom(format!("invalid date {}", s)))
+}
+
+/// Deserialize an optional date in any of the known formats.
+pub fn deserialize_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => match parse(&s) {
+            Some(date) => Ok(Some(date)),
+            None => Err(D::Error::custom(format!("invalid date {}", s))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parse a date in any of the known formats, taking those without timezone as UTC.
+fn parse(s: &str) -> Option<DateTime<Utc>> {
+    parse_zoned(s).or_else(|| parse_naive(s, FixedOffset::east_opt(0).unwrap()))
+}
+
+fn parse_zoned(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    let date = DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_rfc2822(s))
+        .ok()
+        .or_else(|| {
+            ZONED_FORMATS
+                .iter()
+                .find_map(|format| DateTime::parse_from_str(s, format).ok())
+        })?;
+    Some(date.with_timezone(&Utc))
+}
+
+/// Parse a date in any of the formats without timezone, assuming it to be in the offset.
+fn parse_naive(s: &str, offset: FixedOffset) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    let naive = NAIVE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(s, format).ok())
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })?;
+    let date = offset.from_local_datetime(&naive).single()?;
+    Some(date.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plist::Dictionary;
+
+    fn offset(seconds: i32) -> FixedOffset {
+        FixedOffset::east_opt(seconds).unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_utc_offsets() {
+        assert_eq!(parse_utc_offset("+08:00"), Ok(offset(8 * 3600)));
+        assert_eq!(parse_utc_offset("-0530"), Ok(offset(-(5 * 3600 + 30 * 60))));
+        assert_eq!(parse_utc_offset("Z"), Ok(offset(0)));
+        assert_eq!(parse_utc_offset("UTC"), Ok(offset(0)));
+    }
+
+    #[test]
+    fn rejects_invalid_utc_offsets() {
+        for s in [
+            "",
+            "08:00",
+            "+8:00",
+            "+08:00:00",
+            "+0a:00",
+            "+99:00",
+            "−05:00",
+            "+０８:００",
+        ] {
+            assert!(parse_utc_offset(s).is_err(), "{} is accepted", s);
+        }
+    }
+
+    #[test]
+    fn parses_zoned_dates() {
+        let expected = utc("2019-03-04T05:06:07Z");
+        for s in [
+            "2019-03-04T05:06:07Z",
+            "2019-03-04T13:06:07+08:00",
+            "Mon, 04 Mar 2019 05:06:07 +0000",
+            "2019-03-04 00:06:07 -0500",
+            " 2019-03-04 05:06:07.000 +0000 ",
+        ] {
+            assert_eq!(parse(s), Some(expected), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parses_naive_dates_in_offset() {
+        let expected = utc("2019-03-04T05:06:07Z");
+        let offset = offset(8 * 3600);
+        for s in [
+            "2019-03-04T13:06:07",
+            "2019-03-04 13:06:07",
+            "2019-03-04 13:06:07.0",
+            "03/04/2019 01:06:07 PM",
+            "03/04/2019 13:06:07",
+            "04.03.2019 13:06:07",
+        ] {
+            assert_eq!(parse_naive(s, offset), Some(expected), "{}", s);
+            assert_eq!(
+                parse(s),
+                Some(expected + chrono::Duration::hours(8)),
+                "{}",
+                s
+            );
+        }
+        assert_eq!(
+            parse_naive("2019-03-04", offset),
+            Some(utc("2019-03-03T16:00:00Z")),
+        );
+        assert_eq!(parse("yesterday"), None);
+    }
+
+    #[test]
+    fn resolves_naive_dates_of_tracks() {
+        let data = b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <plist version=\"1.0\"><dict><key>Tracks</key><dict><key>1</key><dict>\
+            <key>Date Added</key><date>2019-03-04 13:06:07</date>\
+            <key>Date Modified</key><date>2019-03-04T05:06:07Z</date>\
+            <key>Name</key><string>2019-03-04 13:06:07</string>\
+            </dict></dict></dict></plist>";
+        let mut library = Value::from_reader_xml(&*dates_as_strings(data)).unwrap();
+        resolve_naive_dates(&mut library, offset(8 * 3600));
+        let track = library
+            .as_dictionary()
+            .and_then(|library| library.get("Tracks"))
+            .and_then(Value::as_dictionary)
+            .and_then(|tracks| tracks.get("1"))
+            .and_then(Value::as_dictionary)
+            .unwrap();
+        let string =
+            |track: &Dictionary, key| track.get(key).and_then(Value::as_string).map(str::to_owned);
+        assert_eq!(
+            string(track, "Date Added").and_then(|s| parse(&s)),
+            Some(utc("2019-03-04T05:06:07Z")),
+        );
+        assert_eq!(
+            string(track, "Date Modified").as_deref(),
+            Some("2019-03-04T05:06:07Z"),
+        );
+        assert_eq!(
+            string(track, "Name").as_deref(),
+            Some("2019-03-04 13:06:07")
+        );
+    }
+}
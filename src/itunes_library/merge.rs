@@ -0,0 +1,225 @@
+use super::{ItunesLibrary, MalformedRecord, Track, TrackId};
+use crate::event::Event;
+use clap::ValueEnum;
+use log::{info, warn, Level};
+use std::cmp;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// How to combine play statistics of a track present in multiple libraries.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum MergeStrategy {
+    /// Add up play and skip counts
+    #[default]
+    Sum,
+    /// Take the largest play and skip counts
+    Max,
+    /// Keep the statistics from the library given first
+    First,
+}
+
+/// Owned version of `TrackKey`, used to find duplicate tracks across libraries.
+type OwnedKey = (
+    String,
+    Option<String>,
+    Option<String>,
+    Option<usize>,
+    Option<usize>,
+);
+
+fn owned_key(track: &Track) -> OwnedKey {
+    (
+        track.name.clone(),
+        track.artist.clone(),
+        track.album.clone(),
+        track.disc_number,
+        track.track_number,
+    )
+}
+
+/// Merge multiple libraries into one.
+///
+/// Tracks with the same persistent ID or metadata are deduplicated,
+/// and playlists are prefixed with the name of their library.
+/// Track IDs are reassigned, as they are only unique within a library.
+pub fn merge_libraries(
+    libraries: Vec<(String, ItunesLibrary)>,
+    strategy: MergeStrategy,
+) -> ItunesLibrary {
+    info!("Merging {} iTunes libraries...", libraries.len());
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut tracks_by_key = HashMap::new();
+    let mut tracks_by_persistent_id = HashMap::new();
+    let mut playlists = Vec::new();
+    let mut malformed_records = Vec::new();
+    let mut duplicate_count = 0;
+    for (name, library) in libraries {
+        let mut library_tracks = library.tracks.into_values().collect::<Vec<_>>();
+        library_tracks.sort_by_key(|track| track.id.0);
+        let mut id_map = HashMap::new();
+        for mut track in library_tracks {
+            let old_id = track.id;
+            let key = owned_key(&track);
+            let persistent_id = track.persistent_id.clone();
+            let existing = persistent_id
+                .as_ref()
+                .and_then(|id| tracks_by_persistent_id.get(id))
+                .or_else(|| tracks_by_key.get(&key))
+                .copied();
+            let index = match existing {
+                Some(index) => {
+                    warn!("merging duplicate track {} from {}", track.name, name);
+                    merge_track(&mut tracks[index], track, strategy);
+                    duplicate_count += 1;
+                    index
+                }
+                None => {
+                    let index = tracks.len();
+                    track.id = TrackId(index as u64 + 1);
+                    tracks.push(track);
+                    index
+                }
+            };
+            tracks_by_key.entry(key).or_insert(index);
+            if let Some(persistent_id) = persistent_id {
+                tracks_by_persistent_id
+                    .entry(persistent_id)
+                    .or_insert(index);
+            }
+            id_map.insert(old_id, tracks[index].id);
+        }
+        malformed_records.extend(library.malformed_records.into_iter().map(|malformed| {
+            MalformedRecord {
+                record: format!("{} in {}", malformed.record, name),
+                ..malformed
+            }
+        }));
+        let missing_track_ids = library.missing_track_ids;
+        for mut playlist in library.playlists {
+            playlist.name = format!("{} - {}", name, playlist.name);
+            // Items of tracks missing from the library have nothing to be merged with.
+            let mut missing_count = 0;
+            playlist
+                .items
+                .retain_mut(|item| match id_map.get(&item.id) {
+                    Some(&id) => {
+                        item.id = id;
+                        true
+                    }
+                    None => {
+                        if missing_track_ids.contains(&item.id) {
+                            missing_count += 1;
+                        }
+                        false
+                    }
+                });
+            if missing_count > 0 {
+                Event::PlaylistItemsMissingFromLibrary {
+                    playlist: &playlist.name,
+                    persistent_id: playlist.persistent_id.as_deref(),
+                    count: missing_count,
+                }
+                .log(Level::Warn);
+            }
+            playlists.push(playlist);
+        }
+    }
+    info!("{} duplicate tracks merged", duplicate_count);
+    ItunesLibrary {
+        tracks: tracks.into_iter().map(|track| (track.id, track)).collect(),
+        playlists,
+        malformed_records,
+        missing_track_ids: HashSet::new(),
+    }
+}
+
+/// Merge tracks with the same metadata within a single library,
+/// the same way duplicates are merged across libraries.
+///
+/// Playlist items of a merged track refer to the track kept instead.
+pub fn merge_duplicate_tracks(library: &mut ItunesLibrary, strategy: MergeStrategy) {
+    let mut ids = library.tracks.keys().copied().collect::<Vec<_>>();
+    ids.sort_by_key(|id| id.0);
+    let mut kept_ids = HashMap::new();
+    let mut duplicates = Vec::new();
+    for id in ids {
+        match kept_ids.entry(owned_key(&library.tracks[&id])) {
+            Entry::Occupied(entry) => duplicates.push((id, *entry.get())),
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+            }
+        }
+    }
+    if duplicates.is_empty() {
+        return;
+    }
+    for &(id, kept_id) in &duplicates {
+        let track = library.tracks.remove(&id).unwrap();
+        warn!("merging duplicate track {}", track.name);
+        merge_track(library.tracks.get_mut(&kept_id).unwrap(), track, strategy);
+    }
+    let id_map = duplicates.into_iter().collect::<HashMap<_, _>>();
+    for playlist in library.playlists.iter_mut() {
+        for item in playlist.items.iter_mut() {
+            if let Some(&id) = id_map.get(&item.id) {
+                item.id = id;
+            }
+        }
+    }
+    info!("{} duplicate tracks merged", id_map.len());
+}
+
+/// Merge the track from a later library into the existing one.
+fn merge_track(existing: &mut Track, other: Track, strategy: MergeStrategy) {
+    match strategy {
+        MergeStrategy::Sum | MergeStrategy::Max => {
+            let count = |a, b| match strategy {
+                MergeStrategy::Sum => a + b,
+                _ => cmp::max(a, b),
+            };
+            existing.play_count = combine(existing.play_count, other.play_count, count);
+            existing.skip_count = combine(existing.skip_count, other.skip_count, count);
+            existing.play_date = combine(existing.play_date, other.play_date, cmp::max);
+            existing.skip_date = combine(existing.skip_date, other.skip_date, cmp::max);
+            existing.date_added = cmp::min(existing.date_added, other.date_added);
+        }
+        MergeStrategy::First => {
+            existing.play_count = existing.play_count.or(other.play_count);
+            existing.skip_count = existing.skip_count.or(other.skip_count);
+            existing.play_date = existing.play_date.or(other.play_date);
+            existing.skip_date = existing.skip_date.or(other.skip_date);
+        }
+    }
+    // Ratings from the library given first take precedence.
+    (existing.rating, existing.rating_computed) = merge_rating(
+        (existing.rating, existing.rating_computed),
+        (other.rating, other.rating_computed),
+    );
+    (existing.album_rating, existing.album_rating_computed) = merge_rating(
+        (existing.album_rating, existing.album_rating_computed),
+        (other.album_rating, other.album_rating_computed),
+    );
+    existing.bpm = existing.bpm.or(other.bpm);
+    existing.comments = existing.comments.take().or(other.comments);
+    existing.loved |= other.loved;
+    existing.disliked |= other.disliked;
+    existing.disabled &= other.disabled;
+}
+
+/// Merge ratings along with whether they are computed,
+/// preferring explicit ratings over computed ones.
+fn merge_rating(existing: (Option<u8>, bool), other: (Option<u8>, bool)) -> (Option<u8>, bool) {
+    let rated = |(rating, _): (Option<u8>, bool)| rating.is_some_and(|rating| rating > 0);
+    if !rated(existing) || (existing.1 && rated(other) && !other.1) {
+        other
+    } else {
+        existing
+    }
+}
+
+fn combine<T>(a: Option<T>, b: Option<T>, f: impl Fn(T, T) -> T) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(f(a, b)),
+        (a, b) => a.or(b),
+    }
+}
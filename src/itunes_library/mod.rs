@@ -1,24 +1,441 @@
-use chrono::{DateTime, Utc};
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::compression;
+use crate::event::{Event, SkipReason};
+use crate::track_key::TrackKey;
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Utc};
+use glob::Pattern;
+use log::{debug, info, Level};
+use plist::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
+mod date;
+mod merge;
 mod track_id;
 
+pub use date::parse_utc_offset;
+pub use merge::{merge_duplicate_tracks, merge_libraries, MergeStrategy};
 use serde::de::IgnoredAny;
 pub use track_id::TrackId;
 
+/// Subset of tracks to migrate, e.g. for trying on part of the library first.
+///
+/// Tracks need to match any pattern of each non-empty list.
+#[derive(Debug, Default)]
+pub struct TrackSubset {
+    pub artists: Vec<Pattern>,
+    pub albums: Vec<Pattern>,
+    pub genres: Vec<Pattern>,
+    /// Maximum number of tracks, taken in the order of their IDs.
+    pub limit: Option<usize>,
+}
+
+impl TrackSubset {
+    /// Whether any track may be left out.
+    pub fn is_restricted(&self) -> bool {
+        !self.artists.is_empty()
+            || !self.albums.is_empty()
+            || !self.genres.is_empty()
+            || self.limit.is_some()
+    }
+
+    fn includes(&self, track: &Track) -> bool {
+        let matches = |patterns: &[Pattern], value: &Option<String>| {
+            patterns.is_empty()
+                || value
+                    .as_deref()
+                    .is_some_and(|value| patterns.iter().any(|p| p.matches(value)))
+        };
+        matches(&self.artists, &track.artist)
+            && matches(&self.albums, &track.album)
+            && matches(&self.genres, &track.genre)
+    }
+}
+
+/// Options of reading iTunes libraries for migration.
+#[derive(Debug)]
+pub struct ReadOptions {
+    /// Fail on malformed tracks or playlists rather than skipping them.
+    pub strict: bool,
+    /// UTC offset assumed for dates without timezone.
+    pub timezone: FixedOffset,
+    /// Resolve playlist items referencing tracks missing from the library via their persistent IDs.
+    pub resolve_missing_tracks: bool,
+    /// How to combine play statistics of tracks in multiple libraries.
+    pub merge: MergeStrategy,
+    /// Keep movies, TV shows, and music videos rather than skipping them.
+    pub include_videos: bool,
+    /// Skip tracks disabled (unchecked) in iTunes.
+    pub skip_disabled: bool,
+    pub subset: TrackSubset,
+    /// Add a playlist for each classical work.
+    pub work_playlists: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            strict: false,
+            timezone: FixedOffset::east_opt(0).unwrap(),
+            resolve_missing_tracks: false,
+            merge: MergeStrategy::default(),
+            include_videos: false,
+            skip_disabled: false,
+            subset: TrackSubset::default(),
+            work_playlists: false,
+        }
+    }
+}
+
+/// Read the libraries at the paths, merging them if there are multiple,
+/// and strip tracks which are not to be migrated according to the options.
+///
+/// `names` are used to prefix playlists of each library when merging,
+/// which default to `Library 1`, `Library 2`, and so on.
+pub fn read_libraries(
+    paths: &[PathBuf],
+    names: &[String],
+    options: &ReadOptions,
+) -> Result<ItunesLibrary> {
+    let read_library = |path: &Path| {
+        let mut library = ItunesLibrary::read(path, options)?;
+        if options.resolve_missing_tracks {
+            library.resolve_missing_tracks();
+        }
+        Ok::<_, anyhow::Error>(library)
+    };
+    let mut itunes_library = match paths {
+        [path] => {
+            let mut library = read_library(path)?;
+            merge_duplicate_tracks(&mut library, options.merge);
+            library
+        }
+        _ => {
+            let libraries = paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    let name = match names.get(i) {
+                        Some(name) => name.clone(),
+                        None => format!("Library {}", i + 1),
+                    };
+                    let library = read_library(path)
+                        .with_context(|| format!("failed to read {}", path.display()))?;
+                    Ok((name, library))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            merge_libraries(libraries, options.merge)
+        }
+    };
+    if !options.include_videos {
+        itunes_library.strip_videos();
+    }
+    if options.skip_disabled {
+        itunes_library.strip_disabled_tracks();
+    }
+    if options.subset.is_restricted() {
+        itunes_library.restrict_to(&options.subset);
+    }
+    if options.work_playlists {
+        itunes_library.add_work_playlists();
+    }
+    Ok(itunes_library)
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ItunesLibrary {
     #[serde(rename = "Tracks")]
     pub tracks: HashMap<TrackId, Track>,
     #[serde(rename = "Playlists")]
     pub playlists: Vec<Playlist>,
+    /// Records skipped because they can't be parsed.
+    #[serde(skip)]
+    pub malformed_records: Vec<MalformedRecord>,
+    /// IDs referenced by playlist items but absent from the tracks of the library file,
+    /// as opposed to tracks stripped afterwards, e.g. videos.
+    #[serde(skip)]
+    pub missing_track_ids: HashSet<TrackId>,
+}
+
+/// A record in the iTunes library which can't be parsed.
+#[derive(Clone, Debug, Serialize)]
+pub struct MalformedRecord {
+    /// What the record is, e.g. `track 123` or `playlist Party`.
+    pub record: String,
+    pub error: String,
+}
+
+/// The library with records left unparsed, so that malformed ones can be skipped.
+#[derive(Deserialize)]
+struct RawLibrary {
+    #[serde(rename = "Tracks")]
+    tracks: BTreeMap<String, Value>,
+    #[serde(rename = "Playlists")]
+    playlists: Vec<Value>,
+}
+
+impl ItunesLibrary {
+    /// Read the library from an XML file, stripping tracks which can't be migrated.
+    ///
+    /// Malformed tracks and playlists are skipped and recorded,
+    /// unless `strict` is set in the options, in which case they fail the whole library.
+    /// The file can be compressed with gzip or zstd.
+    pub fn read(path: &Path, options: &ReadOptions) -> Result<Self> {
+        info!("Reading iTunes library...");
+        let value = read_plist(path, options.timezone)?;
+        let mut library = if options.strict {
+            plist::from_value(&value)
+        } else {
+            plist::from_value(&value).map(Self::parse_leniently)
+        }
+        .context("failed to read iTunes library")?;
+        library.missing_track_ids = library
+            .playlists
+            .iter()
+            .flat_map(|playlist| playlist.items.iter())
+            .map(|item| item.id)
+            .filter(|id| !library.tracks.contains_key(id))
+            .collect();
+        library.strip_non_local_tracks();
+        Ok(library)
+    }
+
+    /// Point playlist items referencing tracks missing from the library
+    /// to the tracks with the same persistent IDs, for items which have them.
+    pub fn resolve_missing_tracks(&mut self) {
+        let tracks_by_persistent_id = self
+            .tracks
+            .values()
+            .filter_map(|track| Some((track.persistent_id.as_deref()?, track.id)))
+            .collect::<HashMap<_, _>>();
+        let mut resolved_count = 0;
+        for playlist in self.playlists.iter_mut() {
+            for item in playlist.items.iter_mut() {
+                if !self.missing_track_ids.contains(&item.id) {
+                    continue;
+                }
+                let id = item
+                    .persistent_id
+                    .as_deref()
+                    .and_then(|id| tracks_by_persistent_id.get(id));
+                if let Some(&id) = id {
+                    item.id = id;
+                    resolved_count += 1;
+                }
+            }
+        }
+        info!(
+            "{} playlist items referencing missing tracks are resolved via persistent IDs",
+            resolved_count
+        );
+    }
+
+    fn parse_leniently(raw: RawLibrary) -> Self {
+        let mut malformed_records = Vec::new();
+        let mut skip = |record: String, error: String| {
+            Event::RecordSkipped {
+                record: &record,
+                error: &error,
+            }
+            .log(Level::Warn);
+            malformed_records.push(MalformedRecord { record, error });
+        };
+        let tracks = raw
+            .tracks
+            .into_iter()
+            .filter_map(|(key, value)| match plist::from_value::<Track>(&value) {
+                Ok(track) => Some((track.id, track)),
+                Err(e) => {
+                    skip(format!("track {}", key), e.to_string());
+                    None
+                }
+            })
+            .collect::<HashMap<_, _>>();
+        let playlists = raw
+            .playlists
+            .iter()
+            .enumerate()
+            .filter_map(|(i, value)| match plist::from_value::<Playlist>(value) {
+                Ok(playlist) => Some(playlist),
+                Err(e) => {
+                    let name = value
+                        .as_dictionary()
+                        .and_then(|dict| dict.get("Name"))
+                        .and_then(Value::as_string);
+                    let record = match name {
+                        Some(name) => format!("playlist {}", name),
+                        None => format!("playlist #{}", i + 1),
+                    };
+                    skip(record, e.to_string());
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if !malformed_records.is_empty() {
+            info!("{} malformed records are skipped", malformed_records.len());
+        }
+        ItunesLibrary {
+            tracks,
+            playlists,
+            malformed_records,
+            missing_track_ids: HashSet::new(),
+        }
+    }
+
+    /// Strip movies, TV shows, and music videos.
+    pub fn strip_videos(&mut self) {
+        let count = self.tracks.len();
+        self.tracks.retain(|_, track| !track.is_video());
+        let skipped_count = count - self.tracks.len();
+        if skipped_count > 0 {
+            info!("{} videos are skipped", skipped_count);
+        }
+    }
+
+    /// Keep only tracks in the given subset, and playlists which still have items afterwards.
+    pub fn restrict_to(&mut self, subset: &TrackSubset) {
+        let mut ids = self
+            .tracks
+            .values()
+            .filter(|track| subset.includes(track))
+            .map(|track| track.id)
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.0);
+        if let Some(limit) = subset.limit {
+            ids.truncate(limit);
+        }
+        let ids = ids.into_iter().collect::<HashSet<_>>();
+        let track_count = self.tracks.len();
+        self.tracks.retain(|id, _| ids.contains(id));
+        let playlist_count = self.playlists.len();
+        self.playlists.retain_mut(|playlist| {
+            playlist.items.retain(|item| ids.contains(&item.id));
+            !playlist.items.is_empty()
+        });
+        info!(
+            "{} of {} tracks and {} of {} playlists are included",
+            self.tracks.len(),
+            track_count,
+            self.playlists.len(),
+            playlist_count,
+        );
+    }
+
+    /// Add a static playlist for each classical work, with its movements in order.
+    ///
+    /// Works with the same name on different albums are told apart by the album name.
+    pub fn add_work_playlists(&mut self) {
+        let mut works = BTreeMap::<_, Vec<&Track>>::new();
+        for track in self.tracks.values() {
+            if let Some(work) = track.work_name() {
+                works
+                    .entry((work, track.album.as_deref()))
+                    .or_default()
+                    .push(track);
+            }
+        }
+        let mut work_name_counts = HashMap::new();
+        for (work, _) in works.keys() {
+            *work_name_counts.entry(*work).or_insert(0) += 1;
+        }
+        let last_id = self.playlists.iter().map(|p| p.id).max().unwrap_or(0);
+        let mut playlists = Vec::with_capacity(works.len());
+        for (id, ((work, album), mut tracks)) in (last_id + 1..).zip(works) {
+            tracks.sort_by_key(|track| {
+                (track.disc_number, track.movement_number, track.track_number)
+            });
+            let name = match album {
+                Some(album) if work_name_counts[work] > 1 => format!("{} ({})", work, album),
+                _ => work.to_owned(),
+            };
+            playlists.push(Playlist {
+                name,
+                id,
+                persistent_id: None,
+                smart_info: None,
+                sort_field: None,
+                items: tracks
+                    .into_iter()
+                    .map(|track| PlaylistItem {
+                        id: track.id,
+                        persistent_id: track.persistent_id.clone(),
+                    })
+                    .collect(),
+            });
+        }
+        info!("{} work playlists are added", playlists.len());
+        self.playlists.extend(playlists);
+    }
+
+    /// Strip tracks which are disabled, i.e. unchecked, in iTunes.
+    pub fn strip_disabled_tracks(&mut self) {
+        self.tracks.retain(|_, track| {
+            if !track.disabled {
+                return true;
+            }
+            Event::SongSkipped {
+                song: TrackKey::from(&*track),
+                persistent_id: track.persistent_id.as_deref(),
+                reason: SkipReason::Disabled,
+            }
+            .log(Level::Info);
+            false
+        });
+    }
+
+    /// Strip tracks which are not backed by a local file,
+    /// e.g. cloud-only tracks, streams, and dead tracks without a location.
+    pub fn strip_non_local_tracks(&mut self) {
+        let mut skipped_count = 0;
+        self.tracks.retain(|_, track| {
+            let reason = match (track.track_type, &track.location) {
+                (Some(TrackType::Remote), _) => SkipReason::CloudOnly,
+                (Some(TrackType::Url), _) => SkipReason::Url,
+                (_, None) => SkipReason::NoLocation,
+                _ => return true,
+            };
+            Event::SongSkipped {
+                song: TrackKey::from(&*track),
+                persistent_id: track.persistent_id.as_deref(),
+                reason,
+            }
+            .log(Level::Warn);
+            skipped_count += 1;
+            false
+        });
+        if skipped_count > 0 {
+            info!("{} songs without local file are skipped", skipped_count);
+        }
+    }
+}
+
+/// Read the plist file, decompressing it if needed,
+/// with dates without timezone taken to be in the given offset.
+fn read_plist(path: &Path, timezone: FixedOffset) -> Result<Value> {
+    let (_, mut reader) = compression::open(path).context("failed to open iTunes library")?;
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .context("failed to read iTunes library")?;
+    // Binary plists have dates in a fixed format.
+    let data = if data.starts_with(b"bplist") {
+        data.into()
+    } else {
+        date::dates_as_strings(&data)
+    };
+    let mut value: Value = plist::from_bytes(&data).context("failed to read iTunes library")?;
+    date::resolve_naive_dates(&mut value, timezone);
+    Ok(value)
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Track {
     #[serde(rename = "Track ID")]
     pub id: TrackId,
+    #[serde(rename = "Persistent ID")]
+    pub persistent_id: Option<String>,
     #[serde(rename = "Name")]
     pub name: String,
     #[serde(rename = "Artist")]
@@ -27,30 +444,179 @@ pub struct Track {
     pub album: Option<String>,
     #[serde(rename = "Genre")]
     pub genre: Option<String>,
+    #[serde(rename = "Grouping")]
+    pub grouping: Option<String>,
+    #[serde(rename = "Work")]
+    pub work: Option<String>,
+    #[serde(rename = "Movement Name")]
+    pub movement_name: Option<String>,
+    #[serde(rename = "Movement Number")]
+    pub movement_number: Option<usize>,
     #[serde(rename = "Disc Number")]
     pub disc_number: Option<usize>,
     #[serde(rename = "Track Number")]
     pub track_number: Option<usize>,
+    /// Duration in milliseconds.
+    #[serde(rename = "Total Time")]
+    pub total_time: Option<u64>,
+    /// Position in milliseconds where playback starts, if trimmed in iTunes.
+    #[serde(rename = "Start Time")]
+    pub start_time: Option<u64>,
+    /// Position in milliseconds where playback stops, if trimmed in iTunes.
+    #[serde(rename = "Stop Time")]
+    pub stop_time: Option<u64>,
     #[serde(rename = "Year")]
     pub year: Option<u16>,
-    #[serde(rename = "Date Modified")]
+    /// Bit rate in kbit/s.
+    #[serde(rename = "Bit Rate")]
+    pub bit_rate: Option<u32>,
+    #[serde(rename = "BPM")]
+    pub bpm: Option<u16>,
+    #[serde(rename = "Comments")]
+    pub comments: Option<String>,
+    #[serde(rename = "Date Modified", deserialize_with = "date::deserialize")]
     pub date_modified: DateTime<Utc>,
-    #[serde(rename = "Date Added")]
+    #[serde(rename = "Date Added", deserialize_with = "date::deserialize")]
     pub date_added: DateTime<Utc>,
     #[serde(rename = "Play Count")]
     pub play_count: Option<usize>,
-    #[serde(rename = "Play Date UTC")]
+    #[serde(
+        rename = "Play Date UTC",
+        default,
+        deserialize_with = "date::deserialize_option"
+    )]
     pub play_date: Option<DateTime<Utc>>,
     #[serde(rename = "Skip Count")]
     pub skip_count: Option<usize>,
-    #[serde(rename = "Skip Date")]
+    #[serde(
+        rename = "Skip Date",
+        default,
+        deserialize_with = "date::deserialize_option"
+    )]
     pub skip_date: Option<DateTime<Utc>>,
     #[serde(rename = "Rating")]
     pub rating: Option<u8>,
+    /// Whether the rating is computed by iTunes, e.g. from the album rating,
+    /// rather than given by the user.
+    #[serde(rename = "Rating Computed", default)]
+    pub rating_computed: bool,
+    #[serde(rename = "Album Rating")]
+    pub album_rating: Option<u8>,
+    /// Whether the album rating is computed by iTunes from ratings of its tracks.
+    #[serde(rename = "Album Rating Computed", default)]
+    pub album_rating_computed: bool,
+    #[serde(rename = "Loved", default)]
+    pub loved: bool,
+    #[serde(rename = "Disliked", default)]
+    pub disliked: bool,
+    #[serde(rename = "Disabled", default)]
+    pub disabled: bool,
     #[serde(rename = "Movie", default)]
     pub movie: bool,
+    #[serde(rename = "TV Show", default)]
+    pub tv_show: bool,
+    #[serde(rename = "Music Video", default)]
+    pub music_video: bool,
+    #[serde(rename = "Has Video", default)]
+    pub has_video: bool,
+    #[serde(rename = "Kind")]
+    pub kind: Option<String>,
+    #[serde(rename = "Protected", default)]
+    pub protected: bool,
+    #[serde(rename = "Track Type")]
+    pub track_type: Option<TrackType>,
     #[serde(rename = "Location")]
-    pub location: String,
+    pub location: Option<String>,
+}
+
+impl Track {
+    /// Name of the classical work the track is a movement of.
+    ///
+    /// iTunes before 12.5 didn't have a work tag, so grouping was used for that instead.
+    pub fn work_name(&self) -> Option<&str> {
+        self.work.as_deref().or(self.grouping.as_deref())
+    }
+
+    /// Rating of the track in 0-100 to migrate, if any.
+    ///
+    /// Ratings computed by iTunes are only used with `include_computed`,
+    /// and unrated tracks get the rating of their album with `album_rating`.
+    pub fn effective_rating(&self, include_computed: bool, album_rating: bool) -> Option<u8> {
+        let pick = |rating: Option<u8>, computed: bool| {
+            rating.filter(|&rating| rating > 0 && (include_computed || !computed))
+        };
+        pick(self.rating, self.rating_computed).or_else(|| {
+            album_rating
+                .then(|| pick(self.album_rating, self.album_rating_computed))
+                .flatten()
+        })
+    }
+
+    /// Whether the start or the stop time is set in iTunes,
+    /// which Rhythmbox has no equivalent of.
+    pub fn is_trimmed(&self) -> bool {
+        self.start_time.is_some() || self.stop_time.is_some()
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.movie || self.tv_show || self.music_video || self.has_video
+    }
+
+    /// Whether the track is DRM-protected, e.g. AAC bought before iTunes Plus,
+    /// which can't be played outside iTunes.
+    pub fn is_protected(&self) -> bool {
+        let protected_kind = self
+            .kind
+            .as_deref()
+            .is_some_and(|kind| kind.contains("Protected"));
+        let protected_file = self
+            .location
+            .as_deref()
+            .is_some_and(|location| location.to_lowercase().ends_with(".m4p"));
+        self.protected || protected_kind || protected_file
+    }
+
+    /// MIME type of the track as Rhythmbox records it, e.g. `audio/mpeg`.
+    ///
+    /// This is taken from the kind, falling back to the file extension
+    /// for kinds in other languages than English.
+    pub fn media_type(&self) -> Option<&'static str> {
+        let from_kind = |kind: &str| {
+            Some(match kind {
+                _ if kind.contains("MPEG") => "audio/mpeg",
+                _ if kind.contains("AAC") => "audio/x-aac",
+                _ if kind.contains("Apple Lossless") => "audio/x-alac",
+                _ if kind.contains("WAV") => "audio/x-wav",
+                _ if kind.contains("AIFF") => "audio/x-aiff",
+                _ => return None,
+            })
+        };
+        let from_extension = |location: &str| {
+            let extension = location.rsplit_once('.')?.1.to_lowercase();
+            Some(match extension.as_str() {
+                "mp3" => "audio/mpeg",
+                "m4a" | "m4p" | "aac" => "audio/x-aac",
+                "wav" => "audio/x-wav",
+                "aif" | "aiff" => "audio/x-aiff",
+                _ => return None,
+            })
+        };
+        self.kind
+            .as_deref()
+            .and_then(from_kind)
+            .or_else(|| self.location.as_deref().and_then(from_extension))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum TrackType {
+    /// Track backed by a local file.
+    File,
+    /// Track of an Internet stream.
+    #[serde(rename = "URL")]
+    Url,
+    /// Track only available in the cloud, e.g. via iTunes Match or Apple Music.
+    Remote,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,14 +625,89 @@ pub struct Playlist {
     pub name: String,
     #[serde(rename = "Playlist ID")]
     pub id: u32,
+    #[serde(rename = "Playlist Persistent ID")]
+    pub persistent_id: Option<String>,
     #[serde(rename = "Smart Info")]
     pub smart_info: Option<IgnoredAny>,
+    /// Field the playlist is sorted by in iTunes, if not in the manual order.
+    #[serde(rename = "Sort Field")]
+    pub sort_field: Option<String>,
     #[serde(rename = "Playlist Items", default)]
     pub items: Vec<PlaylistItem>,
 }
 
+impl Playlist {
+    /// Items of the playlist in the order shown in iTunes.
+    ///
+    /// Items are in the manual order unless the playlist is sorted by a field,
+    /// in which case they are stably sorted by it, since Rhythmbox doesn't keep
+    /// sort settings of static playlists.
+    /// Items whose tracks aren't in the library are kept at their positions in the manual order.
+    pub fn sorted_items<'a>(&'a self, tracks: &HashMap<TrackId, Track>) -> Vec<&'a PlaylistItem> {
+        let mut items = self.items.iter().collect::<Vec<_>>();
+        let sort_field = match self.sort_field.as_deref() {
+            None | Some("Manual") | Some("Playlist Order") => return items,
+            Some(sort_field) => sort_field,
+        };
+        let key = match sort_key(sort_field) {
+            Some(key) => key,
+            None => {
+                debug!(
+                    "playlist {} is sorted by unsupported {}, keeping the manual order",
+                    self.name, sort_field,
+                );
+                return items;
+            }
+        };
+        let mut sorted = items
+            .iter()
+            .filter_map(|item| tracks.get(&item.id).map(|track| (key(track), *item)))
+            .collect::<Vec<_>>();
+        sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut sorted = sorted.into_iter().map(|(_, item)| item);
+        for item in items.iter_mut() {
+            if tracks.contains_key(&item.id) {
+                *item = sorted.next().unwrap();
+            }
+        }
+        items
+    }
+}
+
+/// Value of a track to sort by.
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum SortValue {
+    /// Text case-folded, as iTunes sorts text regardless of case.
+    Text(Option<String>),
+    Number(Option<u64>),
+    Date(Option<DateTime<Utc>>),
+}
+
+/// Key function for the sort field of iTunes playlists.
+fn sort_key(sort_field: &str) -> Option<fn(&Track) -> SortValue> {
+    fn text(s: Option<&str>) -> SortValue {
+        SortValue::Text(s.map(str::to_lowercase))
+    }
+    Some(match sort_field {
+        "Name" => |track| text(Some(&track.name)),
+        "Artist" => |track| text(track.artist.as_deref()),
+        "Album" => |track| text(track.album.as_deref()),
+        "Genre" => |track| text(track.genre.as_deref()),
+        "Year" => |track| SortValue::Number(track.year.map(u64::from)),
+        "Time" => |track| SortValue::Number(track.total_time),
+        "Play Count" => |track| SortValue::Number(track.play_count.map(|c| c as u64)),
+        "Rating" => |track| SortValue::Number(track.rating.map(u64::from)),
+        "Date Added" => |track| SortValue::Date(Some(track.date_added)),
+        "Last Played" => |track| SortValue::Date(track.play_date),
+        _ => return None,
+    })
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PlaylistItem {
     #[serde(rename = "Track ID")]
     pub id: TrackId,
+    /// Persistent ID of the track, which some tools write along with the track ID.
+    #[serde(rename = "Persistent ID")]
+    pub persistent_id: Option<String>,
 }
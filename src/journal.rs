@@ -0,0 +1,114 @@
+use crate::rhythmdb::MatchedEntry;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+pub const JOURNAL_FILENAME: &str = "migration-state.json";
+
+/// Phase of the migration which modifies files.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Backup,
+    Database,
+    Artwork,
+    Playlists,
+}
+
+/// Record of the progress of a migration, kept in the Rhythmbox data directory,
+/// so that an interrupted migration can be resumed rather than started over.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Journal {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Phases which have been completed.
+    pub completed: Vec<Phase>,
+    /// Persistent IDs of iTunes tracks synchronized into the database,
+    /// which stay the same across exports of the iTunes library unlike track IDs.
+    pub synced_tracks: Vec<String>,
+}
+
+impl Journal {
+    /// Load the journal of an interrupted migration, or start a new one.
+    pub fn open(rhythmbox_path: &Path) -> Result<Self> {
+        let path = rhythmbox_path.join(JOURNAL_FILENAME);
+        let mut journal = match fs::read(&path) {
+            Ok(content) => {
+                serde_json::from_slice::<Journal>(&content).context("failed to read journal")?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Journal::default(),
+            Err(e) => return Err(e).context("failed to open journal"),
+        };
+        journal.path = path;
+        if !journal.completed.is_empty() {
+            info!("Resuming the previous migration...");
+        }
+        Ok(journal)
+    }
+
+    pub fn is_completed(&self, phase: Phase) -> bool {
+        self.completed.contains(&phase)
+    }
+
+    /// Record that the phase has been completed.
+    pub fn complete(&mut self, phase: Phase) -> Result<()> {
+        self.completed.push(phase);
+        let content = serde_json::to_vec_pretty(self)?;
+        // Write into a temporary file first, so that the journal is never left partially written.
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, content).context("failed to write journal")?;
+        fs::rename(&temp_path, &self.path).context("failed to write journal")?;
+        Ok(())
+    }
+
+    /// Record the iTunes tracks synchronized into the database.
+    pub fn record_synced_tracks(&mut self, matched_entries: &[MatchedEntry<'_>]) {
+        self.synced_tracks = persistent_ids(matched_entries);
+    }
+
+    /// Warn if the matched iTunes tracks differ from those synchronized before the interruption,
+    /// e.g. because the iTunes library has been exported again in between.
+    pub fn check_synced_tracks(&self, matched_entries: &[MatchedEntry<'_>]) {
+        if !self.is_completed(Phase::Database) {
+            return;
+        }
+        let synced = self.synced_tracks.iter().collect::<HashSet<_>>();
+        let ids = persistent_ids(matched_entries);
+        let changed_count = synced.symmetric_difference(&ids.iter().collect()).count();
+        if changed_count > 0 {
+            warn!(
+                "{} tracks have changed since the interrupted migration, \
+                 restore and migrate again to include them",
+                changed_count,
+            );
+        }
+    }
+
+    /// Remove the journal after the migration has finished.
+    pub fn finish(self) -> Result<()> {
+        remove_file(&self.path)
+    }
+}
+
+/// Remove the journal in the Rhythmbox data directory, if any.
+pub fn remove_journal(rhythmbox_path: &Path) -> Result<()> {
+    remove_file(&rhythmbox_path.join(JOURNAL_FILENAME))
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context("failed to remove journal"),
+        _ => Ok(()),
+    }
+}
+
+fn persistent_ids(matched_entries: &[MatchedEntry<'_>]) -> Vec<String> {
+    matched_entries
+        .iter()
+        .filter_map(|matched| matched.track.persistent_id.clone())
+        .collect()
+}
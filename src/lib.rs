@@ -0,0 +1,28 @@
+pub mod artwork;
+pub mod backup;
+pub mod compression;
+pub mod event;
+pub mod explain;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixture;
+pub mod installation;
+pub mod itunes_export;
+pub mod itunes_library;
+pub mod journal;
+pub mod location;
+pub mod matching;
+pub mod migration;
+pub mod missing_files;
+pub mod playlist_diff;
+pub mod playlist_export;
+pub mod playlists;
+pub mod remote;
+pub mod report;
+pub mod rhythmdb;
+pub mod shell;
+pub mod sync_state;
+pub mod track_key;
+pub mod trim_script;
+pub mod verify;
+pub mod xml;
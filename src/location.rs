@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A prefix substitution applied to iTunes locations,
+/// so that they point to where the files live on this machine.
+#[derive(Clone, Debug)]
+pub struct LocationMapping {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for LocationMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("location mapping should be in the form of OLD=NEW"))?;
+        Ok(LocationMapping {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+}
+
+/// Apply the first matching mapping to the given location.
+pub fn remap<'a>(location: &'a str, mappings: &[LocationMapping]) -> Cow<'a, str> {
+    mappings
+        .iter()
+        .find_map(|mapping| {
+            let rest = location.strip_prefix(&mapping.from)?;
+            Some(Cow::Owned(format!("{}{}", mapping.to, rest)))
+        })
+        .unwrap_or(Cow::Borrowed(location))
+}
+
+/// Convert a `file://` URI into a local path.
+///
+/// Returns `None` if the URI is not a local file URI or is malformed.
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    // iTunes uses `file://localhost/...`.
+    let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !rest.starts_with('/') {
+        return None;
+    }
+    let bytes = percent_decode(rest)?;
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Convert an absolute local path into a `file://` URI.
+///
+/// Returns `None` if the path is not absolute or not valid UTF-8.
+pub fn path_to_uri(path: &Path) -> Option<String> {
+    if !path.is_absolute() {
+        return None;
+    }
+    let mut uri = "file://".to_owned();
+    for b in path.to_str()?.bytes() {
+        // Same set of characters GLib leaves unescaped in file URIs.
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&b) {
+            uri.push(char::from(b));
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Some(uri)
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hi = char::from(bytes.next()?).to_digit(16)?;
+            let lo = char::from(bytes.next()?).to_digit(16)?;
+            result.push((hi * 16 + lo) as u8);
+        } else {
+            result.push(b);
+        }
+    }
+    Some(result)
+}
@@ -0,0 +1,210 @@
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use log::kv::{self, Key, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::{self, IsTerminal, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use stderrlog::{ColorChoice, StdErrLog, Timestamp};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable messages
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+pub struct LogOptions<'a> {
+    pub module: &'static str,
+    /// Number of extra levels to log beyond info.
+    pub verbosity: u8,
+    pub quiet: bool,
+    pub format: LogFormat,
+    /// File descriptor to write JSON output to, instead of stderr.
+    pub fd: Option<i32>,
+    pub file: Option<&'a Path>,
+}
+
+enum Console {
+    Text(Box<StdErrLog>),
+    Json {
+        level: LevelFilter,
+        writer: Mutex<Box<dyn Write + Send>>,
+    },
+}
+
+/// Logger which writes to stderr, and optionally to a file with more details.
+struct Logger {
+    module: &'static str,
+    console: Console,
+    file: Option<(LevelFilter, Mutex<LineWriter<File>>)>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.console_enabled(metadata) || self.file_enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        match &self.console {
+            Console::Text(console) => console.log(record),
+            Console::Json { writer, .. } => {
+                if self.console_enabled(record.metadata()) {
+                    let line = json_line(record);
+                    let _ = writeln!(writer.lock().unwrap(), "{}", line);
+                }
+            }
+        }
+        if let Some((_, file)) = &self.file {
+            if self.file_enabled(record.metadata()) {
+                let mut file = file.lock().unwrap();
+                let _ = writeln!(file, "{} - {}", record.level(), record.args());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        match &self.console {
+            Console::Text(console) => console.flush(),
+            Console::Json { writer, .. } => {
+                let _ = writer.lock().unwrap().flush();
+            }
+        }
+        if let Some((_, file)) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+impl Logger {
+    fn console_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        match &self.console {
+            Console::Text(console) => console.enabled(metadata),
+            Console::Json { level, .. } => {
+                metadata.level() <= *level && metadata.target().starts_with(self.module)
+            }
+        }
+    }
+
+    fn file_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        match &self.file {
+            Some((level, _)) => {
+                metadata.level() <= *level && metadata.target().starts_with(self.module)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Convert a log record into a JSON object,
+/// with fields of the structured event attached to it, if any.
+fn json_line(record: &Record<'_>) -> Value {
+    struct Collector<'a>(&'a mut Map<String, Value>);
+
+    impl<'kvs> VisitSource<'kvs> for Collector<'_> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+            match serde_json::to_value(value).map_err(kv::Error::boxed)? {
+                Value::Object(fields) => self.0.extend(fields),
+                value => {
+                    self.0.insert(key.to_string(), value);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut object = Map::new();
+    object.insert(
+        "level".to_owned(),
+        record.level().as_str().to_lowercase().into(),
+    );
+    let _ = record.key_values().visit(&mut Collector(&mut object));
+    object.entry("event").or_insert_with(|| "message".into());
+    object.insert("message".to_owned(), record.args().to_string().into());
+    Value::Object(object)
+}
+
+/// Take ownership of the file descriptor given by the user to write to.
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<File> {
+    use std::os::unix::io::FromRawFd;
+    ensure!(
+        fd > 2,
+        "file descriptor {} is a standard stream, use --log-fd 3 or above",
+        fd
+    );
+    // SAFETY: F_GETFD only reads the flags of the file descriptor.
+    let valid = unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1;
+    ensure!(valid, "file descriptor {} is not open", fd);
+    // SAFETY: the file descriptor is open, given by the user to be written to,
+    // and it is not used anywhere else in the program.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<File> {
+    anyhow::bail!("--log-fd is only supported on Unix")
+}
+
+/// Initialize the global logger.
+///
+/// The log file, if any, gets at least debug level messages.
+pub fn init(options: LogOptions<'_>) -> Result<()> {
+    // 2 for info level
+    let console_verbosity = 2 + usize::from(options.verbosity);
+    let console_level = match (options.quiet, console_verbosity) {
+        (true, _) => LevelFilter::Off,
+        (false, 2) => LevelFilter::Info,
+        (false, 3) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let console = match options.format {
+        LogFormat::Text => {
+            let color = if io::stderr().is_terminal() {
+                ColorChoice::Auto
+            } else {
+                ColorChoice::Never
+            };
+            let mut console = stderrlog::new();
+            console
+                .module(options.module)
+                .verbosity(console_verbosity)
+                .quiet(options.quiet)
+                .color(color)
+                .timestamp(Timestamp::Off);
+            Console::Text(Box::new(console))
+        }
+        LogFormat::Json => {
+            let writer: Box<dyn Write + Send> = match options.fd {
+                Some(fd) => Box::new(LineWriter::new(open_fd(fd)?)),
+                None => Box::new(io::stderr()),
+            };
+            Console::Json {
+                level: console_level,
+                writer: Mutex::new(writer),
+            }
+        }
+    };
+    let file = match options.file {
+        Some(path) => {
+            let file = File::create(path).context("failed to create log file")?;
+            let level = console_level.max(LevelFilter::Debug);
+            Some((level, Mutex::new(LineWriter::new(file))))
+        }
+        None => None,
+    };
+    let max_level = match &file {
+        Some((level, _)) => console_level.max(*level),
+        None => console_level,
+    };
+    log::set_max_level(max_level);
+    log::set_boxed_logger(Box::new(Logger {
+        module: options.module,
+        console,
+        file,
+    }))?;
+    Ok(())
+}
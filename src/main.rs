@@ -1,299 +1,1034 @@
-use crate::itunes_library::{ItunesLibrary, Track, TrackId};
-use crate::track_key::TrackKey;
+use crate::logger::{LogFormat, LogOptions};
 use anyhow::{anyhow, ensure, Context, Result};
-use by_address::ByAddress;
-use clap::Parser;
-use elementtree::{Element, QName, WriteOptions, XmlProlog};
-use log::{info, warn};
+use chrono::FixedOffset;
+use clap::error::ErrorKind;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use glob::Pattern;
+use log::{debug, info};
+use migrate_itunes_to_rhythmbox::backup;
+use migrate_itunes_to_rhythmbox::compression;
+use migrate_itunes_to_rhythmbox::explain;
+use migrate_itunes_to_rhythmbox::fixture::{self, FixtureSize};
+use migrate_itunes_to_rhythmbox::installation;
+use migrate_itunes_to_rhythmbox::itunes_export;
+use migrate_itunes_to_rhythmbox::itunes_library::{
+    self, ItunesLibrary, MergeStrategy, ReadOptions, TrackSubset,
+};
+use migrate_itunes_to_rhythmbox::journal;
+use migrate_itunes_to_rhythmbox::location::LocationMapping;
+use migrate_itunes_to_rhythmbox::matching::{self, DurationMismatchAction, MatchOptions};
+use migrate_itunes_to_rhythmbox::migration::{
+    self, DisabledAction, DislikedAction, MigrationOptions,
+};
+use migrate_itunes_to_rhythmbox::missing_files::{self, CheckFilesAction, CopyMode};
+use migrate_itunes_to_rhythmbox::playlist_diff;
+use migrate_itunes_to_rhythmbox::playlist_export::ExportFormat;
+use migrate_itunes_to_rhythmbox::playlists::{
+    self, DedupeMode, PlaylistFilter, PLAYLISTS_FILENAME,
+};
+use migrate_itunes_to_rhythmbox::remote::RemoteDir;
+use migrate_itunes_to_rhythmbox::report::{
+    MatchReport, MigrationSummary, PlayComparison, ReportedTrack,
+};
+use migrate_itunes_to_rhythmbox::rhythmdb::{
+    self, ConflictPolicy, DatabaseMatch, Field, PruneAction, SyncOptions, RHYTHMDB_FILENAME,
+};
+use migrate_itunes_to_rhythmbox::sync_state::{self, SyncState};
+use migrate_itunes_to_rhythmbox::track_key::{ArtistCanonicalizer, Sanitizer};
+use migrate_itunes_to_rhythmbox::verify;
+use regex::Regex;
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{self, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use stderrlog::Timestamp;
+use std::thread;
+use std::time::{Duration, Instant};
 
-mod itunes_library;
-mod track_key;
+mod config;
+mod logger;
 
 #[derive(Debug, Parser)]
 struct Opt {
-    /// Path to the iTunes Library XML file
-    #[arg(name = "iTunes Library file")]
-    itunes_library: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Path to the Rhythmbox path
     ///
     /// When not specified,
-    /// it is `$XDG_DATA_HOME/rhythmbox` or `$HOME/.local/share/rhythmbox` by default.
-    #[arg(name = "Rhythmbox path", short, long)]
+    /// it is `$XDG_DATA_HOME/rhythmbox` or `$HOME/.local/share/rhythmbox` by default,
+    /// unless a Flatpak or Snap installation of Rhythmbox is detected.
+    ///
+    /// A directory on another machine can be given as e.g. `sftp://host/~/.local/share/rhythmbox`,
+    /// which is accessed via `ssh`.
+    #[arg(name = "Rhythmbox path", short, long = "rhythmbox-path", global = true)]
     rhythmbox_path: Option<PathBuf>,
     /// Silence all output
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     quiet: bool,
+    /// Print more details, can be repeated for even more
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Write detailed log, including each matching decision, to the given file
+    #[arg(long, value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
+    /// Format of the output
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+    /// Write the JSON output to the given file descriptor instead of stderr,
+    /// which requires `--log-format json`
+    #[arg(long, value_name = "FD", global = true)]
+    log_fd: Option<i32>,
+    /// Read default options from the given config file
+    ///
+    /// When not specified, `migrate-itunes-to-rhythmbox.toml` in `$XDG_CONFIG_HOME` is used if exists.
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+    /// Only warn about unsupported formats or versions of Rhythmbox files, rather than failing,
+    /// e.g. for a patched Rhythmbox
+    ///
+    /// They are then handled like files of the newest supported version.
+    #[arg(long, global = true)]
+    assume_compatible: bool,
+    /// Print the man page in roff format, e.g. for packaging
+    #[arg(long, exclusive = true)]
+    generate_manpage: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Migrate the iTunes library into Rhythmbox
+    Migrate(MigrateArgs),
+    /// Restore Rhythmbox files from the backups made by migration
+    Restore,
+    /// Print statistics of matching both libraries without writing anything
+    Report(ReportArgs),
+    /// Validate previously migrated Rhythmbox files against the iTunes library
+    Verify(VerifyArgs),
+    /// Export Rhythmbox songs and static playlists as an iTunes Library XML file
+    ExportItunes(ExportItunesArgs),
+    /// Explain why an iTunes track matches or doesn't match songs in Rhythmbox
+    Why(WhyArgs),
+    /// Print the completion script for the given shell, e.g. for packaging
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Generate a synthetic iTunes library and Rhythmbox database, e.g. for benchmarking
+    #[command(hide = true)]
+    GenFixture(GenFixtureArgs),
+}
+
+#[derive(Debug, Args)]
+struct GenFixtureArgs {
+    /// Directory to write `iTunes Music Library.xml` and the Rhythmbox data directory `rhythmbox` into
+    #[arg(name = "output directory")]
+    output: PathBuf,
+    /// Number of tracks
+    #[arg(long, value_name = "COUNT", default_value_t = 10_000)]
+    tracks: usize,
+    /// Number of playlists, defaults to one per 100 tracks
+    #[arg(long, value_name = "COUNT")]
+    playlists: Option<usize>,
+}
+
+#[derive(Debug, Args)]
+struct ReportArgs {
+    #[command(flatten)]
+    library: LibraryArgs,
+    /// Write the report into the file rather than printing it,
+    /// compressed if the name ends with `.gz` or `.zst`
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Check that files of matched songs exist, and list those which don't
+    #[arg(long)]
+    check_files: bool,
+    /// Also list play count, rating, last played, and first seen of each matched song
+    /// in both libraries side by side
+    #[arg(long)]
+    compare: bool,
+}
+
+#[derive(Debug, Args)]
+struct WhyArgs {
+    #[command(flatten)]
+    library: LibraryArgs,
+    /// Title of the iTunes track
+    #[arg(long)]
+    title: String,
+    /// Artist of the iTunes track, if there are multiple tracks with the title
+    #[arg(long)]
+    artist: Option<String>,
+    /// Album of the iTunes track, if there are multiple tracks with the title
+    #[arg(long)]
+    album: Option<String>,
+    /// Maximum number of similar Rhythmbox songs to show for each track
+    #[arg(long, value_name = "COUNT", default_value_t = 5)]
+    candidates: usize,
+}
+
+#[derive(Debug, Args)]
+struct ExportItunesArgs {
+    /// Path to write the iTunes Library XML file to
+    #[arg(name = "output file")]
+    output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct LibraryArgs {
+    /// Path to the iTunes Library XML file, multiple libraries are merged
+    ///
+    /// Files compressed with gzip or zstd, e.g. `.xml.gz`, are decompressed transparently.
+    #[arg(name = "iTunes Library file", required = true)]
+    itunes_libraries: Vec<PathBuf>,
+    /// Name of each library, used to prefix its playlists when merging, can be repeated
+    ///
+    /// Defaults to `Library 1`, `Library 2`, and so on.
+    #[arg(long, value_name = "NAME")]
+    library_name: Vec<String>,
+    /// How to combine play statistics of duplicate tracks, within or across libraries
+    #[arg(long, value_enum, default_value = "sum")]
+    merge: MergeStrategy,
+    /// Keep movies, TV shows, and music videos rather than skipping them
+    ///
+    /// They are matched against Rhythmbox entries like songs,
+    /// which requires video files to be imported into Rhythmbox.
+    #[arg(long)]
+    include_videos: bool,
+    /// Fail on malformed tracks or playlists in the iTunes library rather than skipping them
+    #[arg(long)]
+    strict: bool,
+    /// Resolve playlist items referencing tracks missing from the iTunes library
+    /// via their persistent IDs, when the items have them
+    #[arg(long)]
+    resolve_missing_tracks: bool,
+    /// UTC offset assumed for dates without timezone in the iTunes library, e.g. `+08:00`
+    #[arg(long, value_name = "OFFSET", default_value = "Z",
+          value_parser = itunes_library::parse_utc_offset)]
+    timezone: FixedOffset,
+    /// Add a playlist for each classical work, with its movements in order
+    ///
+    /// Works are taken from the work tag, or the grouping for older iTunes.
+    #[arg(long)]
+    work_playlists: bool,
+    /// Only include iTunes tracks by artists matching the given name, can be repeated
+    ///
+    /// Glob patterns like `The *` are supported.
+    /// This and other filters are meant for trying on part of the library first.
+    #[arg(long, value_name = "NAME")]
+    filter_artist: Vec<Pattern>,
+    /// Only include iTunes tracks on albums matching the given name, can be repeated
+    #[arg(long, value_name = "NAME")]
+    filter_album: Vec<Pattern>,
+    /// Only include iTunes tracks of genres matching the given name, can be repeated
+    #[arg(long, value_name = "NAME")]
+    filter_genre: Vec<Pattern>,
+    /// Only include the first N iTunes tracks, after applying the filters
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+    /// Artist name Rhythmbox gives songs without artist, can be repeated
+    #[arg(long, value_name = "NAME", default_value = "未知")]
+    unknown_artist: Vec<String>,
+    /// Strip decorations like `(Remastered 2011)` or `feat. X` from names
+    /// to match songs which don't match otherwise
+    #[arg(long)]
+    sanitize: bool,
+    /// Regular expression of decorations to strip when sanitizing, can be repeated
+    ///
+    /// The built-in patterns are replaced when specified.
+    #[arg(long, value_name = "REGEX", requires = "sanitize")]
+    sanitize_pattern: Vec<Regex>,
+    /// File of artist aliases, with lines like `Artist A = Artist B`
+    /// to match songs of `Artist A` with those of `Artist B`
+    #[arg(long, value_name = "PATH")]
+    artist_aliases: Option<PathBuf>,
+    /// Romanize Cyrillic artist names to match songs which don't match otherwise
+    ///
+    /// Other scripts, e.g. Chinese, Japanese, and Korean, are not romanized,
+    /// as their romanizations rarely match the Latin names artists use.
+    /// List such names in `--artist-aliases` instead.
+    #[arg(long)]
+    transliterate: bool,
+    /// Repair mojibake, i.e. UTF-8 text decoded as Latin-1, to match songs which don't match otherwise
+    ///
+    /// Songs matched this way are listed in the summary.
+    #[arg(long)]
+    fix_encoding: bool,
+    /// File of manual matches, each line being the persistent ID of an iTunes track
+    /// and the location of a Rhythmbox song, separated by a tab
+    #[arg(long, value_name = "PATH")]
+    match_file: Option<PathBuf>,
+    /// Maximum difference in seconds between durations of matched songs, 0 to disable the check
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    duration_tolerance: u64,
+    /// What to do with matched songs whose durations differ beyond the tolerance
+    #[arg(long, value_enum, default_value = "warn")]
+    duration_mismatch: DurationMismatchAction,
+}
+
+impl LibraryArgs {
+    fn match_options(&self) -> Result<MatchOptions> {
+        let manual_matches = match &self.match_file {
+            Some(path) => matching::read_manual_matches(path)
+                .with_context(|| format!("failed to read {}", path.display()))?,
+            None => HashMap::new(),
+        };
+        let aliases = match &self.artist_aliases {
+            Some(path) => ArtistCanonicalizer::read_aliases(path)
+                .with_context(|| format!("failed to read {}", path.display()))?,
+            None => HashMap::new(),
+        };
+        Ok(MatchOptions {
+            unknown_artists: self.unknown_artist.clone(),
+            sanitizer: match (self.sanitize, self.sanitize_pattern.is_empty()) {
+                (false, _) => None,
+                (true, true) => Some(Sanitizer::default()),
+                (true, false) => Some(Sanitizer::new(self.sanitize_pattern.clone())),
+            },
+            canonicalizer: ArtistCanonicalizer::new(aliases, self.transliterate),
+            repair_encoding: self.fix_encoding,
+            manual_matches,
+            duration_tolerance: Some(self.duration_tolerance).filter(|&t| t > 0),
+            duration_mismatch: self.duration_mismatch,
+        })
+    }
+
+    fn track_subset(&self) -> TrackSubset {
+        TrackSubset {
+            artists: self.filter_artist.clone(),
+            albums: self.filter_album.clone(),
+            genres: self.filter_genre.clone(),
+            limit: self.limit,
+        }
+    }
+
+    fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            strict: self.strict,
+            timezone: self.timezone,
+            resolve_missing_tracks: self.resolve_missing_tracks,
+            merge: self.merge,
+            include_videos: self.include_videos,
+            skip_disabled: false,
+            subset: self.track_subset(),
+            work_playlists: self.work_playlists,
+        }
+    }
+
+    fn read(&self) -> Result<ItunesLibrary> {
+        self.read_with(&self.read_options())
+    }
+
+    fn read_with(&self, options: &ReadOptions) -> Result<ItunesLibrary> {
+        itunes_library::read_libraries(&self.itunes_libraries, &self.library_name, options)
+    }
+}
+
+#[derive(Debug, Args)]
+struct SyncArgs {
+    /// Fields of the Rhythmbox database to migrate, separated by comma
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = Field::DEFAULT)]
+    fields: Vec<Field>,
+    /// Also migrate BPM and comments, for the tags which only live in iTunes
+    #[arg(long)]
+    sync_metadata: bool,
+    /// Rating given to loved tracks without explicit rating, 0 to disable
+    #[arg(long, value_name = "RATING", default_value_t = 5,
+          value_parser = clap::value_parser!(u8).range(0..=5))]
+    loved_rating: u8,
+    /// How to treat disliked tracks
+    #[arg(long, value_enum, default_value = "rating")]
+    disliked: DislikedAction,
+    /// Also migrate ratings computed by iTunes, e.g. from album ratings
+    #[arg(long)]
+    import_computed_ratings: bool,
+    /// Give tracks without rating the rating of their album
+    #[arg(long)]
+    propagate_album_ratings: bool,
+    /// How to treat tracks disabled (unchecked) in iTunes
+    #[arg(long, value_enum, default_value = "keep")]
+    disabled: DisabledAction,
+    /// What to do when a Rhythmbox song already has a different value
+    #[arg(long, value_enum, default_value = "overwrite")]
+    conflict_policy: ConflictPolicy,
+}
+
+impl SyncArgs {
+    fn sync_options(&self) -> SyncOptions {
+        SyncOptions {
+            fields: if self.sync_metadata {
+                let mut fields = self.fields.clone();
+                fields.extend(Field::METADATA.iter().filter(|f| !self.fields.contains(f)));
+                fields
+            } else {
+                self.fields.clone()
+            },
+            loved_rating: Some(self.loved_rating).filter(|&rating| rating > 0),
+            disliked_rating: self.disliked.rating(),
+            computed_ratings: self.import_computed_ratings,
+            album_ratings: self.propagate_album_ratings,
+            hide_disabled: self.disabled == DisabledAction::Hide,
+            conflict_policy: self.conflict_policy,
+        }
+    }
+
+    fn read_itunes_library(&self, library: &LibraryArgs) -> Result<ItunesLibrary> {
+        library.read_with(&ReadOptions {
+            skip_disabled: self.disabled == DisabledAction::Skip,
+            ..library.read_options()
+        })
+    }
+}
+
+/// Options of which playlists to migrate and how.
+#[derive(Debug, Args)]
+struct PlaylistArgs {
+    /// Only migrate playlists matching the given name, can be repeated
+    ///
+    /// Glob patterns like `Party *` are supported.
+    #[arg(long, value_name = "NAME")]
+    playlist: Vec<Pattern>,
+    /// Don't migrate playlists matching the given name, can be repeated
+    ///
+    /// Glob patterns like `Party *` are supported.
+    #[arg(long, value_name = "NAME")]
+    exclude_playlist: Vec<Pattern>,
+    /// Remove duplicate items from playlists, either all or only consecutive ones
+    #[arg(long, value_enum, value_name = "MODE", num_args = 0..=1,
+          default_missing_value = "all")]
+    dedupe_playlist_items: Option<DedupeMode>,
+    /// Migrate the playlist of the given name into the play queue of Rhythmbox,
+    /// e.g. one kept for what is currently being listened to
+    ///
+    /// The play queue is replaced rather than being added as a static playlist.
+    #[arg(long, value_name = "NAME")]
+    queue_playlist: Option<String>,
+}
+
+impl PlaylistArgs {
+    fn playlist_filter(&self) -> PlaylistFilter {
+        PlaylistFilter {
+            include: self.playlist.clone(),
+            exclude: self.exclude_playlist.clone(),
+            exclude_tracks: HashSet::new(),
+            dedupe_items: self.dedupe_playlist_items,
+            queue_playlist: self.queue_playlist.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct VerifyArgs {
+    #[command(flatten)]
+    library: LibraryArgs,
+    #[command(flatten)]
+    sync: SyncArgs,
+    #[command(flatten)]
+    playlists: PlaylistArgs,
+    /// Expect DRM-protected tracks to have been included in playlists
+    #[arg(long)]
+    include_protected: bool,
+}
+
+#[derive(Debug, Args)]
+struct MigrateArgs {
+    #[command(flatten)]
+    library: LibraryArgs,
+    #[command(flatten)]
+    sync: SyncArgs,
+    /// Don't modify the Rhythmbox database
+    #[arg(long, conflicts_with = "skip_playlists")]
+    skip_db: bool,
+    /// Don't migrate playlists
+    #[arg(long)]
+    skip_playlists: bool,
+    /// Create empty Rhythmbox database and playlists files if they don't exist yet
+    ///
+    /// Songs can then be added from iTunes via `--copy-missing-files`.
+    #[arg(long)]
+    bootstrap: bool,
+    /// Only synchronize iTunes tracks which have changed since the last incremental migration
+    ///
+    /// What has been written for each track is recorded in the Rhythmbox data directory,
+    /// so that other entries are left untouched.
+    #[arg(long, conflicts_with = "skip_db")]
+    incremental: bool,
+    /// Export playlists as standalone files into the given directory
+    ///
+    /// Rhythmbox files are not modified when this is specified.
+    #[arg(long, value_name = "DIR")]
+    export_playlists: Option<PathBuf>,
+    /// Write the migrated database and playlists into the given directory,
+    /// rather than replacing those in the Rhythmbox data directory
+    ///
+    /// No backup is made, and the Rhythmbox data directory is only read, so it can be read-only.
+    /// The state of `--incremental` is kept in the given directory as well.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["bootstrap", "export_playlists"])]
+    output_dir: Option<PathBuf>,
+    /// Format of the exported playlists
+    #[arg(long, value_enum, default_value = "m3u8")]
+    export_format: ExportFormat,
+    /// Replace prefix of iTunes locations, in the form of OLD=NEW
+    ///
+    /// This is used for tracks which are not found in Rhythmbox.
+    #[arg(long, value_name = "OLD=NEW")]
+    map_location: Vec<LocationMapping>,
+    #[command(flatten)]
+    playlists: PlaylistArgs,
+    /// List playlists in the iTunes library without migrating anything
+    #[arg(long)]
+    list_playlists: bool,
+    /// Show the changes to each playlist without migrating anything
+    ///
+    /// Added and removed locations are shown in a unified-diff-like form,
+    /// followed by the iTunes tracks which can't be found in Rhythmbox, marked with `?`.
+    #[arg(long, conflicts_with_all = ["bootstrap", "export_playlists", "skip_playlists"])]
+    diff: bool,
+    /// Write album art of migrated tracks as `cover.jpg` or `cover.png` files
+    /// into the directories of the audio files, where Rhythmbox picks them up
+    ///
+    /// This adds files to your music directories.
+    /// Directories which already have a cover image are left untouched.
+    #[arg(long)]
+    write_cover_images: bool,
+    /// Path to the iTunes Album Artwork directory, can be repeated
+    ///
+    /// Defaults to `Album Artwork` next to each iTunes Library file.
+    #[arg(long, value_name = "DIR", requires = "write_cover_images")]
+    artwork_dir: Vec<PathBuf>,
+    /// Write a shell script which trims files of songs with start or stop time in iTunes
+    /// via `ffmpeg`, as Rhythmbox doesn't keep them
+    ///
+    /// The script replaces each file by its trimmed copy when run, so review it first.
+    #[arg(long, value_name = "PATH")]
+    trim_script: Option<PathBuf>,
+    /// List Rhythmbox songs which are not in the iTunes library, and optionally hide or remove them
+    #[arg(long, value_enum, value_name = "ACTION", num_args = 0..=1,
+          default_missing_value = "list", conflicts_with = "skip_db")]
+    prune_db: Option<PruneAction>,
+    /// Copy files of iTunes tracks not in Rhythmbox into the given directory,
+    /// and add them to the Rhythmbox database
+    ///
+    /// Files are looked up at their iTunes locations, after applying `--map-location`.
+    #[arg(long, value_name = "DEST", conflicts_with = "skip_db")]
+    copy_missing_files: Option<PathBuf>,
+    /// How to put the missing files into the destination directory
+    #[arg(
+        long,
+        value_enum,
+        default_value = "copy",
+        requires = "copy_missing_files"
+    )]
+    copy_mode: CopyMode,
+    /// Don't skip DRM-protected tracks when copying missing files and migrating playlists
+    #[arg(long)]
+    include_protected: bool,
+    /// Check that files of matched songs exist, and list those which don't,
+    /// optionally excluding them from migrated playlists
+    #[arg(long, value_enum, value_name = "ACTION", num_args = 0..=1,
+          default_missing_value = "report")]
+    check_files: Option<CheckFilesAction>,
+    /// Don't ask for confirmation before hiding or removing songs
+    #[arg(long, short)]
+    yes: bool,
+    /// Keep running, and migrate again whenever the iTunes Library file changes
+    ///
+    /// Backups from the first migration are kept, and nothing is done while Rhythmbox is running.
+    #[arg(long, conflicts_with_all = ["export_playlists", "list_playlists"])]
+    watch: bool,
+    /// Interval in seconds between checks for changes in watch mode
+    #[arg(long, value_name = "SECONDS", default_value_t = 60, requires = "watch")]
+    watch_interval: u64,
 }
 
 fn main() -> Result<()> {
-    let opt = Opt::parse();
-    stderrlog::new()
-        .module(module_path!())
-        // 2 for info level
-        .verbosity(2)
-        .quiet(opt.quiet)
-        .timestamp(Timestamp::Off)
-        .init()?;
+    let opt = Opt::parse_from(config::apply_config(env::args_os().collect())?);
+    if opt.log_fd.is_some() && opt.log_format != LogFormat::Json {
+        Opt::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--log-fd requires --log-format json",
+            )
+            .exit();
+    }
+    // Asking about each duration mismatch only makes sense when migrating interactively.
+    let read_only_library = match &opt.command {
+        Some(Command::Report(args)) => Some(&args.library),
+        Some(Command::Verify(args)) => Some(&args.library),
+        Some(Command::Why(args)) => Some(&args.library),
+        _ => None,
+    };
+    if read_only_library
+        .is_some_and(|args| args.duration_mismatch == DurationMismatchAction::Prompt)
+    {
+        Opt::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--duration-mismatch prompt can only be used with migrate",
+            )
+            .exit();
+    }
+    if let Some(Command::Migrate(args)) = &opt.command {
+        if args.library.duration_mismatch == DurationMismatchAction::Prompt
+            && !io::stdin().is_terminal()
+        {
+            Opt::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    "--duration-mismatch prompt requires stdin to be a terminal",
+                )
+                .exit();
+        }
+    }
+    logger::init(LogOptions {
+        module: module_path!(),
+        verbosity: opt.verbose,
+        quiet: opt.quiet,
+        format: opt.log_format,
+        fd: opt.log_fd,
+        file: opt.log_file.as_deref(),
+    })?;
+
+    // Commands which don't involve Rhythmbox are run before looking for it.
+    let command = match opt.command {
+        _ if opt.generate_manpage => {
+            return clap_mangen::Man::new(Opt::command())
+                .render(&mut io::stdout())
+                .context("failed to write man page");
+        }
+        Some(Command::Completions { shell }) => {
+            let mut command = Opt::command();
+            let name = command.get_name().to_owned();
+            clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+            return Ok(());
+        }
+        Some(Command::GenFixture(args)) => {
+            let mut size = FixtureSize::with_tracks(args.tracks);
+            if let Some(playlists) = args.playlists {
+                size.playlist_count = playlists;
+            }
+            return fixture::write_fixture(&args.output, &size);
+        }
+        Some(command) => command,
+        None => Opt::command()
+            .error(ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit(),
+    };
 
     // Determine path of Rhythmbox data directory.
     let rhythmbox_path = match opt.rhythmbox_path {
         Some(path) => path,
         None => {
-            let mut path = dirs::data_dir().ok_or_else(|| {
+            let (installation, path) = installation::detect().ok_or_else(|| {
                 anyhow!("No data dir available, please specify path to Rhythmbox data dir")
             })?;
-            path.push("rhythmbox");
+            info!("Detected {} installation of Rhythmbox", installation);
             path
         }
     };
     info!("Rhythmbox path: {}", rhythmbox_path.display());
 
-    info!("Reading iTunes library...");
-    let mut itunes_library: ItunesLibrary =
-        plist::from_file(&opt.itunes_library).context("failed to read iTunes library")?;
-    // Strip movies from the library.
-    itunes_library.tracks.retain(|_, track| !track.movie);
-    let itunes_track_map = itunes_library
-        .tracks
-        .values()
-        .map(|track| {
-            let key = TrackKey::from(track);
-            (key, track)
-        })
-        .collect::<HashMap<_, _>>();
+    let remote_dir = match RemoteDir::parse(&rhythmbox_path)? {
+        Some(remote_dir) => remote_dir,
+        None => return run(&rhythmbox_path, &command, false, opt.assume_compatible),
+    };
+    // Options working on the music files would look for them on the local machine.
+    let local_files_option = match &command {
+        Command::Migrate(args) if args.watch => Some("--watch"),
+        Command::Migrate(args) if args.copy_missing_files.is_some() => Some("--copy-missing-files"),
+        Command::Migrate(args) if args.check_files.is_some() => Some("--check-files"),
+        Command::Migrate(args) if args.write_cover_images => Some("--write-cover-images"),
+        Command::Migrate(args) if args.trim_script.is_some() => Some("--trim-script"),
+        Command::Report(args) if args.check_files => Some("--check-files"),
+        _ => None,
+    };
     ensure!(
-        itunes_track_map.len() == itunes_library.tracks.len(),
-        "duplicate song in iTunes library"
+        local_files_option.is_none(),
+        "{} is not supported for remote Rhythmbox",
+        local_files_option.unwrap_or_default(),
     );
+    // Work on a local copy of the files, and upload those changed after success.
+    let local_copy = remote_dir.download()?;
+    if let Command::Migrate(args) = &command {
+        ensure!(
+            args.output_dir.is_some() || !local_copy.is_rhythmbox_running()?,
+            "Rhythmbox is running on the remote machine, quit it before migrating, \
+             as it would overwrite the changes",
+        );
+    }
+    run(local_copy.path(), &command, true, opt.assume_compatible)?;
+    local_copy.upload()
+}
 
-    let (rhythmdb_path, playlists_path) =
-        backup_rhythmbox_files(&rhythmbox_path).context("failed to backup Rhythmbox files")?;
-
-    let track_locations = sync_to_database(&rhythmdb_path, &itunes_track_map)
-        .context("failed to synchronize to Rhythmbox database")?;
-
-    migrate_playlists(&playlists_path, &itunes_library, &track_locations)
-        .context("failed to migrate playlists")?;
+/// Run the command on the Rhythmbox data directory,
+/// which is a local copy of the files if `remote` is set,
+/// in which case whether Rhythmbox is running is checked on the remote machine beforehand.
+///
+/// Rhythmbox files of unsupported formats are accepted if `assume_compatible` is set.
+fn run(
+    rhythmbox_path: &Path,
+    command: &Command,
+    remote: bool,
+    assume_compatible: bool,
+) -> Result<()> {
+    match command {
+        Command::Migrate(args) if args.watch => watch(rhythmbox_path, args, assume_compatible),
+        Command::Migrate(args) => migrate(rhythmbox_path, args, true, !remote, assume_compatible),
+        Command::Restore => {
+            backup::restore_rhythmbox_files(rhythmbox_path)
+                .context("failed to restore Rhythmbox files")?;
+            sync_state::remove_sync_state(rhythmbox_path)?;
+            journal::remove_journal(rhythmbox_path)
+        }
+        Command::Report(args) => report(rhythmbox_path, args, assume_compatible),
+        Command::Verify(args) => verify(rhythmbox_path, args, assume_compatible),
+        Command::Why(args) => why(rhythmbox_path, args, assume_compatible),
+        Command::ExportItunes(args) => {
+            let rhythmdb = rhythmdb::read_database(
+                &rhythmbox_path.join(RHYTHMDB_FILENAME),
+                assume_compatible,
+            )?;
+            let playlists = playlists::read_playlists(
+                &rhythmbox_path.join(PLAYLISTS_FILENAME),
+                assume_compatible,
+            )?;
+            itunes_export::export_itunes_library(&rhythmdb, &playlists, &args.output)?;
+            Ok(())
+        }
+        Command::Completions { .. } | Command::GenFixture(_) => unreachable!(),
+    }
+}
 
-    Ok(())
+/// Migrate whenever the iTunes Library files change, until interrupted.
+///
+/// Changes are batched by waiting until the files stay unchanged for an interval,
+/// as the files may be copied over in several steps.
+fn watch(rhythmbox_path: &Path, args: &MigrateArgs, assume_compatible: bool) -> Result<()> {
+    let interval = Duration::from_secs(args.watch_interval);
+    let modified_times = || {
+        args.library
+            .itunes_libraries
+            .iter()
+            .map(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut migrated_times = None;
+    let mut last_times = modified_times();
+    loop {
+        if migrated_times.as_ref() != Some(&last_times) {
+            if installation::is_running() {
+                info!("Rhythmbox is running, waiting for it to quit before migrating...");
+            } else {
+                let backup = migrated_times.is_none();
+                migrate(rhythmbox_path, args, backup, true, assume_compatible)?;
+                migrated_times = Some(last_times.clone());
+                info!("Watching for changes in the iTunes library...");
+            }
+        }
+        thread::sleep(interval);
+        let times = modified_times();
+        if times != last_times {
+            debug!("iTunes library changed, waiting for it to settle...");
+            last_times = times;
+            // Wait until an interval passes without change before migrating.
+            loop {
+                thread::sleep(interval);
+                let times = modified_times();
+                if times == last_times {
+                    break;
+                }
+                last_times = times;
+            }
+        }
+    }
 }
 
-fn backup_rhythmbox_files(rhythmbox_path: &Path) -> Result<(PathBuf, PathBuf)> {
-    info!("Backing up existing Rhythmbox files...");
-    const RHYTHMDB_FILENAME: &str = "rhythmdb.xml";
-    const RHYTHMDB_BACKUP_FILENAME: &str = "rhythmdb.xml.bak";
-    let rhythmdb_path = rhythmbox_path.join(RHYTHMDB_FILENAME);
-    let rhythmdb_bak = rhythmbox_path.join(RHYTHMDB_BACKUP_FILENAME);
+/// Migrate the iTunes library into Rhythmbox.
+///
+/// Rhythmbox files are backed up first if `backup` is set,
+/// otherwise they are expected to have been backed up by a previous run.
+fn migrate(
+    rhythmbox_path: &Path,
+    args: &MigrateArgs,
+    backup: bool,
+    check_running: bool,
+    assume_compatible: bool,
+) -> Result<()> {
     ensure!(
-        !rhythmdb_bak.exists(),
-        "backup of database already exists: {}",
-        rhythmdb_bak.display(),
+        args.prune_db.is_none() || !args.library.track_subset().is_restricted(),
+        "--prune-db can't be used with a subset of the iTunes library",
     );
-    fs::copy(&rhythmdb_path, &rhythmdb_bak)?;
-    const PLAYLISTS_FILENAME: &str = "playlists.xml";
-    const PLAYLISTS_BACKUP_FILENAME: &str = "playlists.xml.bak";
+    let mut summary = MigrationSummary::default();
+    let start = Instant::now();
+    let itunes_library = args.sync.read_itunes_library(&args.library)?;
+    summary.record_phase("reading iTunes", start);
+    if args.list_playlists {
+        list_playlists(&itunes_library);
+        return Ok(());
+    }
+    let artwork_dirs = if args.artwork_dir.is_empty() {
+        args.library
+            .itunes_libraries
+            .iter()
+            .filter_map(|path| path.parent())
+            .map(|dir| dir.join("Album Artwork"))
+            .filter(|dir| dir.is_dir())
+            .collect()
+    } else {
+        args.artwork_dir.clone()
+    };
+    let options = MigrationOptions {
+        match_options: args.library.match_options()?,
+        sync_options: args.sync.sync_options(),
+        disliked: args.sync.disliked,
+        disabled: args.sync.disabled,
+        playlist_filter: args.playlists.playlist_filter(),
+        exclude_protected: !args.include_protected,
+        skip_db: args.skip_db,
+        skip_playlists: args.skip_playlists,
+        incremental: args.incremental,
+        backup,
+        check_running,
+        assume_compatible,
+        output_dir: args.output_dir.clone(),
+        prune_db: args.prune_db,
+        copy_missing_files: args.copy_missing_files.clone(),
+        copy_mode: args.copy_mode,
+        location_mappings: args.map_location.clone(),
+        check_files: args.check_files,
+        trim_script: args.trim_script.clone(),
+        write_cover_images: Some(artwork_dirs).filter(|_| args.write_cover_images),
+    };
+
+    let rhythmdb_path = rhythmbox_path.join(RHYTHMDB_FILENAME);
     let playlists_path = rhythmbox_path.join(PLAYLISTS_FILENAME);
-    let playlists_bak = rhythmbox_path.join(PLAYLISTS_BACKUP_FILENAME);
-    ensure!(
-        !playlists_bak.exists(),
-        "backup of playlists already exists: {}",
-        playlists_bak.display(),
-    );
-    fs::copy(&playlists_path, &playlists_bak)?;
-    Ok((rhythmdb_path, playlists_path))
-}
 
-fn sync_to_database(
-    rhythmdb_path: &Path,
-    itunes_track_map: &HashMap<TrackKey<'_>, &Track>,
-) -> Result<HashMap<TrackId, String>> {
-    info!("Reading Rhythmbox database...");
-    let rhythmdb = File::open(rhythmdb_path).context("failed to open database file")?;
-    let mut rhythmdb =
-        Element::from_reader(BufReader::new(rhythmdb)).context("failed to read database")?;
-    ensure!(
-        rhythmdb.tag() == &QName::from("rhythmdb"),
-        "unknown database format",
-    );
+    if let Some(export_dir) = &args.export_playlists {
+        let itunes_track_map = matching::build_track_map(&itunes_library)?;
+        let rhythmdb = rhythmdb::read_database(&rhythmdb_path, assume_compatible)?;
+        let database_match =
+            rhythmdb::match_database(&rhythmdb, &itunes_track_map, &options.match_options)
+                .context("failed to match Rhythmbox database")?;
+        playlists::export_playlists(
+            export_dir,
+            args.export_format,
+            &itunes_library,
+            &rhythmdb::track_locations(&database_match.entries),
+            &args.map_location,
+            &options.playlist_filter(&itunes_library),
+        )
+        .context("failed to export playlists")?;
+        return Ok(());
+    }
+
+    if args.bootstrap {
+        backup::create_missing_files(rhythmbox_path).context("failed to create Rhythmbox files")?;
+    }
     ensure!(
-        rhythmdb.get_attr("version") == Some("2.0"),
-        "unknown database version",
+        rhythmdb_path.exists(),
+        "Rhythmbox database doesn't exist: {}, \
+         start Rhythmbox once or use --bootstrap to create an empty one",
+        rhythmdb_path.display(),
     );
-
-    info!("Synchronizing to Rhythmbox database...");
-    let mut unused_itunes_tracks = itunes_track_map
-        .values()
-        .copied()
-        .map(ByAddress)
-        .collect::<HashSet<_>>();
-    let mut track_locations = HashMap::with_capacity(itunes_track_map.len());
-    for entry in rhythmdb.children_mut() {
-        ensure!(
-            entry.tag() == &QName::from("entry"),
-            "unknown entry element in database"
+    if args.diff {
+        let itunes_track_map = matching::build_track_map(&itunes_library)?;
+        let rhythmdb = rhythmdb::read_database(&rhythmdb_path, assume_compatible)?;
+        let database_match =
+            rhythmdb::match_database(&rhythmdb, &itunes_track_map, &options.match_options)
+                .context("failed to match Rhythmbox database")?;
+        let track_locations = rhythmdb::track_locations(&database_match.entries);
+        let playlist_filter = options.playlist_filter(&itunes_library);
+        let before = playlists::read_playlists(&playlists_path, assume_compatible)?;
+        let state_path = args.output_dir.as_deref().unwrap_or(rhythmbox_path);
+        let migrated_playlists = SyncState::open(state_path)?.playlists;
+        let mut after = before.clone();
+        playlists::migrate_playlists(
+            &mut after,
+            &itunes_library,
+            &track_locations,
+            &playlist_filter,
+            &mut migrated_playlists.clone(),
+        );
+        let diffs = playlist_diff::diff_playlists(
+            &before,
+            &after,
+            &itunes_library,
+            &track_locations,
+            &playlist_filter,
+            &migrated_playlists,
         );
-        if entry.get_attr("type") != Some("song") {
-            continue;
+        for diff in diffs {
+            print!("{}", diff);
         }
-        // Read the metadata of the entry.
-        let child_text = |tag: &'static str| entry.find(tag).map(Element::text);
-        let name = child_text("title").expect("song without name");
-        let artist = child_text("artist");
-        let album = child_text("album");
-        let disc_number = child_text("disc-number").map(str::parse).transpose()?;
-        let track_number = child_text("track-number").map(str::parse).transpose()?;
-        let location = child_text("location")
-            .expect("song without location")
-            .to_owned();
-        // Fixup known "unknown" artist.
-        let artist = match artist {
-            Some("未知") => None,
-            artist => artist,
-        };
-        let key = TrackKey {
-            name,
-            artist,
-            album,
-            disc_number,
-            track_number,
-        };
+        return Ok(());
+    }
 
-        let track = match itunes_track_map.get(&key) {
-            Some(track) => {
-                unused_itunes_tracks.remove(&ByAddress(*track));
-                track_locations.insert(track.id, location);
-                *track
-            }
-            None => {
-                warn!("song {} not found", key);
-                continue;
-            }
-        };
-        // Create a new key from the iTunes track,
-        // so that we stop holding sharable borrows to the entry.
-        let key = TrackKey::from(track);
+    migration::migrate(
+        &itunes_library,
+        rhythmbox_path,
+        &options,
+        |database_match, action| confirm_prune(database_match, action, args.yes),
+        &mut summary,
+    )?;
+    print!("{}", summary);
+    Ok(())
+}
 
-        let mut update_or_append_child = |tag: &'static str, text: String| match entry.find_mut(tag)
-        {
-            Some(element) => {
-                if tag != "first-seen" {
-                    warn!("overriding {} of {}: {}", tag, key, element.text());
-                }
-                element.set_text(text);
-            }
-            None => {
-                let indentation = entry.text().to_string();
-                let last_element = entry.get_child_mut(entry.child_count() - 1).unwrap();
-                let mut element = Element::new(tag);
-                element.set_text(text);
-                element.set_tail(last_element.tail());
-                last_element.set_tail(indentation);
-                entry.append_child(element);
-            }
-        };
-        update_or_append_child("first-seen", track.date_added.timestamp().to_string());
-        if let Some(play_date) = track.play_date {
-            update_or_append_child("last-played", play_date.timestamp().to_string());
-        }
-        if let Some(play_count) = track.play_count {
-            if play_count > 0 {
-                update_or_append_child("play-count", play_count.to_string());
-            }
-        }
+fn confirm_prune(
+    database_match: &DatabaseMatch<'_>,
+    action: PruneAction,
+    yes: bool,
+) -> Result<Option<PruneAction>> {
+    for unmatched in database_match.unmatched_entries.iter() {
+        println!(
+            "{}\t{}",
+            unmatched.metadata.key(),
+            unmatched.metadata.location
+        );
     }
-    for track in unused_itunes_tracks {
-        warn!("song {} unused", TrackKey::from(*track));
+    let count = database_match.unmatched_entries.len();
+    info!("{} songs in Rhythmbox are not in iTunes", count);
+    if action == PruneAction::List || count == 0 {
+        return Ok(None);
+    }
+    if yes {
+        return Ok(Some(action));
+    }
+    let verb = match action {
+        PruneAction::Hide => "Hide",
+        _ => "Remove",
+    };
+    eprint!("{} {} songs from Rhythmbox? [y/N] ", verb, count);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if matches!(answer.trim(), "y" | "Y" | "yes") {
+        Ok(Some(action))
+    } else {
+        info!("Songs not in iTunes are kept");
+        Ok(None)
     }
-
-    info!("Saving the change to Rhythmbox database...");
-    let rhythmdb_file = File::create(rhythmdb_path).context("failed to open database to update")?;
-    let options = WriteOptions::new().set_xml_prolog(Some(XmlProlog::Version10));
-    rhythmdb
-        .to_writer_with_options(BufWriter::new(rhythmdb_file), options)
-        .context("failed to update database")?;
-
-    Ok(track_locations)
 }
 
-fn migrate_playlists(
-    playlists_path: &Path,
-    itunes_library: &ItunesLibrary,
-    track_locations: &HashMap<TrackId, String>,
-) -> Result<()> {
-    info!("Reading Rhythmbox playlists...");
-    let playlists = File::open(playlists_path).context("failed to open playlists file")?;
-    let mut playlists =
-        Element::from_reader(BufReader::new(playlists)).context("failed to read playlists")?;
-    ensure!(
-        playlists.tag() == &QName::from("rhythmdb-playlists"),
-        "unknown playlists format"
-    );
-
-    info!("Migrating playlists...");
-    playlists
-        .get_child_mut(playlists.child_count() - 1)
-        .unwrap()
-        .set_tail("\n  ");
+fn list_playlists(itunes_library: &ItunesLibrary) {
     for playlist in itunes_library.playlists.iter() {
-        if playlist.smart_info.is_some() {
-            // Skip smart playlists, until we are able to parse and convert them.
-            warn!("playlist {} is skipped because it's smart", playlist.name);
-            continue;
-        }
-        let mut playlist_element = Element::new("playlist");
-        playlist_element.set_attr("name", &playlist.name);
-        playlist_element.set_attr("type", "static");
-        playlist_element.set_text("\n    ");
-        let mut unfound_count = 0;
-        for item in playlist.items.iter() {
-            let location = match track_locations.get(&item.id) {
-                Some(location) => location,
-                None => {
-                    unfound_count += 1;
-                    continue;
-                }
-            };
-            let mut location_element = Element::new("location");
-            location_element.set_text(location);
-            location_element.set_tail("\n    ");
-            playlist_element.append_child(location_element);
-        }
-        let item_count = playlist_element.child_count();
-        if item_count > 0 {
-            playlist_element
-                .get_child_mut(item_count - 1)
-                .unwrap()
-                .set_tail("\n  ");
+        let kind = if playlist.smart_info.is_some() {
+            "smart"
         } else {
-            playlist_element.set_text("");
+            "static"
+        };
+        println!(
+            "{}\t{}\t{} items",
+            playlist.name,
+            kind,
+            playlist.items.len()
+        );
+    }
+}
+
+fn report(rhythmbox_path: &Path, args: &ReportArgs, assume_compatible: bool) -> Result<()> {
+    let itunes_library = args.library.read()?;
+    let itunes_track_map = matching::build_track_map(&itunes_library)?;
+    let rhythmdb =
+        rhythmdb::read_database(&rhythmbox_path.join(RHYTHMDB_FILENAME), assume_compatible)?;
+    let database_match =
+        rhythmdb::match_database(&rhythmdb, &itunes_track_map, &args.library.match_options()?)
+            .context("failed to match Rhythmbox database")?;
+    let mut report = MatchReport::new(&itunes_library, &database_match);
+    if args.check_files {
+        let dead_entries = missing_files::find_dead_entries(&database_match.entries);
+        let missing_files = dead_entries.into_iter().map(ReportedTrack::matched);
+        report.missing_files = Some(missing_files.collect());
+    }
+    if args.compare {
+        report.comparison = Some(PlayComparison::new(&rhythmdb, &database_match));
+    }
+    match &args.output {
+        Some(path) => {
+            let mut writer = compression::create(path)?;
+            write!(writer, "{}", report)?;
+            writer.finish().context("failed to write report")?;
         }
-        playlist_element.set_tail("\n  ");
-        playlists.append_child(playlist_element);
-        if unfound_count > 0 {
-            warn!(
-                "{} items in playlist {} are not found",
-                unfound_count, playlist.name
-            );
+        None => print!("{}", report),
+    }
+    Ok(())
+}
+
+fn why(rhythmbox_path: &Path, args: &WhyArgs, assume_compatible: bool) -> Result<()> {
+    let itunes_library = args.library.read()?;
+    let itunes_track_map = matching::build_track_map(&itunes_library)?;
+    let matches = |value: Option<&str>, query: &Option<String>| match query {
+        Some(query) => value.is_some_and(|value| value.eq_ignore_ascii_case(query)),
+        None => true,
+    };
+    let mut tracks = itunes_library
+        .tracks
+        .values()
+        .filter(|track| track.name.eq_ignore_ascii_case(&args.title))
+        .filter(|track| matches(track.artist.as_deref(), &args.artist))
+        .filter(|track| matches(track.album.as_deref(), &args.album))
+        .collect::<Vec<_>>();
+    ensure!(!tracks.is_empty(), "no iTunes track titled {}", args.title);
+    tracks.sort_by_key(|track| track.id.0);
+    let match_options = args.library.match_options()?;
+    let rhythmdb =
+        rhythmdb::read_database(&rhythmbox_path.join(RHYTHMDB_FILENAME), assume_compatible)?;
+    let entries = rhythmdb::read_entries(&rhythmdb, &match_options)?;
+    for (i, track) in tracks.into_iter().enumerate() {
+        if i > 0 {
+            println!();
         }
+        let explanation = explain::explain(
+            track,
+            &entries,
+            &itunes_track_map,
+            &match_options,
+            args.candidates,
+        );
+        print!("{}", explanation);
     }
-    playlists
-        .get_child_mut(playlists.child_count() - 1)
-        .unwrap()
-        .set_tail("\n");
-
-    info!("Saving the playlists...");
-    let playlists_file =
-        File::create(playlists_path).context("failed to open playlists to update")?;
-    let options = WriteOptions::new().set_xml_prolog(Some(XmlProlog::Version10));
-    playlists
-        .to_writer_with_options(BufWriter::new(playlists_file), options)
-        .context("failed to update playlists")?;
+    Ok(())
+}
 
+fn verify(rhythmbox_path: &Path, args: &VerifyArgs, assume_compatible: bool) -> Result<()> {
+    let itunes_library = args.sync.read_itunes_library(&args.library)?;
+    let itunes_track_map = matching::build_track_map(&itunes_library)?;
+    let rhythmdb =
+        rhythmdb::read_database(&rhythmbox_path.join(RHYTHMDB_FILENAME), assume_compatible)?;
+    let database_match =
+        rhythmdb::match_database(&rhythmdb, &itunes_track_map, &args.library.match_options()?)
+            .context("failed to match Rhythmbox database")?;
+    let playlists =
+        playlists::read_playlists(&rhythmbox_path.join(PLAYLISTS_FILENAME), assume_compatible)?;
+    let mut playlist_filter = args.playlists.playlist_filter();
+    playlist_filter
+        .exclude_tracks
+        .extend(migration::excluded_tracks(
+            &itunes_library,
+            args.sync.disliked,
+            args.sync.disabled,
+            !args.include_protected,
+        ));
+    let migrated_playlists = SyncState::open(rhythmbox_path)?.playlists;
+    let problem_count =
+        verify::verify_database(&rhythmdb, &database_match, &args.sync.sync_options())
+            + verify::verify_playlists(
+                &playlists,
+                &rhythmdb,
+                &itunes_library,
+                &rhythmdb::track_locations(&database_match.entries),
+                &playlist_filter,
+                &migrated_playlists,
+            );
+    ensure!(
+        problem_count == 0,
+        "{} problems found in migrated Rhythmbox files",
+        problem_count
+    );
+    info!("Rhythmbox files reflect the iTunes library");
     Ok(())
 }
@@ -0,0 +1,349 @@
+use crate::event::Event;
+use crate::itunes_library::{ItunesLibrary, Track, TrackId};
+use crate::track_key::{self, ArtistCanonicalizer, SanitizedKey, Sanitizer, TrackKey};
+use anyhow::{anyhow, bail, ensure, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn, Level};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Options of matching Rhythmbox entries with iTunes tracks.
+#[derive(Debug)]
+pub struct MatchOptions {
+    /// Artist names Rhythmbox gives songs without artist, e.g. `未知` in Chinese locale.
+    pub unknown_artists: Vec<String>,
+    /// Sanitizer of names for songs which don't match otherwise.
+    pub sanitizer: Option<Sanitizer>,
+    /// Canonicalizer of artist names for songs which don't match otherwise.
+    pub canonicalizer: ArtistCanonicalizer,
+    /// Whether to repair mojibake in names of songs which don't match otherwise.
+    pub repair_encoding: bool,
+    /// Persistent IDs of iTunes tracks to match with Rhythmbox songs regardless of metadata,
+    /// keyed by the location of the songs.
+    pub manual_matches: HashMap<String, String>,
+    /// Maximum difference in seconds between durations of matched songs, if checked.
+    pub duration_tolerance: Option<u64>,
+    pub duration_mismatch: DurationMismatchAction,
+}
+
+/// What to do with matched songs whose durations differ, e.g. a radio edit and an album version.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum DurationMismatchAction {
+    /// Keep the match with a warning
+    Warn,
+    /// Treat the songs as not matched
+    Unmatch,
+    /// Ask whether to keep each match, only when migrating from a terminal
+    Prompt,
+}
+
+/// Snapshot of the metadata of a Rhythmbox database entry used for matching.
+///
+/// Entries are snapshotted so that matching can happen in parallel
+/// without holding borrows into the database tree, which is mutated afterwards.
+#[derive(Clone, Debug)]
+pub struct EntryMetadata {
+    pub name: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub disc_number: Option<usize>,
+    pub track_number: Option<usize>,
+    pub location: String,
+    /// Duration in seconds.
+    pub duration: Option<u64>,
+}
+
+impl EntryMetadata {
+    pub fn key(&self) -> TrackKey<'_> {
+        TrackKey {
+            name: &self.name,
+            artist: self.artist.as_deref(),
+            album: self.album.as_deref(),
+            disc_number: self.disc_number,
+            track_number: self.track_number,
+        }
+    }
+}
+
+pub type TrackMap<'a> = HashMap<TrackKey<'a>, &'a Track>;
+
+/// Build the map from track key to iTunes track.
+pub fn build_track_map(itunes_library: &ItunesLibrary) -> Result<TrackMap<'_>> {
+    let track_map = itunes_library
+        .tracks
+        .par_iter()
+        .map(|(_, track)| (TrackKey::from(track), track))
+        .collect::<HashMap<_, _>>();
+    ensure!(
+        track_map.len() == itunes_library.tracks.len(),
+        "duplicate song in iTunes library"
+    );
+    Ok(track_map)
+}
+
+/// Find the matching iTunes track for each of the given entries.
+///
+/// The result has the same order as the entries.
+pub fn match_entries<'a>(
+    entries: &[EntryMetadata],
+    track_map: &TrackMap<'a>,
+) -> Vec<Option<&'a Track>> {
+    entries
+        .par_iter()
+        .map(|entry| track_map.get(&entry.key()).copied())
+        .collect()
+}
+
+/// Check that durations of matched songs are within the tolerance,
+/// returning the number of mismatching songs.
+pub fn check_durations(
+    entries: &[EntryMetadata],
+    matched_tracks: &mut [Option<&Track>],
+    tolerance: u64,
+    action: DurationMismatchAction,
+) -> Result<usize> {
+    let mut mismatch_count = 0;
+    for (entry, matched) in entries.iter().zip(matched_tracks.iter_mut()) {
+        let (rhythmbox, itunes) = match (entry.duration, matched.and_then(|t| t.total_time)) {
+            (Some(rhythmbox), Some(itunes)) => (rhythmbox, (itunes + 500) / 1000),
+            _ => continue,
+        };
+        if rhythmbox.abs_diff(itunes) <= tolerance {
+            continue;
+        }
+        mismatch_count += 1;
+        let song = entry.key();
+        Event::DurationMismatch {
+            song,
+            rhythmbox,
+            itunes,
+        }
+        .log(Level::Warn);
+        let keep = match action {
+            DurationMismatchAction::Warn => true,
+            DurationMismatchAction::Unmatch => false,
+            DurationMismatchAction::Prompt => {
+                eprint!("Keep the match of {}? [y/N] ", song);
+                io::stderr().flush()?;
+                let mut answer = String::new();
+                if io::stdin().read_line(&mut answer)? == 0 {
+                    bail!("no answer about the match of {}, stdin is closed", song);
+                }
+                matches!(answer.trim(), "y" | "Y" | "yes")
+            }
+        };
+        if !keep {
+            info!("song {} is unmatched", song);
+            *matched = None;
+        }
+    }
+    Ok(mismatch_count)
+}
+
+/// Read the file of manual matches, with each line being the persistent ID of an iTunes track
+/// and the location of a Rhythmbox song separated by a tab.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn read_manual_matches(path: &Path) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)?;
+    let mut manual_matches = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (persistent_id, location) = line
+            .split_once('\t')
+            .ok_or_else(|| anyhow!("invalid manual match at line {}", i + 1))?;
+        manual_matches.insert(location.to_owned(), persistent_id.to_owned());
+    }
+    Ok(manual_matches)
+}
+
+/// Match the entries listed in the manual matches, overriding other matches.
+pub fn match_manually<'a>(
+    entries: &[EntryMetadata],
+    matched_tracks: &mut [Option<&'a Track>],
+    track_map: &TrackMap<'a>,
+    manual_matches: &HashMap<String, String>,
+) {
+    let tracks_by_persistent_id = track_map
+        .values()
+        .filter_map(|&track| Some((track.persistent_id.as_deref()?, track)))
+        .collect::<HashMap<_, _>>();
+    for (entry, matched) in entries.iter().zip(matched_tracks.iter_mut()) {
+        let persistent_id = match manual_matches.get(&entry.location) {
+            Some(persistent_id) => persistent_id,
+            None => continue,
+        };
+        match tracks_by_persistent_id.get(persistent_id.as_str()) {
+            Some(&track) => {
+                debug!("song {} matched {} manually", entry.key(), persistent_id);
+                *matched = Some(track);
+            }
+            None => warn!("track {} of manual match not found", persistent_id),
+        }
+    }
+}
+
+/// Match the entries not matched yet with sanitized keys.
+pub fn match_sanitized<'a>(
+    entries: &[EntryMetadata],
+    matched_tracks: &mut [Option<&'a Track>],
+    track_map: &TrackMap<'a>,
+    sanitizer: &Sanitizer,
+) {
+    let key = |key: &TrackKey<'_>| sanitizer.key(key);
+    match_normalized(entries, matched_tracks, track_map, key, "sanitization");
+}
+
+/// Match the entries not matched yet with mojibake repaired on either side,
+/// returning indices of the entries matched this way.
+pub fn match_repaired<'a>(
+    entries: &[EntryMetadata],
+    matched_tracks: &mut [Option<&'a Track>],
+    track_map: &TrackMap<'a>,
+) -> Vec<usize> {
+    match_normalized(
+        entries,
+        matched_tracks,
+        track_map,
+        track_key::repaired_key,
+        "encoding repair",
+    )
+}
+
+/// Match the entries not matched yet with canonicalized artist names.
+pub fn match_canonical<'a>(
+    entries: &[EntryMetadata],
+    matched_tracks: &mut [Option<&'a Track>],
+    track_map: &TrackMap<'a>,
+    canonicalizer: &ArtistCanonicalizer,
+) {
+    let key = |key: &TrackKey<'_>| canonicalizer.key(key);
+    match_normalized(entries, matched_tracks, track_map, key, "canonicalization");
+}
+
+/// Match the entries not matched yet with normalized keys,
+/// returning indices of the entries matched this way.
+///
+/// Normalized keys shared by multiple iTunes tracks are ambiguous, and thus not used.
+/// So are keys of tracks matched already, which would otherwise get a second entry.
+fn match_normalized<'a>(
+    entries: &[EntryMetadata],
+    matched_tracks: &mut [Option<&'a Track>],
+    track_map: &TrackMap<'a>,
+    normalize: impl Fn(&TrackKey<'_>) -> SanitizedKey + Sync,
+    normalization: &str,
+) -> Vec<usize> {
+    let normalized_keys = track_map
+        .par_iter()
+        .map(|(key, &track)| (normalize(key), track))
+        .collect::<Vec<_>>();
+    let matched_ids = matched_track_ids(matched_tracks);
+    let mut normalized_map = HashMap::with_capacity(normalized_keys.len());
+    for (key, track) in normalized_keys {
+        normalized_map
+            .entry(key)
+            .and_modify(|existing| *existing = None)
+            .or_insert_with(|| Some(track).filter(|track| !matched_ids.contains(&track.id)));
+    }
+    let mut newly_matched = Vec::new();
+    for (i, (entry, matched)) in entries.iter().zip(matched_tracks.iter_mut()).enumerate() {
+        if matched.is_some() {
+            continue;
+        }
+        if let Some(&Some(track)) = normalized_map.get(&normalize(&entry.key())) {
+            info!(
+                "song {} matched {} after {}",
+                entry.key(),
+                TrackKey::from(track),
+                normalization,
+            );
+            *matched = Some(track);
+            newly_matched.push(i);
+        }
+    }
+    newly_matched
+}
+
+/// IDs of iTunes tracks matched by any entry.
+fn matched_track_ids(matched_tracks: &[Option<&Track>]) -> HashSet<TrackId> {
+    matched_tracks
+        .iter()
+        .flatten()
+        .map(|track| track.id)
+        .collect()
+}
+
+/// Match the entries not matched yet with names combining the work and the movement,
+/// e.g. `Symphony No. 5 in C Minor, Op. 67: I. Allegro con brio`,
+/// which is how classical tracks are often titled in the files,
+/// while iTunes may show just the movement.
+///
+/// Names shared by multiple iTunes tracks, or by a track matched already,
+/// are ambiguous, and thus not used.
+pub fn match_works<'a>(
+    entries: &[EntryMetadata],
+    matched_tracks: &mut [Option<&'a Track>],
+    track_map: &TrackMap<'a>,
+) {
+    let work_names = track_map
+        .values()
+        .flat_map(|&track| work_names(track).into_iter().map(move |name| (name, track)))
+        .collect::<Vec<_>>();
+    let matched_ids = matched_track_ids(matched_tracks);
+    let mut work_map = HashMap::with_capacity(work_names.len());
+    for (name, track) in work_names.iter() {
+        let key = TrackKey {
+            name,
+            ..TrackKey::from(*track)
+        };
+        work_map
+            .entry(key)
+            .and_modify(|existing| *existing = None)
+            .or_insert_with(|| Some(*track).filter(|track| !matched_ids.contains(&track.id)));
+    }
+    for (entry, matched) in entries.iter().zip(matched_tracks.iter_mut()) {
+        if matched.is_some() {
+            continue;
+        }
+        if let Some(&Some(track)) = work_map.get(&entry.key()) {
+            info!(
+                "song {} matched {} by its work",
+                entry.key(),
+                TrackKey::from(track),
+            );
+            *matched = Some(track);
+        }
+    }
+}
+
+/// Alternative names of a movement of a classical work.
+pub fn work_names(track: &Track) -> Vec<String> {
+    let work = match track.work_name() {
+        Some(work) => work,
+        None => return Vec::new(),
+    };
+    let movement = track.movement_name.as_deref().unwrap_or(&track.name);
+    let mut names = vec![format!("{}: {}", work, movement)];
+    if let Some(number) = track.movement_number.and_then(roman_numeral) {
+        names.push(format!("{}: {}. {}", work, number, movement));
+    }
+    if movement != track.name {
+        names.push(movement.to_owned());
+    }
+    names
+}
+
+/// Roman numeral of movement numbers, which rarely exceed a dozen.
+fn roman_numeral(number: usize) -> Option<&'static str> {
+    const NUMERALS: [&str; 20] = [
+        "I", "II", "III", "IV", "V", "VI", "VII", "VIII", "IX", "X", "XI", "XII", "XIII", "XIV",
+        "XV", "XVI", "XVII", "XVIII", "XIX", "XX",
+    ];
+    NUMERALS.get(number.checked_sub(1)?).copied()
+}
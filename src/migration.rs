@@ -0,0 +1,355 @@
+//! The migration flow shared by the `migrate` command and the C-compatible interface.
+
+use crate::artwork;
+use crate::backup::{self, RhythmboxFile};
+use crate::installation;
+use crate::itunes_library::{ItunesLibrary, TrackId};
+use crate::journal::{Journal, Phase};
+use crate::location::LocationMapping;
+use crate::matching::{self, MatchOptions};
+use crate::missing_files::{self, CheckFilesAction, CopyMode};
+use crate::playlists::{self, PlaylistFilter, PLAYLISTS_FILENAME};
+use crate::report::{MatchReport, MigrationSummary, ReportedTrack};
+use crate::rhythmdb::{self, DatabaseMatch, Field, PruneAction, SyncOptions, RHYTHMDB_FILENAME};
+use crate::sync_state::SyncState;
+use crate::trim_script;
+use crate::xml::{stage_xml, stage_xml_preserving};
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use log::info;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// How to treat tracks disliked in iTunes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum DislikedAction {
+    /// Give disliked tracks without explicit rating a rating of 1
+    Rating,
+    /// Exclude disliked tracks from migrated playlists
+    Exclude,
+    /// Don't treat disliked tracks specially
+    Ignore,
+}
+
+impl DislikedAction {
+    /// Rating given to disliked tracks without explicit rating, if any.
+    pub fn rating(self) -> Option<u8> {
+        match self {
+            DislikedAction::Rating => Some(1),
+            DislikedAction::Exclude | DislikedAction::Ignore => None,
+        }
+    }
+}
+
+/// How to treat tracks disabled (unchecked) in iTunes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum DisabledAction {
+    /// Migrate disabled tracks like other tracks
+    Keep,
+    /// Skip disabled tracks entirely
+    Skip,
+    /// Exclude disabled tracks from migrated playlists
+    Exclude,
+    /// Hide entries of disabled tracks in Rhythmbox
+    Hide,
+}
+
+/// Options of the migration, mirroring the options of the `migrate` command.
+#[derive(Debug)]
+pub struct MigrationOptions {
+    pub match_options: MatchOptions,
+    pub sync_options: SyncOptions,
+    pub disliked: DislikedAction,
+    pub disabled: DisabledAction,
+    /// Playlists to migrate, while tracks to exclude from them are added according to other options.
+    pub playlist_filter: PlaylistFilter,
+    /// Don't migrate DRM-protected tracks into playlists or copy their files.
+    pub exclude_protected: bool,
+    pub skip_db: bool,
+    pub skip_playlists: bool,
+    /// Only synchronize iTunes tracks which have changed since the last incremental migration.
+    pub incremental: bool,
+    /// Back up Rhythmbox files before modifying them,
+    /// otherwise they are expected to have been backed up by a previous run.
+    pub backup: bool,
+    /// Refuse to migrate while Rhythmbox is running, as it would overwrite the changes.
+    pub check_running: bool,
+    /// Accept Rhythmbox files of unsupported formats, assuming them compatible.
+    pub assume_compatible: bool,
+    /// Directory to write the migrated files into rather than the Rhythmbox data directory.
+    pub output_dir: Option<PathBuf>,
+    pub prune_db: Option<PruneAction>,
+    /// Directory to copy files of iTunes tracks not in Rhythmbox into.
+    pub copy_missing_files: Option<PathBuf>,
+    pub copy_mode: CopyMode,
+    pub location_mappings: Vec<LocationMapping>,
+    pub check_files: Option<CheckFilesAction>,
+    /// Path to write the shell script trimming files of songs with start or stop time.
+    pub trim_script: Option<PathBuf>,
+    /// Write cover images into the directories of the audio files,
+    /// taking album art from the given iTunes Album Artwork directories when available.
+    pub write_cover_images: Option<Vec<PathBuf>>,
+}
+
+impl MigrationOptions {
+    /// Filter of playlists to migrate, excluding tracks according to the options.
+    pub fn playlist_filter(&self, itunes_library: &ItunesLibrary) -> PlaylistFilter {
+        let mut filter = self.playlist_filter.clone();
+        filter.exclude_tracks.extend(excluded_tracks(
+            itunes_library,
+            self.disliked,
+            self.disabled,
+            self.exclude_protected,
+        ));
+        filter
+    }
+}
+
+/// Tracks to exclude from migrated playlists according to how to treat them.
+pub fn excluded_tracks(
+    itunes_library: &ItunesLibrary,
+    disliked: DislikedAction,
+    disabled: DisabledAction,
+    exclude_protected: bool,
+) -> HashSet<TrackId> {
+    itunes_library
+        .tracks
+        .values()
+        .filter(|track| {
+            (disliked == DislikedAction::Exclude && track.disliked)
+                || (exclude_protected && track.is_protected())
+                || (disabled == DisabledAction::Exclude && track.disabled)
+        })
+        .map(|track| track.id)
+        .collect()
+}
+
+/// Migrate the iTunes library into the Rhythmbox data directory.
+///
+/// Songs not in iTunes are only hidden or removed if `confirm_prune` returns the action to take,
+/// after being given the matched database and the requested action.
+/// Elapsed time of each phase is recorded into the summary.
+pub fn migrate(
+    itunes_library: &ItunesLibrary,
+    rhythmbox_path: &Path,
+    options: &MigrationOptions,
+    confirm_prune: impl FnOnce(&DatabaseMatch<'_>, PruneAction) -> Result<Option<PruneAction>>,
+    summary: &mut MigrationSummary,
+) -> Result<()> {
+    let rhythmdb_path = rhythmbox_path.join(RHYTHMDB_FILENAME);
+    let playlists_path = rhythmbox_path.join(PLAYLISTS_FILENAME);
+    ensure!(
+        rhythmdb_path.exists(),
+        "Rhythmbox database doesn't exist: {}, \
+         start Rhythmbox once or use --bootstrap to create an empty one",
+        rhythmdb_path.display(),
+    );
+    ensure!(
+        !options.check_running || options.output_dir.is_some() || !installation::is_running(),
+        "Rhythmbox is running, quit it before migrating, as it would overwrite the changes",
+    );
+    let itunes_track_map = matching::build_track_map(itunes_library)?;
+    let sync_options = &options.sync_options;
+    let mut playlist_filter = options.playlist_filter(itunes_library);
+
+    // Files to modify are written into the output directory if given,
+    // along with the journal, so that nothing is written into the Rhythmbox data directory.
+    let output_path = match &options.output_dir {
+        Some(output_dir) => {
+            fs::create_dir_all(output_dir).with_context(|| {
+                format!("failed to create output directory {}", output_dir.display())
+            })?;
+            output_dir.as_path()
+        }
+        None => rhythmbox_path,
+    };
+    let output_rhythmdb_path = output_path.join(RHYTHMDB_FILENAME);
+    let output_playlists_path = output_path.join(PLAYLISTS_FILENAME);
+    let mut journal = Journal::open(output_path)?;
+    let mut sync_state = SyncState::open(output_path)?;
+    if options.backup && options.output_dir.is_none() && !journal.is_completed(Phase::Backup) {
+        let mut files_to_modify = Vec::new();
+        if !options.skip_db {
+            files_to_modify.push(RhythmboxFile::Database);
+        }
+        if !options.skip_playlists {
+            files_to_modify.push(RhythmboxFile::Playlists);
+        }
+        backup::backup_rhythmbox_files(rhythmbox_path, &files_to_modify)
+            .context("failed to backup Rhythmbox files")?;
+        journal.complete(Phase::Backup)?;
+    }
+
+    let start = Instant::now();
+    // Continue from the migrated database when resuming.
+    let rhythmdb_path = if journal.is_completed(Phase::Database) {
+        &output_rhythmdb_path
+    } else {
+        &rhythmdb_path
+    };
+    let (mut rhythmdb, original_rhythmdb) =
+        rhythmdb::read_database_preserving(rhythmdb_path, options.assume_compatible)?;
+    let mut database_match =
+        rhythmdb::match_database(&rhythmdb, &itunes_track_map, &options.match_options)
+            .context("failed to match Rhythmbox database")?;
+    summary.record_phase("matching", start);
+    journal.check_synced_tracks(&database_match.entries);
+    let missing_files = options.check_files.map(|action| {
+        let dead_entries = missing_files::find_dead_entries(&database_match.entries);
+        if action == CheckFilesAction::Exclude {
+            playlist_filter
+                .exclude_tracks
+                .extend(dead_entries.iter().map(|matched| matched.track.id));
+        }
+        dead_entries
+            .into_iter()
+            .map(ReportedTrack::matched)
+            .collect::<Vec<_>>()
+    });
+    // Files are only replaced after all of them have been written,
+    // so that a failure in between leaves all of them untouched.
+    let mut staged_database = None;
+    if !options.skip_db && !journal.is_completed(Phase::Database) {
+        let start = Instant::now();
+        let prune_action = match options.prune_db {
+            Some(action) => confirm_prune(&database_match, action)?,
+            None => None,
+        };
+        if let Some(dest) = &options.copy_missing_files {
+            missing_files::copy_missing_files(
+                &mut rhythmdb,
+                &mut database_match,
+                dest,
+                options.copy_mode,
+                &options.location_mappings,
+                !options.exclude_protected,
+            )?;
+        }
+        let changed_entries;
+        let entries_to_sync = if options.incremental {
+            changed_entries = sync_state.changed_entries(
+                &database_match.entries,
+                database_match.schema,
+                sync_options,
+            );
+            &changed_entries
+        } else {
+            &database_match.entries
+        };
+        summary.sync = Some(rhythmdb::sync_to_database(
+            &mut rhythmdb,
+            entries_to_sync,
+            database_match.schema,
+            sync_options,
+        )?);
+        summarize_loved_and_disliked(&database_match, options);
+        if let Some(action) = prune_action {
+            rhythmdb::prune_database(
+                &mut rhythmdb,
+                &database_match.unmatched_entries,
+                database_match.schema,
+                action,
+            );
+        }
+        staged_database = Some(
+            stage_xml_preserving(&output_rhythmdb_path, &rhythmdb, &original_rhythmdb)
+                .context("failed to update database")?,
+        );
+        summary.record_phase("database", start);
+    }
+    let staged_trim_script = match &options.trim_script {
+        Some(path) => Some(trim_script::stage_trim_script(
+            path,
+            &database_match.entries,
+        )?),
+        None => None,
+    };
+    let mut staged_playlists = None;
+    if !options.skip_playlists && !journal.is_completed(Phase::Playlists) {
+        let start = Instant::now();
+        let mut playlists = playlists::read_playlists(&playlists_path, options.assume_compatible)?;
+        summary.playlists = Some(playlists::migrate_playlists(
+            &mut playlists,
+            itunes_library,
+            &rhythmdb::track_locations(&database_match.entries),
+            &playlist_filter,
+            &mut sync_state.playlists,
+        ));
+        staged_playlists = Some(
+            stage_xml(&output_playlists_path, &playlists).context("failed to update playlists")?,
+        );
+        summary.record_phase("playlists", start);
+    }
+
+    if let Some(staged) = staged_database {
+        info!("Saving the change to Rhythmbox database...");
+        staged.commit().context("failed to update database")?;
+        if options.incremental {
+            sync_state.record(&database_match.entries, database_match.schema, sync_options);
+            sync_state.save()?;
+        }
+        journal.record_synced_tracks(&database_match.entries);
+        journal.complete(Phase::Database)?;
+    }
+    if let Some(staged) = staged_playlists {
+        info!("Saving the playlists...");
+        staged.commit().context("failed to update playlists")?;
+        sync_state.save()?;
+        journal.complete(Phase::Playlists)?;
+    }
+    if let Some(staged) = staged_trim_script {
+        staged.commit().context("failed to write trim script")?;
+    }
+    // Cover images are written into music directories only once the other files are in place,
+    // so that nothing is written if migrating fails.
+    if let Some(artwork_dirs) = &options.write_cover_images {
+        if !journal.is_completed(Phase::Artwork) {
+            let start = Instant::now();
+            artwork::write_cover_images(&database_match.entries, artwork_dirs)?;
+            journal.complete(Phase::Artwork)?;
+            summary.record_phase("artwork", start);
+        }
+    }
+
+    journal.finish()?;
+    summary.matching = MatchReport::new(itunes_library, &database_match);
+    summary.matching.missing_files = missing_files;
+    Ok(())
+}
+
+fn summarize_loved_and_disliked(database_match: &DatabaseMatch<'_>, options: &MigrationOptions) {
+    let sync_options = &options.sync_options;
+    let rating_migrated = sync_options.fields.contains(&Field::Rating);
+    let unrated_tracks = || {
+        database_match
+            .entries
+            .iter()
+            .map(|matched| matched.track)
+            .filter(|track| {
+                track
+                    .effective_rating(sync_options.computed_ratings, sync_options.album_ratings)
+                    .is_none()
+            })
+    };
+    if let (true, Some(rating)) = (rating_migrated, sync_options.loved_rating) {
+        let count = unrated_tracks().filter(|track| track.loved).count();
+        info!("{} loved tracks are rated {}", count, rating);
+    }
+    match options.disliked {
+        DislikedAction::Rating if rating_migrated => {
+            let count = unrated_tracks().filter(|track| track.disliked).count();
+            info!("{} disliked tracks are rated 1", count);
+        }
+        DislikedAction::Exclude => {
+            let count = database_match
+                .entries
+                .iter()
+                .filter(|matched| matched.track.disliked)
+                .count();
+            info!("{} disliked tracks are excluded from playlists", count);
+        }
+        _ => {}
+    }
+}
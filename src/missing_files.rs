@@ -0,0 +1,397 @@
+use crate::event::{Event, SkipReason};
+use crate::itunes_library::Track;
+use crate::location::{self, LocationMapping};
+use crate::rhythmdb::{self, DatabaseMatch, MatchedEntry};
+use crate::track_key::TrackKey;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use elementtree::Element;
+use log::{debug, info, warn, Level};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// How files are put into the destination directory.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum CopyMode {
+    /// Copy the files, which is a reflink on filesystems supporting it
+    Copy,
+    /// Hard-link the files, falling back to copying across filesystems
+    Link,
+}
+
+/// What to do with matched songs whose files are missing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum CheckFilesAction {
+    /// List songs whose files are missing in the summary
+    Report,
+    /// Also exclude them from migrated playlists
+    Exclude,
+}
+
+/// Copy files of iTunes tracks which are not in Rhythmbox into the destination directory,
+/// and add entries pointing at the new locations to the database.
+///
+/// Files are put under `<artist>/<album>/` in the destination directory,
+/// with a number appended to their names if taken by different files.
+/// The new entries are added to the matched entries of the database match,
+/// so that they take part in the rest of the migration.
+/// DRM-protected tracks are skipped unless `include_protected` is set.
+pub fn copy_missing_files<'a>(
+    rhythmdb: &mut Element,
+    database_match: &mut DatabaseMatch<'a>,
+    dest: &Path,
+    mode: CopyMode,
+    mappings: &[LocationMapping],
+    include_protected: bool,
+) -> Result<()> {
+    info!("Copying missing files into {}...", dest.display());
+    fs::create_dir_all(dest).with_context(|| format!("failed to create {}", dest.display()))?;
+    let dest = dest
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", dest.display()))?;
+    let mut copied_count = 0;
+    let mut used_targets = HashSet::new();
+    let mut remaining_tracks = Vec::new();
+    for track in database_match.unused_tracks.drain(..) {
+        let key = TrackKey::from(track);
+        if !include_protected && track.is_protected() {
+            Event::SongSkipped {
+                song: key,
+                persistent_id: track.persistent_id.as_deref(),
+                reason: SkipReason::Protected,
+            }
+            .log(Level::Warn);
+            remaining_tracks.push(track);
+            continue;
+        }
+        let source = track
+            .location
+            .as_deref()
+            .map(|location| location::remap(location, mappings))
+            .and_then(|location| location::uri_to_path(&location));
+        let source = match source {
+            Some(source) if source.is_file() => source,
+            _ => {
+                warn!("file of song {} not found", key);
+                remaining_tracks.push(track);
+                continue;
+            }
+        };
+        let target = match target_path(&dest, track, &source, &used_targets) {
+            Some(target) => target,
+            None => {
+                remaining_tracks.push(track);
+                continue;
+            }
+        };
+        let location = match location::path_to_uri(&target) {
+            Some(location) => location,
+            None => {
+                warn!(
+                    "{} can't be referenced by Rhythmbox, not copied",
+                    target.display()
+                );
+                remaining_tracks.push(track);
+                continue;
+            }
+        };
+        if let Err(e) = put_file(&source, &target, mode) {
+            warn!("failed to copy {}: {}", source.display(), e);
+            remaining_tracks.push(track);
+            continue;
+        }
+        debug!("copied {} to {}", source.display(), target.display());
+        used_targets.insert(target);
+        let index = rhythmdb::append_song_entry(rhythmdb, database_match.schema, track, &location);
+        database_match.entries.push(MatchedEntry {
+            index,
+            location,
+            track,
+        });
+        database_match.song_count += 1;
+        copied_count += 1;
+    }
+    database_match.unused_tracks = remaining_tracks;
+    info!("{} missing files copied", copied_count);
+    Ok(())
+}
+
+/// Path to put the file of the track at, under `<artist>/<album>/` in the destination directory.
+///
+/// A number is appended to the file name if the path is used by another track in this run,
+/// or taken by a file with different content, so that different files never share a path.
+fn target_path(
+    dest: &Path,
+    track: &Track,
+    source: &Path,
+    used_targets: &HashSet<PathBuf>,
+) -> Option<PathBuf> {
+    let artist = track.artist.as_deref().unwrap_or("Unknown Artist");
+    let album = track.album.as_deref().unwrap_or("Unknown Album");
+    let dir = dest.join(sanitize(artist)).join(sanitize(album));
+    let file_name = source.file_name()?;
+    let file_name = Path::new(file_name);
+    (1..)
+        .map(|n| {
+            if n == 1 {
+                return dir.join(file_name);
+            }
+            let mut name = OsString::from(file_name.file_stem().unwrap_or_default());
+            name.push(format!(" ({})", n));
+            if let Some(extension) = file_name.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            dir.join(name)
+        })
+        .find(|target| {
+            !used_targets.contains(target) && (!target.exists() || has_same_content(source, target))
+        })
+}
+
+/// Make the name usable as a single path component.
+fn sanitize(name: &str) -> String {
+    match name.trim() {
+        "" | "." | ".." => "_".to_owned(),
+        _ => name.replace(&['/', '\0'][..], "_"),
+    }
+}
+
+/// Whether the files have the same content, e.g. when the target was copied by a previous run.
+fn has_same_content(source: &Path, target: &Path) -> bool {
+    let len = |path: &Path| fs::metadata(path).map(|metadata| metadata.len()).ok();
+    if len(source).is_none() || len(source) != len(target) {
+        return false;
+    }
+    let compare = || -> io::Result<bool> {
+        let mut source = BufReader::new(File::open(source)?);
+        let mut target = BufReader::new(File::open(target)?);
+        loop {
+            let (source_chunk, target_chunk) = (source.fill_buf()?, target.fill_buf()?);
+            let len = source_chunk.len().min(target_chunk.len());
+            if source_chunk[..len] != target_chunk[..len] {
+                return Ok(false);
+            }
+            if len == 0 {
+                return Ok(source_chunk.is_empty() && target_chunk.is_empty());
+            }
+            source.consume(len);
+            target.consume(len);
+        }
+    };
+    compare().unwrap_or(false)
+}
+
+/// Put the source file at the target path, unless it's already there.
+fn put_file(source: &Path, target: &Path, mode: CopyMode) -> io::Result<()> {
+    if target.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if mode == CopyMode::Link && fs::hard_link(source, target).is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, target)?;
+    Ok(())
+}
+
+/// Find matched entries whose files don't exist on disk, e.g. deleted after being imported.
+///
+/// Entries with locations other than local files are not checked.
+pub fn find_dead_entries<'a, 'b>(
+    matched_entries: &'b [MatchedEntry<'a>],
+) -> Vec<&'b MatchedEntry<'a>> {
+    info!("Checking files of matched songs...");
+    let dead_entries = matched_entries
+        .par_iter()
+        .filter(|matched| {
+            location::uri_to_path(&matched.location).is_some_and(|path| !path.exists())
+        })
+        .collect::<Vec<_>>();
+    for matched in dead_entries.iter() {
+        Event::FileMissing {
+            song: TrackKey::from(matched.track),
+            location: &matched.location,
+        }
+        .log(Level::Warn);
+    }
+    dead_entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rhythmdb::SCHEMA;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    fn track(id: u64, artist: Option<&str>, album: Option<&str>, source: &Path) -> Track {
+        let field = |key, value: Option<&str>| {
+            value
+                .map(|value| format!("<key>{}</key><string>{}</string>", key, value))
+                .unwrap_or_default()
+        };
+        let xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <plist version=\"1.0\"><dict>\
+             <key>Track ID</key><integer>{}</integer>\
+             <key>Name</key><string>Song {}</string>{}{}\
+             <key>Date Modified</key><date>2019-01-01T00:00:00Z</date>\
+             <key>Date Added</key><date>2019-01-01T00:00:00Z</date>\
+             <key>Location</key><string>{}</string>\
+             </dict></plist>",
+            id,
+            id,
+            field("Artist", artist),
+            field("Album", album),
+            location::path_to_uri(source).unwrap(),
+        );
+        plist::from_bytes(xml.as_bytes()).unwrap()
+    }
+
+    fn write(path: &Path, content: &str) -> PathBuf {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        path.to_owned()
+    }
+
+    #[test]
+    fn target_path_nests_under_artist_and_album() {
+        let dir = TempDir::new().unwrap();
+        let source = write(&dir.path().join("in/song.mp3"), "a");
+        let dest = dir.path().join("out");
+        let slashed = track(1, Some("AC/DC"), None, &source);
+        assert_eq!(
+            target_path(&dest, &slashed, &source, &HashSet::new()),
+            Some(dest.join("AC_DC/Unknown Album/song.mp3")),
+        );
+        let dotted = track(2, Some(" .. "), Some("Back"), &source);
+        assert_eq!(
+            target_path(&dest, &dotted, &source, &HashSet::new()),
+            Some(dest.join("_/Back/song.mp3")),
+        );
+    }
+
+    #[test]
+    fn target_path_numbers_taken_paths() {
+        let dir = TempDir::new().unwrap();
+        let source = write(&dir.path().join("in/song.mp3"), "a");
+        let dest = dir.path().join("out");
+        let track = track(1, Some("Artist"), Some("Album"), &source);
+        let album_dir = dest.join("Artist/Album");
+        let used_targets = HashSet::from([album_dir.join("song.mp3")]);
+        assert_eq!(
+            target_path(&dest, &track, &source, &used_targets),
+            Some(album_dir.join("song (2).mp3")),
+        );
+        write(&album_dir.join("song.mp3"), "b");
+        write(&album_dir.join("song (2).mp3"), "c");
+        assert_eq!(
+            target_path(&dest, &track, &source, &HashSet::new()),
+            Some(album_dir.join("song (3).mp3")),
+        );
+    }
+
+    #[test]
+    fn target_path_reuses_file_with_same_content() {
+        let dir = TempDir::new().unwrap();
+        let source = write(&dir.path().join("in/song.mp3"), "a");
+        let dest = dir.path().join("out");
+        let track = track(1, Some("Artist"), Some("Album"), &source);
+        let target = write(&dest.join("Artist/Album/song.mp3"), "a");
+        assert_eq!(
+            target_path(&dest, &track, &source, &HashSet::new()),
+            Some(target),
+        );
+    }
+
+    #[test]
+    fn compares_content_beyond_first_chunk() {
+        let dir = TempDir::new().unwrap();
+        let content = "a".repeat(20_000);
+        let source = write(&dir.path().join("source.mp3"), &content);
+        let same = write(&dir.path().join("same.mp3"), &content);
+        let different = write(
+            &dir.path().join("different.mp3"),
+            &(content[1..].to_owned() + "b"),
+        );
+        assert!(has_same_content(&source, &same));
+        assert!(!has_same_content(&source, &different));
+    }
+
+    #[test]
+    fn copies_missing_files_and_adds_entries() {
+        let dir = TempDir::new().unwrap();
+        let first = write(&dir.path().join("in/1/song.mp3"), "first");
+        let second = write(&dir.path().join("in/2/song.mp3"), "second");
+        let dest = dir.path().join("out");
+        let tracks = [
+            track(1, Some("Artist"), Some("Album"), &first),
+            track(2, Some("Artist"), Some("Album"), &second),
+            track(
+                3,
+                Some("Artist"),
+                Some("Album"),
+                &dir.path().join("gone.mp3"),
+            ),
+        ];
+        let mut rhythmdb = Element::from_reader("<rhythmdb version=\"2.0\"/>".as_bytes()).unwrap();
+        let mut database_match = DatabaseMatch {
+            entries: Vec::new(),
+            unmatched_entries: Vec::new(),
+            song_count: 0,
+            other_entry_counts: BTreeMap::new(),
+            invalid_song_count: 0,
+            duration_mismatch_count: 0,
+            unused_tracks: tracks.iter().collect(),
+            encoding_repairs: Vec::new(),
+            schema: &SCHEMA,
+        };
+        copy_missing_files(
+            &mut rhythmdb,
+            &mut database_match,
+            &dest,
+            CopyMode::Copy,
+            &[],
+            false,
+        )
+        .unwrap();
+
+        let album_dir = dest.canonicalize().unwrap().join("Artist/Album");
+        assert_eq!(
+            fs::read_to_string(album_dir.join("song.mp3")).unwrap(),
+            "first"
+        );
+        assert_eq!(
+            fs::read_to_string(album_dir.join("song (2).mp3")).unwrap(),
+            "second",
+        );
+        let locations = database_match
+            .entries
+            .iter()
+            .map(|entry| (entry.track.id.0, entry.location.as_str()))
+            .collect::<Vec<_>>();
+        let uri = |name: &str| location::path_to_uri(&album_dir.join(name)).unwrap();
+        assert_eq!(
+            locations,
+            [
+                (1, uri("song.mp3").as_str()),
+                (2, uri("song (2).mp3").as_str())
+            ],
+        );
+        assert_eq!(database_match.song_count, 2);
+        assert_eq!(rhythmdb.find_all("entry").count(), 2);
+        let unused_ids = database_match
+            .unused_tracks
+            .iter()
+            .map(|track| track.id.0)
+            .collect::<Vec<_>>();
+        assert_eq!(unused_ids, [3]);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::itunes_library::{ItunesLibrary, TrackId};
+use crate::playlists::{self, PlaylistFilter};
+use crate::track_key::TrackKey;
+use elementtree::Element;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Number of unchanged items shown around changed ones.
+const CONTEXT: usize = 3;
+/// Maximum size of the table for finding common items of two playlists,
+/// beyond which all items are shown as replaced.
+const MAX_TABLE_SIZE: usize = 1 << 22;
+
+/// Changes which migrating would make to a Rhythmbox playlist.
+#[derive(Debug)]
+pub struct PlaylistDiff {
+    pub name: String,
+    /// Whether the playlist already exists in Rhythmbox and would be replaced.
+    pub existing: bool,
+    /// Whether the playlist would be removed, e.g. in favor of the play queue.
+    pub removed: bool,
+    pub lines: Vec<DiffLine>,
+    /// iTunes tracks in the playlist which can't be resolved to Rhythmbox songs.
+    pub unresolved: Vec<String>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum DiffLine {
+    Unchanged(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Compare playlists before and after migrating,
+/// listing the playlists which change or have items unresolved.
+///
+/// `migrated_playlists` are names of static playlists created by previous migrations.
+pub fn diff_playlists(
+    before: &Element,
+    after: &Element,
+    itunes_library: &ItunesLibrary,
+    track_locations: &HashMap<TrackId, String>,
+    filter: &PlaylistFilter,
+    migrated_playlists: &BTreeSet<String>,
+) -> Vec<PlaylistDiff> {
+    let target_names = playlists::target_names(before, itunes_library, filter, migrated_playlists);
+    let unresolved = unresolved_items(itunes_library, track_locations, filter, &target_names);
+    let mut diffs = Vec::new();
+    for playlist in after.children() {
+        let (name, kind) = match (playlist.get_attr("name"), playlist.get_attr("type")) {
+            (Some(name), Some(kind @ ("static" | "queue"))) => (name, kind),
+            _ => continue,
+        };
+        let existing = before.children().find(|playlist| {
+            playlist.get_attr("name") == Some(name) && playlist.get_attr("type") == Some(kind)
+        });
+        let old = existing.map(locations).unwrap_or_default();
+        let new = locations(playlist);
+        let unresolved = unresolved.get(name).cloned().unwrap_or_default();
+        if old == new && unresolved.is_empty() {
+            continue;
+        }
+        diffs.push(PlaylistDiff {
+            name: name.to_owned(),
+            existing: existing.is_some(),
+            removed: false,
+            lines: diff_lines(&old, &new),
+            unresolved,
+        });
+    }
+    for playlist in before.children() {
+        let name = match (playlist.get_attr("name"), playlist.get_attr("type")) {
+            (Some(name), Some("static")) => name,
+            _ => continue,
+        };
+        let kept = after.children().any(|playlist| {
+            playlist.get_attr("name") == Some(name) && playlist.get_attr("type") == Some("static")
+        });
+        if !kept {
+            diffs.push(PlaylistDiff {
+                name: name.to_owned(),
+                existing: true,
+                removed: true,
+                lines: diff_lines(&locations(playlist), &[]),
+                unresolved: Vec::new(),
+            });
+        }
+    }
+    diffs
+}
+
+fn locations(playlist: &Element) -> Vec<&str> {
+    playlist
+        .find_all("location")
+        .map(|location| location.text())
+        .collect()
+}
+
+/// Descriptions of unresolved items of iTunes playlists,
+/// keyed by the names of the Rhythmbox playlists they are migrated into.
+fn unresolved_items<'a>(
+    itunes_library: &ItunesLibrary,
+    track_locations: &HashMap<TrackId, String>,
+    filter: &PlaylistFilter,
+    target_names: &'a HashMap<&str, String>,
+) -> HashMap<&'a str, Vec<String>> {
+    itunes_library
+        .playlists
+        .iter()
+        .filter_map(|playlist| Some((target_names.get(playlist.name.as_str())?, playlist)))
+        .map(|(name, playlist)| {
+            let items = playlist
+                .sorted_items(&itunes_library.tracks)
+                .into_iter()
+                .filter(|item| filter.includes_track(item.id))
+                .filter(|item| !track_locations.contains_key(&item.id))
+                .map(|item| match itunes_library.tracks.get(&item.id) {
+                    Some(track) => TrackKey::from(track).to_string(),
+                    None => format!("track {} missing from the iTunes library", item.id.0),
+                })
+                .collect();
+            (name.as_str(), items)
+        })
+        .collect()
+}
+
+/// Diff two lists of locations by their longest common subsequence.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let unchanged = |items: &[&str]| {
+        items
+            .iter()
+            .map(|item| DiffLine::Unchanged(item.to_string()))
+            .collect::<Vec<_>>()
+    };
+    let mut lines = unchanged(&old[..prefix]);
+    if old_middle.len() * new_middle.len() > MAX_TABLE_SIZE {
+        let removed = old_middle
+            .iter()
+            .map(|item| DiffLine::Removed(item.to_string()));
+        let added = new_middle
+            .iter()
+            .map(|item| DiffLine::Added(item.to_string()));
+        lines.extend(removed.chain(added));
+    } else {
+        lines.extend(diff_middle(old_middle, new_middle));
+    }
+    lines.extend(unchanged(&old[old.len() - suffix..]));
+    lines
+}
+
+fn diff_middle(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    // Length of the longest common subsequence of the suffixes starting at each position.
+    let width = new.len() + 1;
+    let mut table = vec![0_u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            table[i * width + j] = if old[i] == new[j] {
+                table[(i + 1) * width + j + 1] + 1
+            } else {
+                table[(i + 1) * width + j].max(table[i * width + j + 1])
+            };
+        }
+    }
+    let mut lines = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(DiffLine::Unchanged(old[i].to_owned()));
+            i += 1;
+            j += 1;
+        } else if j < new.len()
+            && (i == old.len() || table[i * width + j + 1] >= table[(i + 1) * width + j])
+        {
+            lines.push(DiffLine::Added(new[j].to_owned()));
+            j += 1;
+        } else {
+            lines.push(DiffLine::Removed(old[i].to_owned()));
+            i += 1;
+        }
+    }
+    lines
+}
+
+impl fmt::Display for PlaylistDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        if self.existing {
+            writeln!(f, "--- {}", self.name)?;
+        } else {
+            writeln!(f, "--- /dev/null")?;
+        }
+        if self.removed {
+            writeln!(f, "+++ /dev/null")?;
+        } else {
+            writeln!(f, "+++ {}", self.name)?;
+        }
+        // Show unchanged lines only near changed ones.
+        let changed = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| !matches!(line, DiffLine::Unchanged(_)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut next_changed = changed.iter().peekable();
+        let mut last_shown = None;
+        for (i, line) in self.lines.iter().enumerate() {
+            while next_changed.peek().is_some_and(|&&c| c + CONTEXT < i) {
+                next_changed.next();
+            }
+            let near_change = next_changed.peek().is_some_and(|&&c| c <= i + CONTEXT);
+            if !near_change {
+                continue;
+            }
+            if last_shown.map_or(i > 0, |last| last + 1 < i) {
+                writeln!(f, "@@ ... @@")?;
+            }
+            last_shown = Some(i);
+            match line {
+                DiffLine::Unchanged(location) => writeln!(f, " {}", location)?,
+                DiffLine::Removed(location) => writeln!(f, "-{}", location)?,
+                DiffLine::Added(location) => writeln!(f, "+{}", location)?,
+            }
+        }
+        for item in self.unresolved.iter() {
+            writeln!(f, "? {}", item)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Diff lines of the given items, in the form shown by `--diff`.
+    fn diff(old: &str, new: &str) -> Vec<String> {
+        let old = old.split_whitespace().collect::<Vec<_>>();
+        let new = new.split_whitespace().collect::<Vec<_>>();
+        diff_lines(&old, &new)
+            .into_iter()
+            .map(|line| match line {
+                DiffLine::Unchanged(item) => format!(" {}", item),
+                DiffLine::Removed(item) => format!("-{}", item),
+                DiffLine::Added(item) => format!("+{}", item),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_identical_items() {
+        assert_eq!(diff("a b c", "a b c"), [" a", " b", " c"]);
+        assert!(diff("", "").is_empty());
+    }
+
+    #[test]
+    fn finds_added_and_removed_items() {
+        assert_eq!(diff("a c", "a b c"), [" a", "+b", " c"]);
+        assert_eq!(diff("a b c", "a c"), [" a", "-b", " c"]);
+        assert_eq!(diff("", "a b"), ["+a", "+b"]);
+        assert_eq!(diff("a b", ""), ["-a", "-b"]);
+    }
+
+    #[test]
+    fn keeps_longest_common_subsequence() {
+        assert_eq!(diff("a b c", "a c b"), [" a", "+c", " b", "-c"]);
+        assert_eq!(
+            diff("x a y b z c", "a b c"),
+            ["-x", " a", "-y", " b", "-z", " c"],
+        );
+        assert_eq!(
+            diff("a b c d", "b x d a"),
+            ["-a", " b", "+x", "-c", " d", "+a"],
+        );
+    }
+
+    #[test]
+    fn keeps_repeated_items() {
+        assert_eq!(diff("a a b", "a b a"), [" a", "+b", " a", "-b"]);
+    }
+}
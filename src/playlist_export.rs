@@ -0,0 +1,151 @@
+use crate::itunes_library::Track;
+use crate::location;
+use crate::xml::write_atomically;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use elementtree::{Element, WriteOptions, XmlProlog};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    M3u,
+    M3u8,
+    Xspf,
+    Pls,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::M3u => "m3u",
+            ExportFormat::M3u8 => "m3u8",
+            ExportFormat::Xspf => "xspf",
+            ExportFormat::Pls => "pls",
+        }
+    }
+}
+
+pub struct ExportItem<'a> {
+    pub location: String,
+    pub track: &'a Track,
+}
+
+/// Writer of standalone playlist files into a directory.
+pub struct PlaylistExporter<'a> {
+    dir: &'a Path,
+    format: ExportFormat,
+    used_names: HashSet<String>,
+}
+
+impl<'a> PlaylistExporter<'a> {
+    pub fn new(dir: &'a Path, format: ExportFormat) -> Self {
+        PlaylistExporter {
+            dir,
+            format,
+            used_names: HashSet::new(),
+        }
+    }
+
+    /// Write a playlist, returning the path of the file written.
+    ///
+    /// The file is written atomically, so it's never left partially written.
+    pub fn export(&mut self, name: &str, items: &[ExportItem<'_>]) -> Result<PathBuf> {
+        let path = self.dir.join(self.file_name(name));
+        let format = self.format;
+        write_atomically(&path, |writer| match format {
+            ExportFormat::M3u | ExportFormat::M3u8 => write_m3u(writer, items),
+            ExportFormat::Pls => write_pls(writer, items),
+            ExportFormat::Xspf => write_xspf(writer, name, items),
+        })
+        .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Derive a unique file name from the playlist name.
+    fn file_name(&mut self, name: &str) -> String {
+        let base = name
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | '\0' => '_',
+                c => c,
+            })
+            .collect::<String>();
+        let mut file_name = base.clone();
+        let mut counter = 1;
+        while !self.used_names.insert(file_name.clone()) {
+            counter += 1;
+            file_name = format!("{} ({})", base, counter);
+        }
+        format!("{}.{}", file_name, self.format.extension())
+    }
+}
+
+/// Locations in M3U and PLS files are preferably local paths.
+fn local_location(location: &str) -> String {
+    match location::uri_to_path(location) {
+        Some(path) => path.display().to_string(),
+        None => location.to_owned(),
+    }
+}
+
+fn display_title(track: &Track) -> String {
+    match &track.artist {
+        Some(artist) => format!("{} - {}", artist, track.name),
+        None => track.name.clone(),
+    }
+}
+
+fn write_m3u(writer: &mut impl Write, items: &[ExportItem<'_>]) -> Result<()> {
+    writeln!(writer, "#EXTM3U")?;
+    for item in items {
+        writeln!(writer, "#EXTINF:-1,{}", display_title(item.track))?;
+        writeln!(writer, "{}", local_location(&item.location))?;
+    }
+    Ok(())
+}
+
+fn write_pls(writer: &mut impl Write, items: &[ExportItem<'_>]) -> Result<()> {
+    writeln!(writer, "[playlist]")?;
+    for (i, item) in items.iter().enumerate() {
+        let n = i + 1;
+        writeln!(writer, "File{}={}", n, local_location(&item.location))?;
+        writeln!(writer, "Title{}={}", n, display_title(item.track))?;
+        writeln!(writer, "Length{}=-1", n)?;
+    }
+    writeln!(writer, "NumberOfEntries={}", items.len())?;
+    writeln!(writer, "Version=2")?;
+    Ok(())
+}
+
+fn write_xspf(writer: &mut impl Write, name: &str, items: &[ExportItem<'_>]) -> Result<()> {
+    const XSPF_NS: &str = "http://xspf.org/ns/0/";
+    let mut playlist = Element::new((XSPF_NS, "playlist"));
+    playlist.register_namespace(XSPF_NS, None);
+    playlist.set_attr("version", "1");
+    playlist.append_new_child((XSPF_NS, "title")).set_text(name);
+    let track_list = playlist.append_new_child((XSPF_NS, "trackList"));
+    for item in items {
+        let track_element = track_list.append_new_child((XSPF_NS, "track"));
+        track_element
+            .append_new_child((XSPF_NS, "location"))
+            .set_text(item.location.as_str());
+        track_element
+            .append_new_child((XSPF_NS, "title"))
+            .set_text(item.track.name.as_str());
+        if let Some(artist) = &item.track.artist {
+            track_element
+                .append_new_child((XSPF_NS, "creator"))
+                .set_text(artist.as_str());
+        }
+        if let Some(album) = &item.track.album {
+            track_element
+                .append_new_child((XSPF_NS, "album"))
+                .set_text(album.as_str());
+        }
+    }
+    let options = WriteOptions::new().set_xml_prolog(Some(XmlProlog::Version10));
+    playlist.to_writer_with_options(writer, options)?;
+    Ok(())
+}
@@ -0,0 +1,509 @@
+use crate::event::{Event, SkipReason};
+use crate::itunes_library::{ItunesLibrary, Playlist, TrackId};
+use crate::location::{self, LocationMapping};
+use crate::playlist_export::{ExportFormat, ExportItem, PlaylistExporter};
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use elementtree::{Element, QName};
+use glob::Pattern;
+use log::{debug, info, warn, Level};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+pub const PLAYLISTS_FILENAME: &str = "playlists.xml";
+
+/// Suffix of migrated playlists whose names are taken by playlists created in Rhythmbox.
+const RENAMED_SUFFIX: &str = " (iTunes)";
+
+/// Filter of iTunes playlists to migrate by name.
+#[derive(Clone, Debug, Default)]
+pub struct PlaylistFilter {
+    /// Patterns of playlists to include, all playlists are included if empty.
+    pub include: Vec<Pattern>,
+    /// Patterns of playlists to exclude.
+    pub exclude: Vec<Pattern>,
+    /// Tracks to exclude from playlists.
+    pub exclude_tracks: HashSet<TrackId>,
+    /// How to remove duplicate items from playlists, if at all.
+    pub dedupe_items: Option<DedupeMode>,
+    /// Name of the playlist to migrate into the play queue rather than as a static playlist.
+    pub queue_playlist: Option<String>,
+}
+
+/// Which duplicate items to remove from playlists.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum DedupeMode {
+    /// Remove items repeating the one right before them
+    Consecutive,
+    /// Remove all but the first occurrence of each item
+    All,
+}
+
+/// Remover of duplicate items in a playlist.
+struct Deduplicator {
+    mode: Option<DedupeMode>,
+    seen: HashSet<String>,
+    previous: Option<String>,
+    removed: usize,
+}
+
+impl Deduplicator {
+    fn new(mode: Option<DedupeMode>) -> Self {
+        Deduplicator {
+            mode,
+            seen: HashSet::new(),
+            previous: None,
+            removed: 0,
+        }
+    }
+
+    /// Whether the item at the location should be kept, counting the removed ones.
+    fn keep(&mut self, location: &str) -> bool {
+        let duplicate = match self.mode {
+            None => return true,
+            Some(DedupeMode::Consecutive) => self.previous.as_deref() == Some(location),
+            Some(DedupeMode::All) => !self.seen.insert(location.to_owned()),
+        };
+        self.previous = Some(location.to_owned());
+        if duplicate {
+            self.removed += 1;
+        }
+        !duplicate
+    }
+
+    fn log(&self, playlist: &str) {
+        log_deduplicated(playlist, self.removed);
+    }
+}
+
+fn log_deduplicated(playlist: &str, count: usize) {
+    if count > 0 {
+        Event::PlaylistItemsDeduplicated { playlist, count }.log(Level::Info);
+    }
+}
+
+/// Items of a static iTunes playlist as migrated into Rhythmbox.
+#[derive(Debug, Default)]
+pub struct MigratedItems<'a> {
+    /// Locations of the migrated items, in order.
+    pub locations: Vec<&'a str>,
+    /// Items referencing tracks missing from the iTunes library.
+    pub missing: usize,
+    /// Items whose tracks are not found in Rhythmbox.
+    pub unmatched: usize,
+    /// Duplicate items removed.
+    pub deduplicated: usize,
+}
+
+/// Items of the static iTunes playlist to migrate,
+/// after excluding tracks and removing duplicates according to the filter.
+pub fn migrated_items<'a>(
+    playlist: &Playlist,
+    itunes_library: &ItunesLibrary,
+    track_locations: &'a HashMap<TrackId, String>,
+    filter: &PlaylistFilter,
+) -> MigratedItems<'a> {
+    let mut items = MigratedItems::default();
+    let mut deduplicator = Deduplicator::new(filter.dedupe_items);
+    for item in playlist.sorted_items(&itunes_library.tracks) {
+        if !filter.includes_track(item.id) {
+            continue;
+        }
+        let location = match track_locations.get(&item.id) {
+            Some(location) => location,
+            None if itunes_library.missing_track_ids.contains(&item.id) => {
+                items.missing += 1;
+                continue;
+            }
+            None => {
+                items.unmatched += 1;
+                continue;
+            }
+        };
+        if deduplicator.keep(location) {
+            items.locations.push(location);
+        }
+    }
+    items.deduplicated = deduplicator.removed;
+    items
+}
+
+impl PlaylistFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(name)))
+            && !self.exclude.iter().any(|p| p.matches(name))
+    }
+
+    pub fn includes_track(&self, id: TrackId) -> bool {
+        !self.exclude_tracks.contains(&id)
+    }
+}
+
+/// Statistics of migrating playlists.
+#[derive(Debug, Default, Serialize)]
+pub struct PlaylistStats {
+    pub migrated: usize,
+    /// Migrated playlists without any item.
+    pub empty: usize,
+    /// Playlists excluded by the filter.
+    pub filtered: usize,
+    /// Smart playlists migrated as equivalent automatic playlists.
+    pub automatic: usize,
+    /// Smart playlists skipped as they cannot be converted.
+    pub smart_skipped: usize,
+    /// Duplicate items removed from migrated playlists.
+    pub deduplicated: usize,
+    /// Items of migrated playlists referencing tracks missing from the iTunes library.
+    pub missing_items: usize,
+    /// Items of migrated playlists whose tracks are not found in Rhythmbox.
+    pub unmatched_items: usize,
+}
+
+/// Automatic playlist equivalent to a default smart playlist of iTunes.
+struct AutomaticPlaylist {
+    name: &'static str,
+    sort_key: &'static str,
+    limit: Option<usize>,
+    /// Criteria as (operator, property, value).
+    criteria: &'static [(&'static str, &'static str, &'static str)],
+}
+
+const TWO_WEEKS: &str = "1209600";
+
+/// Equivalents of default smart playlists of iTunes,
+/// whose criteria can be inferred from the names.
+const AUTOMATIC_PLAYLISTS: [AutomaticPlaylist; 4] = [
+    AutomaticPlaylist {
+        name: "Recently Added",
+        sort_key: "FirstSeen",
+        limit: None,
+        criteria: &[("current-time-within", "first-seen", TWO_WEEKS)],
+    },
+    AutomaticPlaylist {
+        name: "Recently Played",
+        sort_key: "LastPlayed",
+        limit: None,
+        criteria: &[("current-time-within", "last-played", TWO_WEEKS)],
+    },
+    AutomaticPlaylist {
+        name: "Top 25 Most Played",
+        sort_key: "PlayCount",
+        limit: Some(25),
+        criteria: &[("greater", "play-count", "0")],
+    },
+    AutomaticPlaylist {
+        name: "My Top Rated",
+        sort_key: "Rating",
+        limit: None,
+        criteria: &[("greater", "rating", "4")],
+    },
+];
+
+/// Build the automatic playlist equivalent to the iTunes smart playlist with the given name.
+fn automatic_playlist(name: &str) -> Option<Element> {
+    let automatic = AUTOMATIC_PLAYLISTS
+        .iter()
+        .find(|automatic| automatic.name == name)?;
+    let mut playlist = Element::new("playlist");
+    playlist.set_attr("name", name);
+    playlist.set_attr("show-browser", "false");
+    playlist.set_attr("browser-position", "180");
+    playlist.set_attr("search-type", "search-match");
+    playlist.set_attr("type", "automatic");
+    playlist.set_attr("sort-key", automatic.sort_key);
+    playlist.set_attr("sort-direction", "1");
+    if let Some(limit) = automatic.limit {
+        playlist.set_attr("limit-count", limit.to_string());
+    }
+    playlist.set_text("\n    ");
+    let conjunction = playlist.append_new_child("conjunction");
+    conjunction.set_text("\n      ");
+    conjunction
+        .append_new_child("equals")
+        .set_attr("prop", "type")
+        .set_text("song");
+    for &(operator, prop, value) in automatic.criteria {
+        conjunction
+            .append_new_child(operator)
+            .set_attr("prop", prop)
+            .set_text(value);
+    }
+    let count = conjunction.child_count();
+    for (i, child) in conjunction.children_mut().enumerate() {
+        child.set_tail(if i + 1 == count { "\n    " } else { "\n      " });
+    }
+    conjunction.set_tail("\n  ");
+    Some(playlist)
+}
+
+/// Read the playlists, failing if their format is unknown unless `assume_compatible` is set.
+pub fn read_playlists(playlists_path: &Path, assume_compatible: bool) -> Result<Element> {
+    info!("Reading Rhythmbox playlists...");
+    let playlists = File::open(playlists_path).context("failed to open playlists file")?;
+    let playlists =
+        Element::from_reader(BufReader::new(playlists)).context("failed to read playlists")?;
+    if playlists.tag() != &QName::from("rhythmdb-playlists") {
+        ensure!(assume_compatible, "unknown playlists format");
+        warn!(
+            "unknown playlists format {}, assuming it compatible",
+            playlists.tag()
+        );
+    }
+    Ok(playlists)
+}
+
+/// Names of the Rhythmbox playlists which static iTunes playlists are migrated into,
+/// keyed by their iTunes names.
+///
+/// `migrated_playlists` are names of static playlists created by previous migrations.
+/// Migrated playlists whose names are taken by other static playlists get a suffix,
+/// so that playlists created in Rhythmbox are never replaced.
+pub fn target_names<'a>(
+    playlists: &Element,
+    itunes_library: &'a ItunesLibrary,
+    filter: &PlaylistFilter,
+    migrated_playlists: &BTreeSet<String>,
+) -> HashMap<&'a str, String> {
+    let taken_names = playlists
+        .children()
+        .filter(|playlist| playlist.get_attr("type") == Some("static"))
+        .filter_map(|playlist| playlist.get_attr("name"))
+        .filter(|name| !migrated_playlists.contains(*name))
+        .collect::<HashSet<_>>();
+    itunes_library
+        .playlists
+        .iter()
+        .filter(|playlist| playlist.smart_info.is_none() && filter.matches(&playlist.name))
+        .map(|playlist| {
+            let name = playlist.name.as_str();
+            let target = if filter.queue_playlist.as_deref() == Some(name) {
+                "Play Queue".to_owned()
+            } else if taken_names.contains(name) {
+                format!("{}{}", name, RENAMED_SUFFIX)
+            } else {
+                name.to_owned()
+            };
+            (name, target)
+        })
+        .collect()
+}
+
+/// Migrate iTunes playlists into the Rhythmbox playlists.
+///
+/// Static playlists created by previous migrations, as listed in `migrated_playlists`,
+/// are replaced, so that migrating again doesn't duplicate them.
+/// Names of the static playlists created are added into `migrated_playlists`.
+pub fn migrate_playlists(
+    playlists: &mut Element,
+    itunes_library: &ItunesLibrary,
+    track_locations: &HashMap<TrackId, String>,
+    filter: &PlaylistFilter,
+    migrated_playlists: &mut BTreeSet<String>,
+) -> PlaylistStats {
+    info!("Migrating playlists...");
+    let mut stats = PlaylistStats::default();
+    let target_names = target_names(playlists, itunes_library, filter, migrated_playlists);
+    let queue_playlist = filter
+        .queue_playlist
+        .as_deref()
+        .filter(|name| target_names.contains_key(name));
+    if let (Some(name), None) = (&filter.queue_playlist, queue_playlist) {
+        warn!("no static playlist {} to migrate into the play queue", name);
+    }
+    let replaced_names = target_names
+        .values()
+        .map(String::as_str)
+        .collect::<HashSet<_>>();
+    for index in (0..playlists.child_count()).rev() {
+        let playlist = playlists.get_child(index).unwrap();
+        let replaced = match playlist.get_attr("type") {
+            Some("static") => playlist.get_attr("name").is_some_and(|name| {
+                migrated_playlists.contains(name) && replaced_names.contains(name)
+            }),
+            Some("queue") => queue_playlist.is_some(),
+            _ => false,
+        };
+        if replaced {
+            debug!("replacing playlist {}", playlist.get_attr("name").unwrap());
+            playlists.remove_child(index);
+        }
+    }
+    if playlists.child_count() > 0 {
+        playlists
+            .get_child_mut(playlists.child_count() - 1)
+            .unwrap()
+            .set_tail("\n  ");
+    } else {
+        playlists.set_text("\n  ");
+    }
+    let mut existing_names = playlists
+        .children()
+        .filter_map(|playlist| playlist.get_attr("name"))
+        .map(str::to_owned)
+        .collect::<HashSet<_>>();
+    for playlist in itunes_library.playlists.iter() {
+        if !filter.matches(&playlist.name) {
+            debug!("playlist {} is filtered out", playlist.name);
+            stats.filtered += 1;
+            continue;
+        }
+        if playlist.smart_info.is_some() {
+            // Default smart playlists are migrated as their automatic equivalents.
+            if let Some(mut playlist_element) = automatic_playlist(&playlist.name) {
+                if existing_names.insert(playlist.name.clone()) {
+                    info!(
+                        "playlist {} is migrated as automatic playlist",
+                        playlist.name
+                    );
+                    playlist_element.set_tail("\n  ");
+                    playlists.append_child(playlist_element);
+                    stats.automatic += 1;
+                } else {
+                    debug!("automatic playlist {} already exists", playlist.name);
+                }
+                continue;
+            }
+            // Skip other smart playlists, until we are able to parse and convert them.
+            Event::PlaylistSkipped {
+                playlist: &playlist.name,
+                persistent_id: playlist.persistent_id.as_deref(),
+                reason: SkipReason::Smart,
+            }
+            .log(Level::Warn);
+            stats.smart_skipped += 1;
+            continue;
+        }
+        let mut playlist_element = Element::new("playlist");
+        if queue_playlist == Some(playlist.name.as_str()) {
+            info!("playlist {} is migrated as the play queue", playlist.name);
+            playlist_element.set_attr("name", "Play Queue");
+            playlist_element.set_attr("show-browser", "false");
+            playlist_element.set_attr("browser-position", "180");
+            playlist_element.set_attr("search-type", "search-match");
+            playlist_element.set_attr("type", "queue");
+        } else {
+            let name = &target_names[playlist.name.as_str()];
+            if *name != playlist.name {
+                warn!(
+                    "playlist {} already exists in Rhythmbox, migrated as {}",
+                    playlist.name, name
+                );
+            }
+            playlist_element.set_attr("name", name);
+            playlist_element.set_attr("type", "static");
+            migrated_playlists.insert(name.clone());
+        }
+        playlist_element.set_text("\n    ");
+        let items = migrated_items(playlist, itunes_library, track_locations, filter);
+        for &location in items.locations.iter() {
+            let mut location_element = Element::new("location");
+            location_element.set_text(location);
+            location_element.set_tail("\n    ");
+            playlist_element.append_child(location_element);
+        }
+        let item_count = playlist_element.child_count();
+        if item_count > 0 {
+            playlist_element
+                .get_child_mut(item_count - 1)
+                .unwrap()
+                .set_tail("\n  ");
+        } else {
+            playlist_element.set_text("");
+            stats.empty += 1;
+        }
+        playlist_element.set_tail("\n  ");
+        playlists.append_child(playlist_element);
+        stats.migrated += 1;
+        stats.deduplicated += items.deduplicated;
+        stats.missing_items += items.missing;
+        stats.unmatched_items += items.unmatched;
+        log_deduplicated(&playlist.name, items.deduplicated);
+        if items.missing > 0 {
+            Event::PlaylistItemsMissingFromLibrary {
+                playlist: &playlist.name,
+                persistent_id: playlist.persistent_id.as_deref(),
+                count: items.missing,
+            }
+            .log(Level::Warn);
+        }
+        if items.unmatched > 0 {
+            Event::PlaylistItemsNotFound {
+                playlist: &playlist.name,
+                persistent_id: playlist.persistent_id.as_deref(),
+                count: items.unmatched,
+            }
+            .log(Level::Warn);
+        }
+    }
+    match playlists.child_count() {
+        0 => playlists.set_text("\n"),
+        count => playlists.get_child_mut(count - 1).unwrap().set_tail("\n"),
+    };
+    stats
+}
+
+pub fn export_playlists(
+    export_dir: &Path,
+    format: ExportFormat,
+    itunes_library: &ItunesLibrary,
+    track_locations: &HashMap<TrackId, String>,
+    location_mappings: &[LocationMapping],
+    filter: &PlaylistFilter,
+) -> Result<()> {
+    info!("Exporting playlists...");
+    fs::create_dir_all(export_dir).context("failed to create export directory")?;
+    let mut exporter = PlaylistExporter::new(export_dir, format);
+    for playlist in itunes_library.playlists.iter() {
+        if !filter.matches(&playlist.name) {
+            debug!("playlist {} is filtered out", playlist.name);
+            continue;
+        }
+        if playlist.smart_info.is_some() {
+            Event::PlaylistSkipped {
+                playlist: &playlist.name,
+                persistent_id: playlist.persistent_id.as_deref(),
+                reason: SkipReason::Smart,
+            }
+            .log(Level::Warn);
+            continue;
+        }
+        let mut missing_count = 0;
+        let mut deduplicator = Deduplicator::new(filter.dedupe_items);
+        let items = playlist
+            .sorted_items(&itunes_library.tracks)
+            .into_iter()
+            .filter(|item| filter.includes_track(item.id))
+            .filter_map(|item| {
+                let track = itunes_library.tracks.get(&item.id);
+                if track.is_none() && itunes_library.missing_track_ids.contains(&item.id) {
+                    missing_count += 1;
+                }
+                let track = track?;
+                // Fallback to the iTunes location for tracks not in Rhythmbox.
+                let location = match track_locations.get(&item.id) {
+                    Some(location) => location.clone(),
+                    None => {
+                        let location = track.location.as_deref()?;
+                        location::remap(location, location_mappings).into_owned()
+                    }
+                };
+                Some(ExportItem { location, track })
+            })
+            .filter(|item| deduplicator.keep(&item.location))
+            .collect::<Vec<_>>();
+        exporter.export(&playlist.name, &items)?;
+        deduplicator.log(&playlist.name);
+        if missing_count > 0 {
+            Event::PlaylistItemsMissingFromLibrary {
+                playlist: &playlist.name,
+                persistent_id: playlist.persistent_id.as_deref(),
+                count: missing_count,
+            }
+            .log(Level::Warn);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use crate::backup::RhythmboxFile;
+use crate::journal::JOURNAL_FILENAME;
+use crate::shell::quote;
+use crate::sync_state::SYNC_STATE_FILENAME;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::{debug, info};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+const SCHEME: &str = "sftp://";
+
+/// Rhythmbox data directory on another machine, accessed via the `ssh` command,
+/// so that keys, agents, and host aliases in the SSH config all apply.
+#[derive(Debug)]
+pub struct RemoteDir {
+    url: String,
+    /// Destination given to `ssh`, e.g. `user@host`.
+    destination: String,
+    port: Option<u16>,
+    /// Path of the directory on the remote machine, relative to the home directory unless absolute.
+    path: String,
+}
+
+impl RemoteDir {
+    /// Parse a URL like `sftp://user@host:2222/~/.local/share/rhythmbox`,
+    /// returning `None` if the path is not such a URL.
+    pub fn parse(path: &Path) -> Result<Option<Self>> {
+        let url = match path.to_str() {
+            Some(url) if url.starts_with(SCHEME) => url,
+            _ => return Ok(None),
+        };
+        let rest = &url[SCHEME.len()..];
+        let path_start = rest
+            .find('/')
+            .ok_or_else(|| anyhow!("no path in {}", url))?;
+        let (authority, path) = rest.split_at(path_start);
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((destination, port)) => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("invalid port in {}", url))?;
+                (destination, Some(port))
+            }
+            None => (authority, None),
+        };
+        ensure!(!destination.is_empty(), "no host in {}", url);
+        // It would be taken as an option by `ssh` otherwise.
+        ensure!(!destination.starts_with('-'), "invalid host in {}", url);
+        let path = match path.strip_prefix("/~") {
+            Some("") => ".".to_owned(),
+            Some(path) if path.starts_with('/') => path[1..].to_owned(),
+            _ => path.to_owned(),
+        };
+        Ok(Some(RemoteDir {
+            url: url.to_owned(),
+            destination: destination.to_owned(),
+            port,
+            path,
+        }))
+    }
+
+    /// Download the Rhythmbox files, along with their backups and the state of migrations,
+    /// into a temporary directory.
+    pub fn download(&self) -> Result<LocalCopy<'_>> {
+        info!("Downloading Rhythmbox files from {}...", self.url);
+        // The directory is only accessible by the user, as it holds the SSH control socket.
+        let dir = tempfile::Builder::new()
+            .prefix("migrate-itunes-to-rhythmbox-")
+            .tempdir()
+            .context("failed to create temporary directory")?;
+        let mut copy = LocalCopy {
+            remote: self,
+            dir,
+            downloaded: Vec::new(),
+        };
+        for filename in remote_filenames() {
+            let content = copy.download(filename)?;
+            if let Some(content) = &content {
+                fs::write(copy.path().join(filename), content)?;
+            }
+            copy.downloaded.push((filename, content));
+        }
+        ensure!(
+            copy.path()
+                .join(RhythmboxFile::Database.filename())
+                .exists(),
+            "no Rhythmbox database found in {}",
+            self.url,
+        );
+        Ok(copy)
+    }
+}
+
+/// Names of files which are synchronized with the remote directory,
+/// in the order they are uploaded, so that backups are in place before the files are replaced.
+fn remote_filenames() -> Vec<&'static str> {
+    let backups = RhythmboxFile::ALL.iter().map(|file| file.backup_filename());
+    let files = RhythmboxFile::ALL.iter().map(|file| file.filename());
+    let states = vec![JOURNAL_FILENAME, SYNC_STATE_FILENAME];
+    backups.chain(files).chain(states).collect()
+}
+
+/// Local copy of files in a remote Rhythmbox data directory, removed when dropped.
+pub struct LocalCopy<'a> {
+    remote: &'a RemoteDir,
+    dir: TempDir,
+    /// Original content of each file, or `None` if the file doesn't exist.
+    downloaded: Vec<(&'static str, Option<Vec<u8>>)>,
+}
+
+impl LocalCopy<'_> {
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Upload files changed locally back to the remote directory.
+    ///
+    /// Each file is written to a temporary file which then replaces the original,
+    /// and files removed locally, e.g. backups after restoring, are removed last.
+    pub fn upload(&self) -> Result<()> {
+        let mut removed = Vec::new();
+        let mut uploaded_count = 0;
+        for (filename, original) in self.downloaded.iter() {
+            let content = match fs::read(self.path().join(filename)) {
+                Ok(content) => Some(content),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            match content {
+                Some(content) if original.as_ref() != Some(&content) => {
+                    if uploaded_count == 0 {
+                        info!("Uploading Rhythmbox files to {}...", self.remote.url);
+                    }
+                    self.upload_file(filename, &content)?;
+                    uploaded_count += 1;
+                }
+                None if original.is_some() => removed.push(*filename),
+                _ => {}
+            }
+        }
+        for filename in removed {
+            debug!("removing {}", filename);
+            let path = quote(&self.remote_path(filename));
+            self.run(&format!("rm -f {}", path), &[])?;
+        }
+        Ok(())
+    }
+
+    /// Whether Rhythmbox is running on the remote machine,
+    /// in which case it may overwrite the uploaded files.
+    pub fn is_rhythmbox_running(&self) -> Result<bool> {
+        let status = self
+            .ssh("pgrep -x rhythmbox > /dev/null")
+            .stdin(Stdio::null())
+            .status()
+            .context("failed to run ssh")?;
+        // `pgrep` exits with 1 if no process matches, and other codes on errors.
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => bail!(
+                "failed to check whether Rhythmbox is running on {}",
+                self.remote.url
+            ),
+        }
+    }
+
+    fn download(&self, filename: &str) -> Result<Option<Vec<u8>>> {
+        let path = quote(&self.remote_path(filename));
+        // Exit with a distinct status if the file doesn't exist.
+        let command = format!("test -e {0} || exit 3; cat {0}", path);
+        let output = self
+            .ssh(&command)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .context("failed to run ssh")?;
+        match output.status.code() {
+            Some(0) => {
+                debug!("downloaded {} ({} bytes)", filename, output.stdout.len());
+                Ok(Some(output.stdout))
+            }
+            Some(3) => Ok(None),
+            _ => bail!("failed to download {} from {}", filename, self.remote.url),
+        }
+    }
+
+    fn upload_file(&self, filename: &str, content: &[u8]) -> Result<()> {
+        debug!("uploading {} ({} bytes)", filename, content.len());
+        let path = self.remote_path(filename);
+        let temp_path = quote(&format!("{}.tmp", path));
+        // `cat` also succeeds if the input ends early, so check the size before replacing.
+        let command = format!(
+            "cat > {0} && [ \"$(wc -c < {0})\" -eq {1} ] && mv -f {0} {2} || {{ rm -f {0}; exit 1; }}",
+            temp_path,
+            content.len(),
+            quote(&path),
+        );
+        self.run(&command, content)
+            .with_context(|| format!("failed to upload {} to {}", filename, self.remote.url))
+    }
+
+    fn run(&self, command: &str, input: &[u8]) -> Result<()> {
+        let mut child = self
+            .ssh(command)
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("failed to run ssh")?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(input)?;
+        drop(stdin);
+        let status = child.wait()?;
+        ensure!(status.success(), "ssh exited with {}", status);
+        Ok(())
+    }
+
+    /// Build an `ssh` command running the shell command on the remote machine.
+    ///
+    /// A single connection is shared by all commands, so that passwords are asked only once.
+    fn ssh(&self, command: &str) -> Command {
+        let mut ssh = self.ssh_with_options();
+        ssh.arg(&self.remote.destination).arg("--").arg(command);
+        ssh
+    }
+
+    fn ssh_with_options(&self) -> Command {
+        let mut ssh = Command::new("ssh");
+        ssh.arg("-o")
+            .arg("ControlMaster=auto")
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.path().join("ssh").display()))
+            .arg("-o")
+            .arg("ControlPersist=yes");
+        if let Some(port) = self.remote.port {
+            ssh.arg("-p").arg(port.to_string());
+        }
+        ssh
+    }
+
+    fn remote_path(&self, filename: &str) -> String {
+        format!("{}/{}", self.remote.path, filename)
+    }
+}
+
+impl Drop for LocalCopy<'_> {
+    fn drop(&mut self) {
+        let status = self
+            .ssh_with_options()
+            .args(["-O", "exit"])
+            .arg(&self.remote.destination)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if let Err(e) = status {
+            debug!("failed to close ssh connection: {}", e);
+        }
+        // The temporary directory is removed when dropped afterwards.
+    }
+}
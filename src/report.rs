@@ -0,0 +1,562 @@
+use crate::itunes_library::{ItunesLibrary, MalformedRecord, Track};
+use crate::playlists::PlaylistStats;
+use crate::rhythmdb::{
+    track_locations, DatabaseMatch, EncodingRepair, EntryType, MatchedEntry, SyncStats,
+};
+use crate::track_key::TrackKey;
+use chrono::{TimeZone, Utc};
+use elementtree::Element;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+
+/// Statistics of matching the iTunes library with the Rhythmbox database.
+#[derive(Debug, Default, Serialize)]
+pub struct MatchReport {
+    pub itunes_tracks: usize,
+    pub rhythmbox_songs: usize,
+    pub matched_songs: usize,
+    /// Rhythmbox songs without iTunes counterpart.
+    pub unmatched_rhythmbox_songs: usize,
+    /// iTunes tracks without Rhythmbox counterpart.
+    pub unmatched_itunes_tracks: usize,
+    /// Matched songs whose durations differ beyond the tolerance.
+    pub duration_mismatches: usize,
+    /// Matched songs which only match after repairing mojibake.
+    pub encoding_repairs: Vec<EncodingRepair>,
+    /// Rhythmbox songs skipped because they lack title or location.
+    pub invalid_rhythmbox_songs: usize,
+    /// Rhythmbox entries other than songs, by type.
+    pub other_rhythmbox_entries: BTreeMap<EntryType, usize>,
+    pub static_playlists: usize,
+    pub smart_playlists: usize,
+    /// Items in static playlists.
+    pub playlist_items: usize,
+    /// Items in static playlists referencing tracks missing from the iTunes library.
+    pub missing_playlist_items: usize,
+    /// Items in static playlists whose tracks are not found in Rhythmbox.
+    pub unmatched_playlist_items: usize,
+    /// Records in the iTunes library skipped because they can't be parsed.
+    pub malformed_itunes_records: Vec<MalformedRecord>,
+    /// DRM-protected iTunes tracks, which need repurchasing or re-ripping.
+    pub protected_tracks: Vec<ReportedTrack>,
+    /// iTunes tracks with start or stop time, which Rhythmbox doesn't keep.
+    pub trimmed_tracks: Vec<TrimmedTrack>,
+    /// Matched songs whose files don't exist, if checked.
+    pub missing_files: Option<Vec<ReportedTrack>>,
+    /// Play statistics of matched songs in both libraries, if compared.
+    pub comparison: Option<PlayComparison>,
+}
+
+/// An iTunes track listed in the report.
+#[derive(Debug, Serialize)]
+pub struct ReportedTrack {
+    pub persistent_id: Option<String>,
+    pub name: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub location: Option<String>,
+}
+
+impl From<&Track> for ReportedTrack {
+    fn from(track: &Track) -> Self {
+        ReportedTrack {
+            persistent_id: track.persistent_id.clone(),
+            name: track.name.clone(),
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            location: track.location.clone(),
+        }
+    }
+}
+
+impl ReportedTrack {
+    /// Report the track with the location of its matched Rhythmbox entry.
+    pub fn matched(matched: &MatchedEntry<'_>) -> Self {
+        ReportedTrack {
+            location: Some(matched.location.clone()),
+            ..ReportedTrack::from(matched.track)
+        }
+    }
+}
+
+/// An iTunes track trimmed via its start or stop time,
+/// with the location of its matched Rhythmbox entry if any.
+#[derive(Debug, Serialize)]
+pub struct TrimmedTrack {
+    #[serde(flatten)]
+    pub track: ReportedTrack,
+    /// Start time in milliseconds.
+    pub start_time: Option<u64>,
+    /// Stop time in milliseconds.
+    pub stop_time: Option<u64>,
+}
+
+impl MatchReport {
+    pub fn new(itunes_library: &ItunesLibrary, database_match: &DatabaseMatch<'_>) -> Self {
+        let mut report = MatchReport {
+            itunes_tracks: itunes_library.tracks.len(),
+            rhythmbox_songs: database_match.song_count,
+            matched_songs: database_match.entries.len(),
+            unmatched_rhythmbox_songs: database_match.song_count - database_match.entries.len(),
+            unmatched_itunes_tracks: database_match.unused_tracks.len(),
+            duration_mismatches: database_match.duration_mismatch_count,
+            encoding_repairs: database_match.encoding_repairs.clone(),
+            invalid_rhythmbox_songs: database_match.invalid_song_count,
+            other_rhythmbox_entries: database_match.other_entry_counts.clone(),
+            malformed_itunes_records: itunes_library.malformed_records.clone(),
+            ..MatchReport::default()
+        };
+        report.protected_tracks = itunes_library
+            .tracks
+            .values()
+            .filter(|track| track.is_protected())
+            .map(ReportedTrack::from)
+            .collect();
+        report
+            .protected_tracks
+            .sort_by(|a, b| (&a.artist, &a.album, &a.name).cmp(&(&b.artist, &b.album, &b.name)));
+        let track_locations = track_locations(&database_match.entries);
+        report.trimmed_tracks = itunes_library
+            .tracks
+            .values()
+            .filter(|track| track.is_trimmed())
+            .map(|track| TrimmedTrack {
+                track: ReportedTrack {
+                    location: track_locations
+                        .get(&track.id)
+                        .cloned()
+                        .or_else(|| track.location.clone()),
+                    ..ReportedTrack::from(track)
+                },
+                start_time: track.start_time,
+                stop_time: track.stop_time,
+            })
+            .collect();
+        report.trimmed_tracks.sort_by(|a, b| {
+            let (a, b) = (&a.track, &b.track);
+            (&a.artist, &a.album, &a.name).cmp(&(&b.artist, &b.album, &b.name))
+        });
+        for playlist in itunes_library.playlists.iter() {
+            if playlist.smart_info.is_some() {
+                report.smart_playlists += 1;
+                continue;
+            }
+            report.static_playlists += 1;
+            report.playlist_items += playlist.items.len();
+            for item in playlist.items.iter() {
+                if itunes_library.missing_track_ids.contains(&item.id) {
+                    report.missing_playlist_items += 1;
+                } else if !track_locations.contains_key(&item.id) {
+                    report.unmatched_playlist_items += 1;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Format milliseconds like `3:05.250`.
+fn format_time(ms: u64) -> String {
+    format!("{}:{:02}.{:03}", ms / 60_000, ms / 1000 % 60, ms % 1000)
+}
+
+fn percentage(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.
+    } else {
+        part as f64 * 100. / total as f64
+    }
+}
+
+impl fmt::Display for MatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        writeln!(f, "iTunes tracks:             {:>8}", self.itunes_tracks)?;
+        writeln!(
+            f,
+            "  unmatched:               {:>8} ({:.1}%)",
+            self.unmatched_itunes_tracks,
+            percentage(self.unmatched_itunes_tracks, self.itunes_tracks),
+        )?;
+        writeln!(f, "Rhythmbox songs:           {:>8}", self.rhythmbox_songs)?;
+        writeln!(
+            f,
+            "  matched:                 {:>8} ({:.1}%)",
+            self.matched_songs,
+            percentage(self.matched_songs, self.rhythmbox_songs),
+        )?;
+        writeln!(
+            f,
+            "  unmatched:               {:>8} ({:.1}%)",
+            self.unmatched_rhythmbox_songs,
+            percentage(self.unmatched_rhythmbox_songs, self.rhythmbox_songs),
+        )?;
+        if self.duration_mismatches > 0 {
+            writeln!(
+                f,
+                "  duration mismatches:     {:>8}",
+                self.duration_mismatches
+            )?;
+        }
+        if self.invalid_rhythmbox_songs > 0 {
+            writeln!(
+                f,
+                "  invalid:                 {:>8}",
+                self.invalid_rhythmbox_songs
+            )?;
+        }
+        for (entry_type, count) in self.other_rhythmbox_entries.iter() {
+            writeln!(
+                f,
+                "Rhythmbox {:<17}{:>8}",
+                format!("{}:", entry_type),
+                count
+            )?;
+        }
+        writeln!(f, "Static playlists:          {:>8}", self.static_playlists)?;
+        writeln!(f, "Smart playlists:           {:>8}", self.smart_playlists)?;
+        writeln!(f, "Playlist items:            {:>8}", self.playlist_items)?;
+        if self.missing_playlist_items > 0 {
+            writeln!(
+                f,
+                "  missing from iTunes:     {:>8} ({:.1}%)",
+                self.missing_playlist_items,
+                percentage(self.missing_playlist_items, self.playlist_items),
+            )?;
+        }
+        writeln!(
+            f,
+            "  unmatched:               {:>8} ({:.1}%)",
+            self.unmatched_playlist_items,
+            percentage(self.unmatched_playlist_items, self.playlist_items),
+        )?;
+        if !self.malformed_itunes_records.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Malformed iTunes records:  {:>8}",
+                self.malformed_itunes_records.len()
+            )?;
+            for malformed in self.malformed_itunes_records.iter() {
+                writeln!(f, "  {}: {}", malformed.record, malformed.error)?;
+            }
+        }
+        if !self.protected_tracks.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "DRM-protected tracks:      {:>8}",
+                self.protected_tracks.len()
+            )?;
+            for track in self.protected_tracks.iter() {
+                write!(f, "  {}", track.name)?;
+                if let Some(artist) = &track.artist {
+                    write!(f, " by {}", artist)?;
+                }
+                if let Some(album) = &track.album {
+                    write!(f, " from {}", album)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        if !self.trimmed_tracks.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Trimmed tracks:            {:>8}",
+                self.trimmed_tracks.len()
+            )?;
+            for trimmed in self.trimmed_tracks.iter() {
+                let track = &trimmed.track;
+                write!(f, "  {}", track.name)?;
+                if let Some(artist) = &track.artist {
+                    write!(f, " by {}", artist)?;
+                }
+                let start = trimmed.start_time.map(format_time);
+                let stop = trimmed.stop_time.map(format_time);
+                writeln!(
+                    f,
+                    ": {} - {}",
+                    start.as_deref().unwrap_or("start"),
+                    stop.as_deref().unwrap_or("end"),
+                )?;
+            }
+        }
+        if !self.encoding_repairs.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "Repaired encodings:        {:>8}",
+                self.encoding_repairs.len()
+            )?;
+            for repair in self.encoding_repairs.iter() {
+                writeln!(f, "  {} (iTunes: {})", repair.rhythmbox, repair.itunes)?;
+            }
+        }
+        if let Some(missing_files) = &self.missing_files {
+            writeln!(f)?;
+            writeln!(f, "Missing files:             {:>8}", missing_files.len())?;
+            for track in missing_files.iter() {
+                writeln!(f, "  {}", track.location.as_deref().unwrap_or_default())?;
+            }
+        }
+        if let Some(comparison) = &self.comparison {
+            writeln!(f)?;
+            write!(f, "{}", comparison)?;
+        }
+        Ok(())
+    }
+}
+
+/// Play statistics of matched songs in iTunes and Rhythmbox side by side.
+#[derive(Debug, Default, Serialize)]
+pub struct PlayComparison {
+    pub songs: Vec<ComparedSong>,
+    pub itunes_play_count: u64,
+    pub rhythmbox_play_count: u64,
+    pub play_count: SideCounts,
+    pub rating: SideCounts,
+    pub last_played: SideCounts,
+    pub first_seen: SideCounts,
+}
+
+/// Values of a matched song in iTunes and Rhythmbox.
+///
+/// Ratings are in the scale of Rhythmbox, and dates are Unix timestamps.
+#[derive(Debug, Serialize)]
+pub struct ComparedSong {
+    pub name: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub location: String,
+    pub play_count: (Option<u64>, Option<u64>),
+    pub rating: (Option<f64>, Option<f64>),
+    pub last_played: (Option<i64>, Option<i64>),
+    pub first_seen: (Option<i64>, Option<i64>),
+}
+
+/// Numbers of songs whose value of a field is larger, i.e. more or newer, on each side.
+#[derive(Debug, Default, Serialize)]
+pub struct SideCounts {
+    pub itunes: usize,
+    pub rhythmbox: usize,
+    pub equal: usize,
+}
+
+impl SideCounts {
+    fn count<T: PartialOrd>(&mut self, (itunes, rhythmbox): &(Option<T>, Option<T>)) {
+        match itunes.partial_cmp(rhythmbox) {
+            Some(Ordering::Greater) => self.itunes += 1,
+            Some(Ordering::Less) => self.rhythmbox += 1,
+            _ => self.equal += 1,
+        }
+    }
+}
+
+impl PlayComparison {
+    pub fn new(rhythmdb: &Element, database_match: &DatabaseMatch<'_>) -> Self {
+        let schema = database_match.schema;
+        let mut comparison = PlayComparison::default();
+        for matched in database_match.entries.iter() {
+            let track = matched.track;
+            let entry = rhythmdb.get_child(matched.index).unwrap();
+            let value = |tag: &'static str| entry.find(tag).map(Element::text);
+            let first_seen = match track.play_date {
+                Some(play_date) => track.date_added.min(play_date),
+                None => track.date_added,
+            };
+            let song = ComparedSong {
+                name: track.name.clone(),
+                artist: track.artist.clone(),
+                album: track.album.clone(),
+                location: matched.location.clone(),
+                play_count: (
+                    track.play_count.map(|count| count as u64),
+                    value(schema.play_count).and_then(|v| v.parse().ok()),
+                ),
+                rating: (
+                    track
+                        .effective_rating(false, false)
+                        .map(|rating| f64::from(rating) * f64::from(schema.max_rating) / 100.),
+                    value(schema.rating).and_then(|v| v.parse().ok()),
+                ),
+                last_played: (
+                    track.play_date.map(|date| date.timestamp()),
+                    value(schema.last_played).and_then(|v| v.parse().ok()),
+                ),
+                first_seen: (
+                    Some(first_seen.timestamp()),
+                    value(schema.first_seen).and_then(|v| v.parse().ok()),
+                ),
+            };
+            comparison.itunes_play_count += song.play_count.0.unwrap_or(0);
+            comparison.rhythmbox_play_count += song.play_count.1.unwrap_or(0);
+            comparison.play_count.count(&song.play_count);
+            comparison.rating.count(&song.rating);
+            comparison.last_played.count(&song.last_played);
+            comparison.first_seen.count(&song.first_seen);
+            comparison.songs.push(song);
+        }
+        comparison
+            .songs
+            .sort_by(|a, b| (&a.artist, &a.album, &a.name).cmp(&(&b.artist, &b.album, &b.name)));
+        comparison
+    }
+}
+
+impl fmt::Display for PlayComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        fn value<T: fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+        }
+        fn date(timestamp: Option<i64>) -> String {
+            let date = timestamp
+                .filter(|&timestamp| timestamp > 0)
+                .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single());
+            value(date.map(|date| date.format("%Y-%m-%d %H:%M")))
+        }
+        writeln!(f, "Play statistics (iTunes | Rhythmbox):")?;
+        for song in self.songs.iter() {
+            let key = TrackKey {
+                name: &song.name,
+                artist: song.artist.as_deref(),
+                album: song.album.as_deref(),
+                disc_number: None,
+                track_number: None,
+            };
+            writeln!(f, "  {}", key)?;
+            writeln!(
+                f,
+                "    play count:  {:>16} | {}",
+                value(song.play_count.0),
+                value(song.play_count.1),
+            )?;
+            writeln!(
+                f,
+                "    rating:      {:>16} | {}",
+                value(song.rating.0),
+                value(song.rating.1),
+            )?;
+            writeln!(
+                f,
+                "    last played: {:>16} | {}",
+                date(song.last_played.0),
+                date(song.last_played.1),
+            )?;
+            writeln!(
+                f,
+                "    first seen:  {:>16} | {}",
+                date(song.first_seen.0),
+                date(song.first_seen.1),
+            )?;
+        }
+        writeln!(
+            f,
+            "Total plays:               {:>8} | {}",
+            self.itunes_play_count, self.rhythmbox_play_count
+        )?;
+        writeln!(f, "Songs with larger value:    iTunes | Rhythmbox | equal")?;
+        for (field, counts) in [
+            ("play count", &self.play_count),
+            ("rating", &self.rating),
+            ("last played", &self.last_played),
+            ("first seen", &self.first_seen),
+        ]
+        .iter()
+        {
+            writeln!(
+                f,
+                "  {:<24}{:>8} | {:>9} | {}",
+                format!("{}:", field),
+                counts.itunes,
+                counts.rhythmbox,
+                counts.equal,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Summary of a migration run.
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationSummary {
+    pub matching: MatchReport,
+    /// Statistics of the database synchronization, if it happened in this run.
+    pub sync: Option<SyncStats>,
+    /// Statistics of the playlist migration, if it happened in this run.
+    pub playlists: Option<PlaylistStats>,
+    /// Elapsed time of each phase in seconds.
+    pub phases: Vec<(&'static str, f64)>,
+}
+
+impl MigrationSummary {
+    /// Record the time elapsed since the start of the phase.
+    pub fn record_phase(&mut self, phase: &'static str, start: Instant) {
+        self.phases.push((phase, start.elapsed().as_secs_f64()));
+    }
+}
+
+impl fmt::Display for MigrationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.matching)?;
+        if let Some(sync) = &self.sync {
+            writeln!(f, "Fields written:            {:>8}", sync.fields_written)?;
+            writeln!(
+                f,
+                "  overridden:              {:>8}",
+                sync.fields_overridden
+            )?;
+            writeln!(f, "  kept:                    {:>8}", sync.fields_kept)?;
+            if !sync.conflicts.is_empty() {
+                writeln!(f, "Conflicts:")?;
+                for conflict in sync.conflicts.iter() {
+                    let resolution = if conflict.kept { "kept" } else { "overwritten" };
+                    writeln!(
+                        f,
+                        "  {} of {}: {} -> {} ({})",
+                        conflict.field, conflict.song, conflict.old, conflict.new, resolution,
+                    )?;
+                }
+            }
+        }
+        if let Some(playlists) = &self.playlists {
+            writeln!(f, "Playlists migrated:        {:>8}", playlists.migrated)?;
+            writeln!(f, "  empty:                   {:>8}", playlists.empty)?;
+            if playlists.deduplicated > 0 {
+                writeln!(
+                    f,
+                    "  duplicate items removed: {:>8}",
+                    playlists.deduplicated
+                )?;
+            }
+            if playlists.missing_items > 0 {
+                writeln!(
+                    f,
+                    "  items missing in iTunes: {:>8}",
+                    playlists.missing_items
+                )?;
+            }
+            if playlists.unmatched_items > 0 {
+                writeln!(
+                    f,
+                    "  items not in Rhythmbox:  {:>8}",
+                    playlists.unmatched_items
+                )?;
+            }
+            writeln!(f, "Playlists filtered out:    {:>8}", playlists.filtered)?;
+            writeln!(f, "Automatic playlists added: {:>8}", playlists.automatic)?;
+            writeln!(
+                f,
+                "Smart playlists skipped:   {:>8}",
+                playlists.smart_skipped
+            )?;
+        }
+        writeln!(f, "Elapsed time:")?;
+        for (phase, seconds) in self.phases.iter() {
+            writeln!(f, "  {:<24}{:>7.2}s", format!("{}:", phase), seconds)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,757 @@
+use crate::event::Event;
+use crate::itunes_library::{Track, TrackId};
+use crate::matching::{self, EntryMetadata, MatchOptions, TrackMap};
+use crate::track_key::TrackKey;
+use crate::xml::OriginalXml;
+use anyhow::{bail, ensure, Context, Result};
+use by_address::ByAddress;
+use clap::ValueEnum;
+use elementtree::{Element, QName};
+use log::{debug, info, warn, Level};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+
+mod schema;
+
+pub use schema::{Schema, Support, SCHEMA};
+
+pub const RHYTHMDB_FILENAME: &str = "rhythmdb.xml";
+
+/// Read the database, failing if its format is unsupported unless `assume_compatible` is set.
+pub fn read_database(rhythmdb_path: &Path, assume_compatible: bool) -> Result<Element> {
+    info!("Reading Rhythmbox database...");
+    let rhythmdb = File::open(rhythmdb_path).context("failed to open database file")?;
+    let rhythmdb =
+        Element::from_reader(BufReader::new(rhythmdb)).context("failed to read database")?;
+    check_database(&rhythmdb, assume_compatible)?;
+    Ok(rhythmdb)
+}
+
+/// Read the database along with its original text,
+/// so that it can be written back with only modified entries rewritten.
+pub fn read_database_preserving(
+    rhythmdb_path: &Path,
+    assume_compatible: bool,
+) -> Result<(Element, OriginalXml)> {
+    info!("Reading Rhythmbox database...");
+    let text = fs::read_to_string(rhythmdb_path).context("failed to open database file")?;
+    let rhythmdb = Element::from_reader(text.as_bytes()).context("failed to read database")?;
+    check_database(&rhythmdb, assume_compatible)?;
+    let original = OriginalXml::new(text, &rhythmdb);
+    Ok((rhythmdb, original))
+}
+
+fn check_database(rhythmdb: &Element, assume_compatible: bool) -> Result<()> {
+    if rhythmdb.tag() != &QName::from("rhythmdb") {
+        ensure!(assume_compatible, "unknown database format");
+        warn!(
+            "unknown database format {}, assuming it compatible",
+            rhythmdb.tag()
+        );
+    }
+    let support = schema::check_version(rhythmdb, assume_compatible)?;
+    match support {
+        Support::Known => {}
+        Support::BestEffort => warn!(
+            "unknown database version {}, reading it on a best-effort basis",
+            rhythmdb.get_attr("version").unwrap(),
+        ),
+        Support::Assumed => warn!(
+            "unsupported database version {}, assuming it compatible",
+            rhythmdb.get_attr("version").unwrap_or("(none)"),
+        ),
+    }
+    Ok(())
+}
+
+/// A song entry in the Rhythmbox database which matches an iTunes track.
+#[derive(Clone)]
+pub struct MatchedEntry<'a> {
+    /// Index of the entry element in the database.
+    pub index: usize,
+    pub location: String,
+    pub track: &'a Track,
+}
+
+/// A song entry in the Rhythmbox database which doesn't match any iTunes track.
+pub struct UnmatchedEntry {
+    /// Index of the entry element in the database.
+    pub index: usize,
+    pub metadata: EntryMetadata,
+}
+
+/// Type of entries in the Rhythmbox database.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryType {
+    Song,
+    /// Internet radio station.
+    Iradio,
+    PodcastFeed,
+    PodcastPost,
+    /// File which Rhythmbox decided not to import, e.g. an image.
+    Ignore,
+    /// File which Rhythmbox failed to import.
+    ImportError,
+    /// Element or entry type unknown to this tool.
+    Unknown,
+}
+
+impl EntryType {
+    pub fn of(entry: &Element, schema: &Schema) -> Self {
+        if entry.tag() != &QName::from(schema.entry) {
+            return EntryType::Unknown;
+        }
+        match entry.get_attr("type") {
+            Some(t) if t == schema.song_type => EntryType::Song,
+            Some("iradio") => EntryType::Iradio,
+            Some("podcast-feed") => EntryType::PodcastFeed,
+            Some("podcast-post") => EntryType::PodcastPost,
+            Some("ignore") => EntryType::Ignore,
+            Some("import-error") => EntryType::ImportError,
+            _ => EntryType::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for EntryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str(match self {
+            EntryType::Song => "song",
+            EntryType::Iradio => "iradio",
+            EntryType::PodcastFeed => "podcast-feed",
+            EntryType::PodcastPost => "podcast-post",
+            EntryType::Ignore => "ignore",
+            EntryType::ImportError => "import-error",
+            EntryType::Unknown => "unknown",
+        })
+    }
+}
+
+/// Result of matching the Rhythmbox database with the iTunes library.
+pub struct DatabaseMatch<'a> {
+    pub entries: Vec<MatchedEntry<'a>>,
+    /// Songs in the Rhythmbox database which don't match any iTunes track.
+    pub unmatched_entries: Vec<UnmatchedEntry>,
+    /// Number of songs in the Rhythmbox database.
+    pub song_count: usize,
+    /// Number of entries of each type other than songs, which are left untouched.
+    pub other_entry_counts: BTreeMap<EntryType, usize>,
+    /// Number of songs skipped because they lack title or location.
+    pub invalid_song_count: usize,
+    /// Number of matched songs whose durations differ beyond the tolerance.
+    pub duration_mismatch_count: usize,
+    /// iTunes tracks which don't match any song in the Rhythmbox database.
+    pub unused_tracks: Vec<&'a Track>,
+    /// Matched songs which only match after repairing mojibake.
+    pub encoding_repairs: Vec<EncodingRepair>,
+    /// Schema of the Rhythmbox database.
+    pub schema: &'static Schema,
+}
+
+/// A Rhythmbox song matching an iTunes track after repairing mojibake on either side.
+#[derive(Clone, Debug, Serialize)]
+pub struct EncodingRepair {
+    pub location: String,
+    /// Metadata of the song in Rhythmbox.
+    pub rhythmbox: String,
+    /// Metadata of the track in iTunes.
+    pub itunes: String,
+}
+
+pub fn match_database<'a>(
+    rhythmdb: &Element,
+    itunes_track_map: &TrackMap<'a>,
+    options: &MatchOptions,
+) -> Result<DatabaseMatch<'a>> {
+    info!("Matching Rhythmbox database with iTunes library...");
+    let schema = &SCHEMA;
+    // Snapshot the metadata of all songs, so that they can be matched in parallel.
+    let EntrySnapshot {
+        indices: entry_indices,
+        entries,
+        other_entry_counts,
+        invalid_song_count,
+    } = snapshot_entries(rhythmdb, schema, options)?;
+    let mut matched_tracks = matching::match_entries(&entries, itunes_track_map);
+    matching::match_works(&entries, &mut matched_tracks, itunes_track_map);
+    if let Some(sanitizer) = &options.sanitizer {
+        matching::match_sanitized(&entries, &mut matched_tracks, itunes_track_map, sanitizer);
+    }
+    matching::match_canonical(
+        &entries,
+        &mut matched_tracks,
+        itunes_track_map,
+        &options.canonicalizer,
+    );
+    let repaired_indices = if options.repair_encoding {
+        matching::match_repaired(&entries, &mut matched_tracks, itunes_track_map)
+    } else {
+        Vec::new()
+    };
+    let duration_mismatch_count = match options.duration_tolerance {
+        Some(tolerance) => matching::check_durations(
+            &entries,
+            &mut matched_tracks,
+            tolerance,
+            options.duration_mismatch,
+        )?,
+        None => 0,
+    };
+    matching::match_manually(
+        &entries,
+        &mut matched_tracks,
+        itunes_track_map,
+        &options.manual_matches,
+    );
+    let entries_len = entries.len();
+    for (entry_type, count) in other_entry_counts.iter() {
+        info!("{} {} entries are left untouched", count, entry_type);
+    }
+
+    let mut unused_itunes_tracks = itunes_track_map
+        .values()
+        .copied()
+        .map(ByAddress)
+        .collect::<HashSet<_>>();
+    let mut matched_entries = Vec::with_capacity(itunes_track_map.len());
+    let mut unmatched_entries = Vec::new();
+    // Entries matched by repairing encoding may have been unmatched by the duration check.
+    let encoding_repairs = repaired_indices
+        .into_iter()
+        .filter_map(|i| {
+            let track = matched_tracks[i]?;
+            Some(EncodingRepair {
+                location: entries[i].location.clone(),
+                rhythmbox: entries[i].key().to_string(),
+                itunes: TrackKey::from(track).to_string(),
+            })
+        })
+        .collect();
+    for ((index, metadata), track) in entry_indices.into_iter().zip(entries).zip(matched_tracks) {
+        match track {
+            Some(track) => {
+                debug!("song {} matched at {}", metadata.key(), metadata.location);
+                unused_itunes_tracks.remove(&ByAddress(track));
+                matched_entries.push(MatchedEntry {
+                    index,
+                    location: metadata.location,
+                    track,
+                });
+            }
+            None => {
+                Event::SongNotFound {
+                    song: metadata.key(),
+                }
+                .log(Level::Warn);
+                unmatched_entries.push(UnmatchedEntry { index, metadata });
+            }
+        }
+    }
+    let unused_tracks = unused_itunes_tracks
+        .into_iter()
+        .map(|track| track.0)
+        .collect::<Vec<_>>();
+    for track in unused_tracks.iter() {
+        Event::SongUnused {
+            song: TrackKey::from(*track),
+            persistent_id: track.persistent_id.as_deref(),
+        }
+        .log(Level::Warn);
+    }
+    Ok(DatabaseMatch {
+        song_count: entries_len,
+        other_entry_counts,
+        invalid_song_count,
+        duration_mismatch_count,
+        entries: matched_entries,
+        unmatched_entries,
+        unused_tracks,
+        encoding_repairs,
+        schema,
+    })
+}
+
+/// Metadata of song entries in the database, snapshotted for matching.
+struct EntrySnapshot {
+    /// Index of each entry element in the database.
+    indices: Vec<usize>,
+    entries: Vec<EntryMetadata>,
+    other_entry_counts: BTreeMap<EntryType, usize>,
+    invalid_song_count: usize,
+}
+
+/// Read the metadata of all song entries in the database, as used for matching.
+pub fn read_entries(rhythmdb: &Element, options: &MatchOptions) -> Result<Vec<EntryMetadata>> {
+    Ok(snapshot_entries(rhythmdb, &SCHEMA, options)?.entries)
+}
+
+fn snapshot_entries(
+    rhythmdb: &Element,
+    schema: &Schema,
+    options: &MatchOptions,
+) -> Result<EntrySnapshot> {
+    let mut entry_indices = Vec::new();
+    let mut entries = Vec::new();
+    let mut other_entry_counts = BTreeMap::new();
+    let mut invalid_song_count = 0;
+    for (index, entry) in rhythmdb.children().enumerate() {
+        let entry_type = EntryType::of(entry, schema);
+        if entry_type != EntryType::Song {
+            if entry_type == EntryType::Unknown {
+                debug!("skipping unknown entry {} at {}", entry.tag(), index);
+            }
+            *other_entry_counts.entry(entry_type).or_insert(0) += 1;
+            continue;
+        }
+        // Read the metadata of the entry.
+        let child_text = |tag: &'static str| entry.find(tag).map(Element::text);
+        let (name, location) = match (child_text(schema.title), child_text(schema.location)) {
+            (Some(name), Some(location)) => (name.to_owned(), location.to_owned()),
+            (None, Some(location)) => {
+                warn!("song without title at {} is skipped", location);
+                invalid_song_count += 1;
+                continue;
+            }
+            (_, None) => {
+                warn!("song without location at entry {} is skipped", index);
+                invalid_song_count += 1;
+                continue;
+            }
+        };
+        let artist = child_text(schema.artist)
+            // Fixup known "unknown" artist.
+            .filter(|artist| !options.unknown_artists.iter().any(|a| a == artist))
+            .map(str::to_owned);
+        let album = child_text(schema.album).map(str::to_owned);
+        let disc_number = child_text(schema.disc_number)
+            .map(str::parse)
+            .transpose()
+            .with_context(|| format!("invalid disc number of {}", location))?;
+        let track_number = child_text(schema.track_number)
+            .map(str::parse)
+            .transpose()
+            .with_context(|| format!("invalid track number of {}", location))?;
+        entry_indices.push(index);
+        entries.push(EntryMetadata {
+            name,
+            artist,
+            album,
+            disc_number,
+            track_number,
+            location,
+            duration: child_text(schema.duration).and_then(|d| d.parse().ok()),
+        });
+    }
+    Ok(EntrySnapshot {
+        indices: entry_indices,
+        entries,
+        other_entry_counts,
+        invalid_song_count,
+    })
+}
+
+/// Map from iTunes track to the location of its matched Rhythmbox entry.
+pub fn track_locations(matched_entries: &[MatchedEntry<'_>]) -> HashMap<TrackId, String> {
+    matched_entries
+        .iter()
+        .map(|matched| (matched.track.id, matched.location.clone()))
+        .collect()
+}
+
+/// Field of Rhythmbox entries which can be migrated from iTunes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Field {
+    FirstSeen,
+    LastPlayed,
+    PlayCount,
+    Rating,
+    /// Beats per minute.
+    Bpm,
+    Comment,
+}
+
+impl Field {
+    /// Fields migrated when not specified otherwise.
+    pub const DEFAULT: [Field; 3] = [Field::FirstSeen, Field::LastPlayed, Field::PlayCount];
+    /// Fields of track metadata, which Rhythmbox usually reads from the files.
+    pub const METADATA: [Field; 2] = [Field::Bpm, Field::Comment];
+
+    pub fn tag(self, schema: &Schema) -> &'static str {
+        match self {
+            Field::FirstSeen => schema.first_seen,
+            Field::LastPlayed => schema.last_played,
+            Field::PlayCount => schema.play_count,
+            Field::Rating => schema.rating,
+            Field::Bpm => schema.bpm,
+            Field::Comment => schema.comment,
+        }
+    }
+
+    /// Value of this field from the iTunes track, if it should be written.
+    fn value(self, track: &Track, schema: &Schema, options: &SyncOptions) -> Option<String> {
+        match self {
+            // Keep the history coherent for Rhythmbox's Recently Added and Recently Played,
+            // as iTunes may have a play date earlier than the date added after re-adding,
+            // or a play date without play count.
+            Field::FirstSeen => {
+                let first_seen = match track.play_date {
+                    Some(play_date) => track.date_added.min(play_date),
+                    None => track.date_added,
+                };
+                Some(first_seen.timestamp().to_string())
+            }
+            Field::LastPlayed => track.play_date.map(|date| date.timestamp().to_string()),
+            Field::PlayCount => track
+                .play_count
+                .filter(|&count| count > 0)
+                .or_else(|| track.play_date.map(|_| 1))
+                .map(|count| count.to_string()),
+            Field::Rating => {
+                let rating =
+                    track.effective_rating(options.computed_ratings, options.album_ratings);
+                let rating = match rating {
+                    // iTunes uses 0-100 for rating.
+                    Some(rating) => f64::from(rating) * f64::from(schema.max_rating) / 100.,
+                    None if track.loved => f64::from(options.loved_rating?),
+                    None if track.disliked => f64::from(options.disliked_rating?),
+                    None => return None,
+                };
+                Some(rating.to_string())
+            }
+            Field::Bpm => track.bpm.filter(|&bpm| bpm > 0).map(|bpm| bpm.to_string()),
+            Field::Comment => track
+                .comments
+                .as_ref()
+                .filter(|comments| !comments.is_empty())
+                .cloned(),
+        }
+    }
+}
+
+/// Options controlling what gets written into the Rhythmbox database.
+#[derive(Debug)]
+pub struct SyncOptions {
+    pub fields: Vec<Field>,
+    /// Rating given to loved tracks without explicit rating.
+    pub loved_rating: Option<u8>,
+    /// Rating given to disliked tracks without explicit rating.
+    pub disliked_rating: Option<u8>,
+    /// Whether to migrate ratings computed by iTunes as if given by the user.
+    pub computed_ratings: bool,
+    /// Whether to give unrated tracks the rating of their album.
+    pub album_ratings: bool,
+    /// Whether to hide entries of tracks disabled in iTunes.
+    pub hide_disabled: bool,
+    /// What to do when an entry already has a different value.
+    pub conflict_policy: ConflictPolicy,
+}
+
+/// What to do when a Rhythmbox entry already has a value different from iTunes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Overwrite with the value from iTunes
+    Overwrite,
+    /// Keep the Rhythmbox value when it's larger, e.g. play counts accumulated after switching
+    Keep,
+    /// Ask for each conflicting value
+    Prompt,
+}
+
+impl ConflictPolicy {
+    /// Whether the existing value may be left in place of the migrated one.
+    pub fn may_keep(self, old: &str, new: &str) -> bool {
+        match self {
+            ConflictPolicy::Overwrite => false,
+            ConflictPolicy::Keep => is_larger(old, new),
+            ConflictPolicy::Prompt => true,
+        }
+    }
+}
+
+fn is_larger(a: &str, b: &str) -> bool {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a > b,
+        _ => false,
+    }
+}
+
+/// Fields of the Rhythmbox entry to be written from the iTunes track.
+pub fn migrated_fields(
+    track: &Track,
+    schema: &Schema,
+    options: &SyncOptions,
+) -> Vec<(&'static str, String)> {
+    let mut fields = options
+        .fields
+        .iter()
+        .filter_map(|field| Some((field.tag(schema), field.value(track, schema, options)?)))
+        .collect::<Vec<_>>();
+    if options.hide_disabled && track.disabled {
+        fields.push((schema.hidden, "1".to_owned()));
+    }
+    fields
+}
+
+/// Statistics of synchronizing to the Rhythmbox database.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncStats {
+    pub fields_written: usize,
+    /// Written fields which had a different value before.
+    pub fields_overridden: usize,
+    /// Fields which kept their different value in Rhythmbox.
+    pub fields_kept: usize,
+    /// Fields which had a different value in Rhythmbox, except first seen.
+    pub conflicts: Vec<FieldConflict>,
+}
+
+/// A field of a Rhythmbox entry which had a value different from iTunes.
+#[derive(Debug, Serialize)]
+pub struct FieldConflict {
+    pub song: String,
+    pub persistent_id: Option<String>,
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+    /// Whether the value in Rhythmbox was kept.
+    pub kept: bool,
+}
+
+pub fn sync_to_database(
+    rhythmdb: &mut Element,
+    matched_entries: &[MatchedEntry<'_>],
+    schema: &Schema,
+    options: &SyncOptions,
+) -> Result<SyncStats> {
+    info!("Synchronizing to Rhythmbox database...");
+    let mut stats = SyncStats::default();
+    let mut prompt = ConflictPrompt::default();
+    for matched in matched_entries {
+        let track = matched.track;
+        let entry = rhythmdb.get_child_mut(matched.index).unwrap();
+        let key = TrackKey::from(track);
+
+        for (tag, text) in migrated_fields(track, schema, options) {
+            let element = match entry.find_mut(tag) {
+                Some(element) => element,
+                None => {
+                    debug!("setting {} of {} to {}", tag, key, text);
+                    append_child(entry, tag, text);
+                    stats.fields_written += 1;
+                    continue;
+                }
+            };
+            let old = element.text();
+            if old == text {
+                continue;
+            }
+            // First seen is expected to differ, as it's when the file was imported into Rhythmbox.
+            if tag != schema.first_seen {
+                let kept = options.conflict_policy.may_keep(old, &text)
+                    && match options.conflict_policy {
+                        ConflictPolicy::Prompt => prompt.keep(key, tag, old, &text)?,
+                        _ => true,
+                    };
+                let event = Event::FieldOverridden {
+                    song: key,
+                    field: tag,
+                    old,
+                    new: &text,
+                };
+                stats.conflicts.push(FieldConflict {
+                    song: key.to_string(),
+                    persistent_id: track.persistent_id.clone(),
+                    field: tag,
+                    old: old.to_owned(),
+                    new: text.clone(),
+                    kept,
+                });
+                if kept {
+                    debug!("keeping {} of {} as {}", tag, key, old);
+                    stats.fields_kept += 1;
+                    continue;
+                }
+                event.log(Level::Warn);
+                stats.fields_overridden += 1;
+            }
+            debug!("updating {} of {} to {}", tag, key, text);
+            element.set_text(text);
+            stats.fields_written += 1;
+        }
+    }
+    Ok(stats)
+}
+
+/// Interactive prompt for conflicting values, remembering "all" answers.
+#[derive(Default)]
+struct ConflictPrompt {
+    keep_all: Option<bool>,
+}
+
+impl ConflictPrompt {
+    fn keep(&mut self, key: TrackKey<'_>, field: &str, old: &str, new: &str) -> Result<bool> {
+        if let Some(keep) = self.keep_all {
+            return Ok(keep);
+        }
+        loop {
+            eprint!(
+                "{} of {} is {} in Rhythmbox but {} in iTunes. \
+                 [o]verwrite, [k]eep, overwrite [a]ll, keep a[l]l? ",
+                field, key, old, new,
+            );
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            if io::stdin().read_line(&mut answer)? == 0 {
+                bail!("no answer to the conflict prompt");
+            }
+            match answer.trim() {
+                "o" => return Ok(false),
+                "k" => return Ok(true),
+                "a" => self.keep_all = Some(false),
+                "l" => self.keep_all = Some(true),
+                _ => continue,
+            }
+            return Ok(self.keep_all.unwrap());
+        }
+    }
+}
+
+/// Append a child element with the given text to the entry, keeping the indentation.
+fn append_child(entry: &mut Element, tag: &'static str, text: String) {
+    let indentation = entry.text().to_string();
+    let last_element = entry.get_child_mut(entry.child_count() - 1).unwrap();
+    let mut element = Element::new(tag);
+    element.set_text(text);
+    element.set_tail(last_element.tail());
+    last_element.set_tail(indentation);
+    entry.append_child(element);
+}
+
+/// Append a song entry for the iTunes track at the given location,
+/// returning the index of the new entry.
+///
+/// Only the basic metadata and what the Rhythmbox UI shows about the file are written,
+/// the rest is left for Rhythmbox to fill in when it rescans the file.
+pub fn append_song_entry(
+    rhythmdb: &mut Element,
+    schema: &Schema,
+    track: &Track,
+    location: &str,
+) -> usize {
+    if rhythmdb.child_count() == 0 {
+        rhythmdb.set_text("\n  ");
+    }
+    let indentation = rhythmdb.text().to_string();
+    let child_indentation = format!("{}  ", indentation);
+    let mut fields = vec![(schema.title, track.name.clone())];
+    if let Some(artist) = &track.artist {
+        fields.push((schema.artist, artist.clone()));
+    }
+    if let Some(album) = &track.album {
+        fields.push((schema.album, album.clone()));
+    }
+    if let Some(track_number) = track.track_number {
+        fields.push((schema.track_number, track_number.to_string()));
+    }
+    if let Some(disc_number) = track.disc_number {
+        fields.push((schema.disc_number, disc_number.to_string()));
+    }
+    // There is no field for the sample rate, which Rhythmbox doesn't keep.
+    if let Some(bit_rate) = track.bit_rate {
+        fields.push((schema.bitrate, bit_rate.to_string()));
+    }
+    if let Some(media_type) = track.media_type() {
+        fields.push((schema.media_type, media_type.to_owned()));
+    }
+    fields.push((schema.location, location.to_owned()));
+
+    let mut entry = Element::new(schema.entry);
+    entry.set_attr("type", schema.song_type);
+    entry.set_text(child_indentation.as_str());
+    let field_count = fields.len();
+    for (i, (tag, text)) in fields.into_iter().enumerate() {
+        let tail = if i + 1 == field_count {
+            &indentation
+        } else {
+            &child_indentation
+        };
+        entry
+            .append_new_child(tag)
+            .set_text(text)
+            .set_tail(tail.as_str());
+    }
+    match rhythmdb.child_count() {
+        0 => {
+            entry.set_tail("\n");
+        }
+        count => {
+            let last_entry = rhythmdb.get_child_mut(count - 1).unwrap();
+            entry.set_tail(last_entry.tail());
+            last_entry.set_tail(indentation);
+        }
+    }
+    rhythmdb.append_child(entry);
+    rhythmdb.child_count() - 1
+}
+
+/// What to do with Rhythmbox songs which are not in the iTunes library.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum PruneAction {
+    /// Only list the songs
+    List,
+    /// Hide the songs from the Rhythmbox library
+    Hide,
+    /// Remove the songs from the Rhythmbox database
+    Remove,
+}
+
+/// Hide or remove the unmatched entries from the database.
+///
+/// Removing entries invalidates the indices of matched entries,
+/// so this should be the last change to the database.
+pub fn prune_database(
+    rhythmdb: &mut Element,
+    unmatched_entries: &[UnmatchedEntry],
+    schema: &Schema,
+    action: PruneAction,
+) {
+    match action {
+        PruneAction::List => {}
+        PruneAction::Hide => {
+            info!("Hiding {} songs not in iTunes...", unmatched_entries.len());
+            for unmatched in unmatched_entries {
+                let entry = rhythmdb.get_child_mut(unmatched.index).unwrap();
+                match entry.find_mut(schema.hidden) {
+                    Some(element) => {
+                        element.set_text("1");
+                    }
+                    None => append_child(entry, schema.hidden, "1".to_owned()),
+                }
+            }
+        }
+        PruneAction::Remove => {
+            info!(
+                "Removing {} songs not in iTunes...",
+                unmatched_entries.len()
+            );
+            let mut indices = unmatched_entries
+                .iter()
+                .map(|unmatched| unmatched.index)
+                .collect::<Vec<_>>();
+            indices.sort_unstable();
+            // Remove from the end, so that the remaining indices stay valid.
+            for &index in indices.iter().rev() {
+                let entry = rhythmdb.remove_child(index).unwrap();
+                // The tail of the last entry precedes the closing tag of the database.
+                if index == rhythmdb.child_count() && index > 0 {
+                    let last_entry = rhythmdb.get_child_mut(index - 1).unwrap();
+                    last_entry.set_tail(entry.tail());
+                }
+            }
+        }
+    }
+}
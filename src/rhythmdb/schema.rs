@@ -0,0 +1,114 @@
+use anyhow::{anyhow, ensure, Result};
+use elementtree::Element;
+
+/// Element names and value semantics of the Rhythmbox database.
+#[derive(Debug)]
+pub struct Schema {
+    /// Tag of entry elements.
+    pub entry: &'static str,
+    /// Value of the `type` attribute of song entries.
+    pub song_type: &'static str,
+    pub title: &'static str,
+    pub artist: &'static str,
+    pub album: &'static str,
+    pub disc_number: &'static str,
+    pub track_number: &'static str,
+    pub location: &'static str,
+    /// Duration in seconds.
+    pub duration: &'static str,
+    /// Unix timestamp when the song was added.
+    pub first_seen: &'static str,
+    /// Unix timestamp when the song was last played.
+    pub last_played: &'static str,
+    pub play_count: &'static str,
+    pub rating: &'static str,
+    pub hidden: &'static str,
+    pub bpm: &'static str,
+    pub comment: &'static str,
+    /// Bit rate in kbit/s.
+    pub bitrate: &'static str,
+    /// MIME type of the file, e.g. `audio/mpeg`.
+    pub media_type: &'static str,
+    /// Rating of the best songs, with 0 being unrated.
+    pub max_rating: u8,
+}
+
+/// Schema shared by all supported database versions,
+/// as no version so far has changed the elements used here.
+pub static SCHEMA: Schema = Schema {
+    entry: "entry",
+    song_type: "song",
+    title: "title",
+    artist: "artist",
+    album: "album",
+    disc_number: "disc-number",
+    track_number: "track-number",
+    location: "location",
+    duration: "duration",
+    first_seen: "first-seen",
+    last_played: "last-played",
+    play_count: "play-count",
+    rating: "rating",
+    hidden: "hidden",
+    bpm: "beats-per-minute",
+    comment: "comment",
+    bitrate: "bitrate",
+    media_type: "media-type",
+    max_rating: 5,
+};
+
+/// Major versions of the database the schema applies to.
+const SUPPORTED_MAJOR_VERSIONS: [u32; 2] = [1, 2];
+
+/// Versions written by Rhythmbox releases the schema has been checked against.
+const KNOWN_VERSIONS: [&str; 2] = ["1.8", "2.0"];
+
+/// How well a database version is supported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Support {
+    /// The version is known.
+    Known,
+    /// The version is unknown, but has a supported major version,
+    /// so the schema is used on a best-effort basis.
+    BestEffort,
+    /// The version is unsupported or missing,
+    /// but the schema is used as compatibility is assumed.
+    Assumed,
+}
+
+/// Check that the database has a version the schema applies to.
+///
+/// Databases of unsupported or missing versions are accepted if `assume_compatible` is set,
+/// e.g. for patched Rhythmbox builds.
+pub fn check_version(rhythmdb: &Element, assume_compatible: bool) -> Result<Support> {
+    let result = match rhythmdb.get_attr("version") {
+        Some(version) => Schema::check_version(version),
+        None => Err(anyhow!("database version not specified")),
+    };
+    match result {
+        Err(_) if assume_compatible => Ok(Support::Assumed),
+        result => result,
+    }
+}
+
+impl Schema {
+    /// Check how well the given version string, e.g. `2.0`, is supported.
+    ///
+    /// Unknown minor versions of supported major versions are read on a best-effort basis.
+    fn check_version(version: &str) -> Result<Support> {
+        if KNOWN_VERSIONS.contains(&version) {
+            return Ok(Support::Known);
+        }
+        let major = version
+            .split_once('.')
+            .filter(|(_, minor)| minor.parse::<u32>().is_ok())
+            .and_then(|(major, _)| major.parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("invalid database version {}", version))?;
+        ensure!(
+            SUPPORTED_MAJOR_VERSIONS.contains(&major),
+            "unsupported database version {}",
+            version,
+        );
+        Ok(Support::BestEffort)
+    }
+}
@@ -0,0 +1,4 @@
+/// Quote the string for the POSIX shell.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
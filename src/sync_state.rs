@@ -0,0 +1,212 @@
+use crate::rhythmdb::{migrated_fields, MatchedEntry, Schema, SyncOptions};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+pub const SYNC_STATE_FILENAME: &str = "sync-state.json";
+
+/// Record of what has been written into Rhythmbox files, kept in the Rhythmbox data directory,
+/// so that later migrations only touch entries whose iTunes track has changed,
+/// and only replace playlists created by migration.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SyncState {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Synchronized tracks keyed by their persistent IDs, recorded by incremental migrations.
+    pub tracks: HashMap<String, SyncedTrack>,
+    /// Names of static playlists created by migration.
+    #[serde(default)]
+    pub playlists: BTreeSet<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SyncedTrack {
+    /// Location of the Rhythmbox entry synchronized,
+    /// so that the track is synchronized again if it matches another entry, e.g. after moving.
+    ///
+    /// States written before this was recorded have it empty.
+    #[serde(default)]
+    pub location: String,
+    /// `Date Modified` of the iTunes track.
+    pub date_modified: DateTime<Utc>,
+    /// Values of fields last written, keyed by their tags.
+    ///
+    /// These are compared as well, since iTunes doesn't update `Date Modified` on playback.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl SyncState {
+    /// Load the state of the last incremental migration, or start a new one.
+    pub fn open(rhythmbox_path: &Path) -> Result<Self> {
+        let path = rhythmbox_path.join(SYNC_STATE_FILENAME);
+        let mut state = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<SyncState>(&content)
+                .context("failed to read sync state")?,
+            Err(e) if e.kind() == ErrorKind::NotFound => SyncState::default(),
+            Err(e) => return Err(e).context("failed to open sync state"),
+        };
+        state.path = path;
+        Ok(state)
+    }
+
+    /// Matched entries whose iTunes tracks have changed since they were last synchronized.
+    ///
+    /// Tracks without persistent ID are always considered changed.
+    pub fn changed_entries<'a>(
+        &self,
+        matched_entries: &[MatchedEntry<'a>],
+        schema: &Schema,
+        options: &SyncOptions,
+    ) -> Vec<MatchedEntry<'a>> {
+        let changed = matched_entries
+            .iter()
+            .filter(|matched| {
+                let synced = matched
+                    .track
+                    .persistent_id
+                    .as_ref()
+                    .and_then(|id| self.tracks.get(id));
+                synced != Some(&synced_track(matched, schema, options))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        info!(
+            "{} tracks unchanged since the last migration are skipped",
+            matched_entries.len() - changed.len(),
+        );
+        changed
+    }
+
+    /// Record the iTunes tracks synchronized into the database.
+    pub fn record(
+        &mut self,
+        matched_entries: &[MatchedEntry<'_>],
+        schema: &Schema,
+        options: &SyncOptions,
+    ) {
+        for matched in matched_entries {
+            if let Some(id) = &matched.track.persistent_id {
+                let synced = synced_track(matched, schema, options);
+                self.tracks.insert(id.clone(), synced);
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let content = serde_json::to_vec(self)?;
+        // Write into a temporary file first, so that the state is never left partially written.
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, content).context("failed to write sync state")?;
+        fs::rename(&temp_path, &self.path).context("failed to write sync state")?;
+        Ok(())
+    }
+}
+
+fn synced_track(matched: &MatchedEntry<'_>, schema: &Schema, options: &SyncOptions) -> SyncedTrack {
+    let fields = migrated_fields(matched.track, schema, options);
+    SyncedTrack {
+        location: matched.location.clone(),
+        date_modified: matched.track.date_modified,
+        fields: fields
+            .into_iter()
+            .map(|(tag, value)| (tag.to_owned(), value))
+            .collect(),
+    }
+}
+
+/// Remove the sync state in the Rhythmbox data directory, if any,
+/// e.g. after the database is restored to a state it doesn't describe.
+pub fn remove_sync_state(rhythmbox_path: &Path) -> Result<()> {
+    match fs::remove_file(rhythmbox_path.join(SYNC_STATE_FILENAME)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e).context("failed to remove sync state"),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::itunes_library::Track;
+    use crate::rhythmdb::{ConflictPolicy, SCHEMA};
+
+    fn track() -> Track {
+        let xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <plist version=\"1.0\"><dict>\
+            <key>Track ID</key><integer>1</integer>\
+            <key>Persistent ID</key><string>0123456789ABCDEF</string>\
+            <key>Name</key><string>Song</string>\
+            <key>Play Count</key><integer>3</integer>\
+            <key>Date Modified</key><date>2019-01-01T00:00:00Z</date>\
+            <key>Date Added</key><date>2019-01-01T00:00:00Z</date>\
+            </dict></plist>";
+        plist::from_bytes(xml.as_bytes()).unwrap()
+    }
+
+    fn options() -> SyncOptions {
+        SyncOptions {
+            fields: Vec::new(),
+            loved_rating: None,
+            disliked_rating: None,
+            computed_ratings: false,
+            album_ratings: false,
+            hide_disabled: false,
+            conflict_policy: ConflictPolicy::Overwrite,
+        }
+    }
+
+    fn entry<'a>(track: &'a Track, location: &str) -> MatchedEntry<'a> {
+        MatchedEntry {
+            index: 0,
+            location: location.to_owned(),
+            track,
+        }
+    }
+
+    #[test]
+    fn skips_unchanged_entries() {
+        let (track, options) = (track(), options());
+        let entries = [entry(&track, "file:///music/song.mp3")];
+        let mut state = SyncState::default();
+        assert_eq!(state.changed_entries(&entries, &SCHEMA, &options).len(), 1);
+        state.record(&entries, &SCHEMA, &options);
+        assert!(state
+            .changed_entries(&entries, &SCHEMA, &options)
+            .is_empty());
+    }
+
+    #[test]
+    fn syncs_track_matching_another_entry() {
+        let (track, options) = (track(), options());
+        let mut state = SyncState::default();
+        state.record(
+            &[entry(&track, "file:///music/song.mp3")],
+            &SCHEMA,
+            &options,
+        );
+        let moved = [entry(&track, "file:///music/moved/song.mp3")];
+        let changed = state.changed_entries(&moved, &SCHEMA, &options);
+        let locations = changed
+            .iter()
+            .map(|matched| matched.location.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(locations, ["file:///music/moved/song.mp3"]);
+    }
+
+    #[test]
+    fn syncs_tracks_recorded_without_location() {
+        let (track, options) = (track(), options());
+        let entries = [entry(&track, "file:///music/song.mp3")];
+        let mut state = SyncState::default();
+        state.record(&entries, &SCHEMA, &options);
+        let content = serde_json::to_string(&state)
+            .unwrap()
+            .replace("\"location\":\"file:///music/song.mp3\",", "");
+        let state = serde_json::from_str::<SyncState>(&content).unwrap();
+        assert_eq!(state.changed_entries(&entries, &SCHEMA, &options).len(), 1);
+    }
+}
@@ -1,7 +1,15 @@
 use crate::itunes_library::Track;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::path::Path;
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct TrackKey<'a> {
     pub name: &'a str,
     pub artist: Option<&'a str>,
@@ -33,3 +41,372 @@ impl<'a> fmt::Display for TrackKey<'a> {
         )
     }
 }
+
+/// Patterns stripped from names by default when sanitizing.
+const DEFAULT_SANITIZE_PATTERNS: [&str; 4] = [
+    // Featured artists in brackets, e.g. "Song (feat. X)".
+    r"(?i)\s*[(\[]\s*(?:feat\.?|ft\.|featuring)\s[^)\]]*[)\]]",
+    // Featured artists as suffix, e.g. "Artist feat. X".
+    r"(?i)\s+(?:feat\.?|ft\.|featuring)\s.*$",
+    // Remaster and edition tags in brackets, e.g. "(Remastered 2011)" or "[Deluxe Edition]".
+    r"(?i)\s*[(\[][^)\]]*\b(?:remaster(?:ed)?|deluxe|edition|version|bonus track)\b[^)\]]*[)\]]",
+    // Remaster tags as suffix, e.g. "Song - 2011 Remaster".
+    r"(?i)\s+-\s+(?:\d{4}\s+)?remaster(?:ed)?(?:\s+\d{4})?(?:\s+version)?$",
+];
+
+/// Normalizer of names, so that tracks whose names differ only in decorations
+/// like remaster tags and featured artists can be matched.
+#[derive(Debug)]
+pub struct Sanitizer {
+    patterns: Vec<Regex>,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        let patterns = DEFAULT_SANITIZE_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect();
+        Sanitizer { patterns }
+    }
+}
+
+impl Sanitizer {
+    /// Create a sanitizer which strips all matches of the given patterns.
+    pub fn new(patterns: Vec<Regex>) -> Self {
+        Sanitizer { patterns }
+    }
+
+    pub fn sanitize(&self, s: &str) -> String {
+        let mut result = s.to_owned();
+        for pattern in self.patterns.iter() {
+            result = pattern.replace_all(&result, "").into_owned();
+        }
+        result.trim().to_owned()
+    }
+
+    pub fn key(&self, key: &TrackKey<'_>) -> SanitizedKey {
+        SanitizedKey {
+            name: self.sanitize(key.name),
+            artist: key.artist.map(|artist| self.sanitize(artist)),
+            album: key.album.map(|album| self.sanitize(album)),
+            disc_number: key.disc_number,
+            track_number: key.track_number,
+        }
+    }
+}
+
+/// Track key with sanitized or canonicalized names.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SanitizedKey {
+    pub name: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub disc_number: Option<usize>,
+    pub track_number: Option<usize>,
+}
+
+/// Leading articles of artist names in several languages,
+/// which may also be moved to the end like `Beatles, The`.
+const ARTICLES: [&str; 15] = [
+    "the", "a", "an", // English
+    "die", "der", "das", // German
+    "le", "la", "les", "l'", // French
+    "el", "los", "las", // Spanish
+    "il", "gli", // Italian
+];
+
+/// Romanization of Cyrillic letters, for artists named in Cyrillic on one side only.
+///
+/// CJK scripts are deliberately not covered, as names like `ビートルズ` romanize to
+/// something far from the Latin name, so they are left to the alias file.
+const CYRILLIC: [(char, &str); 37] = [
+    ('а', "a"),
+    ('б', "b"),
+    ('в', "v"),
+    ('г', "g"),
+    ('д', "d"),
+    ('е', "e"),
+    ('ё', "e"),
+    ('ж', "zh"),
+    ('з', "z"),
+    ('и', "i"),
+    ('й', "y"),
+    ('к', "k"),
+    ('л', "l"),
+    ('м', "m"),
+    ('н', "n"),
+    ('о', "o"),
+    ('п', "p"),
+    ('р', "r"),
+    ('с', "s"),
+    ('т', "t"),
+    ('у', "u"),
+    ('ф', "f"),
+    ('х', "kh"),
+    ('ц', "ts"),
+    ('ч', "ch"),
+    ('ш', "sh"),
+    ('щ', "shch"),
+    ('ъ', ""),
+    ('ы', "y"),
+    ('ь', ""),
+    ('э', "e"),
+    ('ю', "yu"),
+    ('я', "ya"),
+    ('і', "i"),
+    ('ї', "yi"),
+    ('є', "ye"),
+    ('ґ', "g"),
+];
+
+/// Normalizer of artist names, so that variants like `The Beatles` and `Beatles, The`,
+/// or names listed in the alias file, can be matched.
+#[derive(Debug, Default)]
+pub struct ArtistCanonicalizer {
+    /// Map from lowercase artist names to the names they are aliases of.
+    aliases: HashMap<String, String>,
+    transliterate: bool,
+}
+
+impl ArtistCanonicalizer {
+    pub fn new(aliases: HashMap<String, String>, transliterate: bool) -> Self {
+        let aliases = aliases
+            .into_iter()
+            .map(|(alias, name)| (alias.to_lowercase(), name))
+            .collect();
+        ArtistCanonicalizer {
+            aliases,
+            transliterate,
+        }
+    }
+
+    /// Read aliases from a file with lines like `Artist A = Artist B`,
+    /// which makes `Artist A` match `Artist B`.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn read_aliases(path: &Path) -> Result<HashMap<String, String>> {
+        let content = fs::read_to_string(path)?;
+        let mut aliases = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (alias, name) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid alias at line {}", i + 1))?;
+            aliases.insert(alias.trim().to_owned(), name.trim().to_owned());
+        }
+        Ok(aliases)
+    }
+
+    pub fn canonicalize(&self, artist: &str) -> String {
+        let artist = artist.trim();
+        let artist = self
+            .aliases
+            .get(&artist.to_lowercase())
+            .map_or(artist, String::as_str)
+            .to_lowercase();
+        let artist = if self.transliterate {
+            artist
+                .chars()
+                .map(
+                    |c| match CYRILLIC.iter().find(|(cyrillic, _)| *cyrillic == c) {
+                        Some((_, latin)) => latin.to_string(),
+                        None => c.to_string(),
+                    },
+                )
+                .collect()
+        } else {
+            artist
+        };
+        strip_article(&artist).to_owned()
+    }
+
+    pub fn key(&self, key: &TrackKey<'_>) -> SanitizedKey {
+        SanitizedKey {
+            name: key.name.to_owned(),
+            artist: key.artist.map(|artist| self.canonicalize(artist)),
+            album: key.album.map(str::to_owned),
+            disc_number: key.disc_number,
+            track_number: key.track_number,
+        }
+    }
+}
+
+/// Strip the leading article, or the article moved to the end, from a lowercase name.
+fn strip_article(name: &str) -> &str {
+    if let Some((rest, article)) = name.rsplit_once(',') {
+        if ARTICLES.contains(&article.trim()) {
+            return rest.trim();
+        }
+    }
+    for article in ARTICLES.iter() {
+        if let Some(rest) = name.strip_prefix(article) {
+            if article.ends_with('\'') {
+                return rest.trim();
+            }
+            if rest.starts_with(' ') {
+                return rest.trim();
+            }
+        }
+    }
+    name
+}
+
+/// Characters of Windows-1252 at bytes 0x80 to 0x9F, where Latin-1 has control characters.
+const WINDOWS_1252: [(char, u8); 27] = [
+    ('€', 0x80),
+    ('‚', 0x82),
+    ('ƒ', 0x83),
+    ('„', 0x84),
+    ('…', 0x85),
+    ('†', 0x86),
+    ('‡', 0x87),
+    ('ˆ', 0x88),
+    ('‰', 0x89),
+    ('Š', 0x8a),
+    ('‹', 0x8b),
+    ('Œ', 0x8c),
+    ('Ž', 0x8e),
+    ('‘', 0x91),
+    ('’', 0x92),
+    ('“', 0x93),
+    ('”', 0x94),
+    ('•', 0x95),
+    ('–', 0x96),
+    ('—', 0x97),
+    ('˜', 0x98),
+    ('™', 0x99),
+    ('š', 0x9a),
+    ('›', 0x9b),
+    ('œ', 0x9c),
+    ('ž', 0x9e),
+    ('Ÿ', 0x9f),
+];
+
+/// Key with mojibake repaired, for names encoded in UTF-8 but decoded as Latin-1,
+/// e.g. `BeyoncÃ©` for `Beyoncé`, as found in tags written by old software.
+pub fn repaired_key(key: &TrackKey<'_>) -> SanitizedKey {
+    SanitizedKey {
+        name: repair_mojibake(key.name).into_owned(),
+        artist: key
+            .artist
+            .map(|artist| repair_mojibake(artist).into_owned()),
+        album: key.album.map(|album| repair_mojibake(album).into_owned()),
+        disc_number: key.disc_number,
+        track_number: key.track_number,
+    }
+}
+
+/// Undo decoding UTF-8 text as Latin-1 or Windows-1252, possibly twice.
+///
+/// Text is left as is unless all of its characters map to bytes forming valid UTF-8,
+/// which is unlikely for text not being mojibake.
+pub fn repair_mojibake(text: &str) -> Cow<'_, str> {
+    let mut text = Cow::Borrowed(text);
+    for _ in 0..2 {
+        if text.is_ascii() {
+            break;
+        }
+        let bytes = text
+            .chars()
+            .map(|c| match u8::try_from(u32::from(c)) {
+                Ok(byte) => Some(byte),
+                Err(_) => WINDOWS_1252
+                    .iter()
+                    .find(|&&(windows_char, _)| windows_char == c)
+                    .map(|&(_, byte)| byte),
+            })
+            .collect::<Option<Vec<_>>>();
+        match bytes.and_then(|bytes| String::from_utf8(bytes).ok()) {
+            Some(repaired) => text = Cow::Owned(repaired),
+            None => break,
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repairs_mojibake() {
+        assert_eq!(repair_mojibake("BeyoncÃ©"), "Beyoncé");
+        assert_eq!(repair_mojibake("Ð\u{9f}Ð¸ÐºÐ½Ð¸Ðº"), "Пикник");
+        assert_eq!(repair_mojibake("Donâ€™t Stop"), "Don’t Stop");
+    }
+
+    #[test]
+    fn repairs_mojibake_decoded_twice() {
+        assert_eq!(repair_mojibake("BeyoncÃƒÂ©"), "Beyoncé");
+    }
+
+    #[test]
+    fn keeps_text_without_mojibake() {
+        for text in ["Beatles", "Beyoncé", "Ångström", "ビートルズ", "Don’t Stop"] {
+            assert!(
+                matches!(repair_mojibake(text), Cow::Borrowed(s) if s == text),
+                "{} is changed",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn canonicalizes_articles() {
+        let canonicalizer = ArtistCanonicalizer::default();
+        for artist in ["The Beatles", "Beatles, The", " beatles "] {
+            assert_eq!(canonicalizer.canonicalize(artist), "beatles", "{}", artist);
+        }
+        assert_eq!(canonicalizer.canonicalize("L'Arc~en~Ciel"), "arc~en~ciel");
+        assert_eq!(canonicalizer.canonicalize("Die Ärzte"), "ärzte");
+        assert_eq!(
+            canonicalizer.canonicalize("Theatre of Tragedy"),
+            "theatre of tragedy"
+        );
+        assert_eq!(canonicalizer.canonicalize("A-ha"), "a-ha");
+    }
+
+    #[test]
+    fn canonicalizes_aliases() {
+        let aliases = HashMap::from([("Prince Rogers Nelson".to_owned(), "The Prince".to_owned())]);
+        let canonicalizer = ArtistCanonicalizer::new(aliases, false);
+        assert_eq!(canonicalizer.canonicalize("PRINCE ROGERS NELSON"), "prince");
+        assert_eq!(canonicalizer.canonicalize("Prince"), "prince");
+    }
+
+    #[test]
+    fn transliterates_cyrillic_only() {
+        let canonicalizer = ArtistCanonicalizer::new(HashMap::new(), true);
+        assert_eq!(canonicalizer.canonicalize("Кино"), "kino");
+        assert_eq!(canonicalizer.canonicalize("Щедрин"), "shchedrin");
+        assert_eq!(canonicalizer.canonicalize("ビートルズ"), "ビートルズ");
+        let canonicalizer = ArtistCanonicalizer::default();
+        assert_eq!(canonicalizer.canonicalize("Кино"), "кино");
+    }
+
+    #[test]
+    fn reads_aliases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aliases.txt");
+        fs::write(
+            &path,
+            "# comment\n\n Prince Rogers Nelson = Prince \nA=B=C\n",
+        )
+        .unwrap();
+        let aliases = ArtistCanonicalizer::read_aliases(&path).unwrap();
+        assert_eq!(
+            aliases,
+            HashMap::from([
+                ("Prince Rogers Nelson".to_owned(), "Prince".to_owned()),
+                ("A".to_owned(), "B=C".to_owned()),
+            ])
+        );
+        fs::write(&path, "A = B\nC\n").unwrap();
+        let error = ArtistCanonicalizer::read_aliases(&path).unwrap_err();
+        assert_eq!(error.to_string(), "invalid alias at line 2");
+    }
+}
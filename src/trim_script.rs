@@ -0,0 +1,70 @@
+use crate::location;
+use crate::rhythmdb::MatchedEntry;
+use crate::shell::quote;
+use crate::xml::{self, StagedFile};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+
+const HEADER: &str = r#"#!/bin/sh
+# Trim files of songs with start or stop time in iTunes, which Rhythmbox doesn't keep.
+# Each file is replaced by its trimmed copy, so review this and keep a backup before running.
+set -e
+
+trim() {
+    tmp="$1.trimmed.${1##*.}"
+    ffmpeg -nostdin -loglevel error -i "$1" -ss "$2" ${3:+-to "$3"} -map 0 -c copy "$tmp"
+    mv "$tmp" "$1"
+}
+"#;
+
+/// Write a shell script which trims files of matched songs like their iTunes tracks via `ffmpeg`
+/// into a temporary file, which replaces the file at the path when committed.
+pub fn stage_trim_script(path: &Path, matched_entries: &[MatchedEntry<'_>]) -> Result<StagedFile> {
+    info!("Writing trim script to {}...", path.display());
+    let mut trimmed_entries = matched_entries
+        .iter()
+        .filter(|matched| matched.track.is_trimmed())
+        .collect::<Vec<_>>();
+    trimmed_entries.sort_by(|a, b| a.location.cmp(&b.location));
+    let mut script = HEADER.to_owned();
+    let mut count = 0;
+    for matched in trimmed_entries {
+        let file = match location::uri_to_path(&matched.location) {
+            Some(file) => file,
+            None => {
+                warn!("{} is not a local file, not trimmed", matched.location);
+                continue;
+            }
+        };
+        let path = match file.to_str() {
+            Some(path) => path,
+            None => {
+                warn!("{} has a name not in UTF-8, not trimmed", file.display());
+                continue;
+            }
+        };
+        let seconds = |ms: u64| format!("{}.{:03}", ms / 1000, ms % 1000);
+        let track = matched.track;
+        writeln!(script).unwrap();
+        writeln!(script, "# {}", track.name.replace('\n', " ")).unwrap();
+        writeln!(
+            script,
+            "trim {} {} {}",
+            quote(path),
+            seconds(track.start_time.unwrap_or(0)),
+            track
+                .stop_time
+                .map(seconds)
+                .unwrap_or_else(|| "''".to_owned()),
+        )
+        .unwrap();
+        count += 1;
+    }
+    let staged = xml::stage(path, |writer| Ok(writer.write_all(script.as_bytes())?))
+        .context("failed to write trim script")?;
+    info!("{} files to trim", count);
+    Ok(staged)
+}
@@ -0,0 +1,122 @@
+use crate::event::Event;
+use crate::itunes_library::{ItunesLibrary, TrackId};
+use crate::playlists::{self, PlaylistFilter};
+use crate::rhythmdb::{migrated_fields, DatabaseMatch, SyncOptions};
+use crate::track_key::TrackKey;
+use elementtree::Element;
+use log::{info, Level};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Check that the matched Rhythmbox entries carry the values from iTunes.
+///
+/// Returns the number of mismatching fields.
+pub fn verify_database(
+    rhythmdb: &Element,
+    database_match: &DatabaseMatch<'_>,
+    options: &SyncOptions,
+) -> usize {
+    info!("Verifying Rhythmbox database...");
+    let mut mismatch_count = 0;
+    for matched in database_match.entries.iter() {
+        let entry = rhythmdb.get_child(matched.index).unwrap();
+        for (tag, expected) in migrated_fields(matched.track, database_match.schema, options) {
+            let actual = entry.find(tag).map(Element::text);
+            let kept =
+                actual.is_some_and(|actual| options.conflict_policy.may_keep(actual, &expected));
+            if actual != Some(expected.as_str()) && !kept {
+                Event::FieldMismatch {
+                    song: TrackKey::from(matched.track),
+                    field: tag,
+                    expected: &expected,
+                    actual,
+                }
+                .log(Level::Warn);
+                mismatch_count += 1;
+            }
+        }
+    }
+    mismatch_count
+}
+
+/// Check that static iTunes playlists exist in Rhythmbox with the expected items,
+/// and that all locations referenced by Rhythmbox playlists exist in the database.
+///
+/// Playlists and their items are expected to have been migrated according to the filter,
+/// with `migrated_playlists` being names of static playlists created by migration.
+/// Returns the number of problems found.
+pub fn verify_playlists(
+    playlists: &Element,
+    rhythmdb: &Element,
+    itunes_library: &ItunesLibrary,
+    track_locations: &HashMap<TrackId, String>,
+    filter: &PlaylistFilter,
+    migrated_playlists: &BTreeSet<String>,
+) -> usize {
+    info!("Verifying Rhythmbox playlists...");
+    let mut problem_count = 0;
+    let rhythmbox_playlists = playlists
+        .find_all("playlist")
+        .filter(|playlist| playlist.get_attr("type") == Some("static"))
+        .filter_map(|playlist| Some((playlist.get_attr("name")?, playlist)))
+        .collect::<HashMap<_, _>>();
+    let queue = playlists
+        .find_all("playlist")
+        .find(|playlist| playlist.get_attr("type") == Some("queue"));
+    let target_names =
+        playlists::target_names(playlists, itunes_library, filter, migrated_playlists);
+    for playlist in itunes_library.playlists.iter() {
+        // Smart playlists and those filtered out are not migrated.
+        let target_name = match target_names.get(playlist.name.as_str()) {
+            Some(target_name) => target_name.as_str(),
+            None => continue,
+        };
+        let rhythmbox_playlist = if filter.queue_playlist.as_ref() == Some(&playlist.name) {
+            queue
+        } else {
+            rhythmbox_playlists.get(target_name).copied()
+        };
+        let rhythmbox_playlist = match rhythmbox_playlist {
+            Some(rhythmbox_playlist) => rhythmbox_playlist,
+            None => {
+                Event::PlaylistMissing {
+                    playlist: &playlist.name,
+                }
+                .log(Level::Warn);
+                problem_count += 1;
+                continue;
+            }
+        };
+        let expected = playlists::migrated_items(playlist, itunes_library, track_locations, filter)
+            .locations
+            .len();
+        let actual = rhythmbox_playlist.find_all("location").count();
+        if expected != actual {
+            Event::PlaylistItemCountMismatch {
+                playlist: &playlist.name,
+                expected,
+                actual,
+            }
+            .log(Level::Warn);
+            problem_count += 1;
+        }
+    }
+
+    let database_locations = rhythmdb
+        .children()
+        .filter_map(|entry| entry.find("location"))
+        .map(Element::text)
+        .collect::<HashSet<_>>();
+    for (name, playlist) in rhythmbox_playlists {
+        for location in playlist.find_all("location").map(Element::text) {
+            if !database_locations.contains(location) {
+                Event::DanglingLocation {
+                    playlist: name,
+                    location,
+                }
+                .log(Level::Warn);
+                problem_count += 1;
+            }
+        }
+    }
+    problem_count
+}
@@ -0,0 +1,408 @@
+use anyhow::{Context, Result};
+use elementtree::{Element, WriteOptions, XmlProlog};
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Write the element into the file.
+///
+/// The content is written into a temporary file which then replaces the file,
+/// so that an interruption never leaves the file partially written.
+pub fn write_xml(path: &Path, element: &Element) -> Result<()> {
+    stage_xml(path, element)?.commit()
+}
+
+/// Write the element into a temporary file like [`write_xml`],
+/// which replaces the file only when committed.
+pub fn stage_xml(path: &Path, element: &Element) -> Result<StagedFile> {
+    stage(path, |writer| {
+        let options = WriteOptions::new().set_xml_prolog(Some(XmlProlog::Version10));
+        element.to_writer_with_options(writer, options)?;
+        Ok(())
+    })
+}
+
+/// Write the element into the file like [`write_xml`],
+/// but keep the original text of children of the root which are unchanged,
+/// so that the file only differs where the content does,
+/// and entities and CDATA sections of untouched children are preserved.
+/// Comments before changed children are kept as well.
+pub fn write_xml_preserving(path: &Path, element: &Element, original: &OriginalXml) -> Result<()> {
+    stage_xml_preserving(path, element, original)?.commit()
+}
+
+/// Write the element into a temporary file like [`write_xml_preserving`],
+/// which replaces the file only when committed.
+pub fn stage_xml_preserving(
+    path: &Path,
+    element: &Element,
+    original: &OriginalXml,
+) -> Result<StagedFile> {
+    let layout = match &original.layout {
+        Some(layout) if layout.root_attrs == attrs(element) => layout,
+        _ => return stage_xml(path, element),
+    };
+    let text = original.text.as_bytes();
+    let serialized = element
+        .children()
+        .map(serialize)
+        .collect::<Result<Vec<_>>>()?;
+    let mut unchanged = layout.unchanged.clone();
+    let kept = serialized
+        .iter()
+        .map(|serialized| unchanged.get_mut(serialized).and_then(VecDeque::pop_front))
+        .collect::<Vec<_>>();
+    // Original children not kept as is, whose leading text goes with the rewritten children.
+    let mut replaced = vec![true; layout.children.len()];
+    for &index in kept.iter().flatten() {
+        replaced[index] = false;
+    }
+    let mut rewritten_count = 0;
+    let staged = stage(path, |writer| {
+        writer.write_all(&text[..layout.root_start_end])?;
+        let mut next = 0;
+        for (serialized, kept) in serialized.iter().zip(kept) {
+            match kept {
+                Some(index) => {
+                    let child = &layout.children[index];
+                    writer.write_all(&text[child.leading.start..child.end])?;
+                    next = index + 1;
+                }
+                None => {
+                    // Keep the text before the child replaced, e.g. a comment about it.
+                    match (next..replaced.len()).find(|&index| replaced[index]) {
+                        Some(index) => {
+                            replaced[index] = false;
+                            writer.write_all(&text[layout.children[index].leading.clone()])?;
+                            next = index + 1;
+                        }
+                        None => writer.write_all(layout.indent.as_bytes())?,
+                    }
+                    writer.write_all(serialized.as_bytes())?;
+                    rewritten_count += 1;
+                }
+            }
+        }
+        writer.write_all(&text[layout.last_child_end..])?;
+        Ok(())
+    })?;
+    debug!(
+        "{} elements rewritten in {}",
+        rewritten_count,
+        path.display()
+    );
+    Ok(staged)
+}
+
+/// Write the file via a temporary file, so that it's never left partially written.
+pub fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    stage(path, write)?.commit()
+}
+
+/// Write the content of the file into a temporary file next to it,
+/// which replaces the file only when committed.
+pub fn stage(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<StagedFile> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let staged = StagedFile {
+        path: path.to_owned(),
+        temp_path: path.with_file_name(temp_name),
+        committed: false,
+    };
+    let file = File::create(&staged.temp_path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    writer.flush()?;
+    Ok(staged)
+}
+
+/// File written into a temporary file, which replaces the file when committed,
+/// so that several files can be written before any of them is replaced.
+///
+/// The temporary file is removed if the staged file is dropped without being committed.
+#[must_use = "the file is only replaced when committed"]
+pub struct StagedFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    pub fn commit(mut self) -> Result<()> {
+        fs::rename(&self.temp_path, &self.path)
+            .with_context(|| format!("failed to replace {}", self.path.display()))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        // Don't leave the partially written file around.
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Original text of an XML file, for writing the file back with minimal changes.
+pub struct OriginalXml {
+    text: String,
+    /// Layout of the text, if it can be understood.
+    layout: Option<Layout>,
+}
+
+struct Layout {
+    root_attrs: Vec<(String, String)>,
+    /// End of the start tag of the root.
+    root_start_end: usize,
+    /// End of the last child of the root, or of the start tag if there is no child.
+    last_child_end: usize,
+    /// Whitespace put before each child of the root.
+    indent: String,
+    children: Vec<ChildSpan>,
+    /// Indices of children of the root keyed by their serialization.
+    unchanged: HashMap<String, VecDeque<usize>>,
+}
+
+/// Span of a child of the root in the original text.
+struct ChildSpan {
+    /// Text before the child since the previous one, e.g. whitespace and comments.
+    leading: Range<usize>,
+    /// End of the child.
+    end: usize,
+}
+
+impl OriginalXml {
+    /// Record the original text of the element parsed from it.
+    ///
+    /// This must be done before the element is modified.
+    pub fn new(text: String, element: &Element) -> Self {
+        let layout = Layout::new(&text, element);
+        if layout.is_none() {
+            debug!("unrecognized XML layout, the whole file will be rewritten");
+        }
+        OriginalXml { text, layout }
+    }
+}
+
+impl Layout {
+    fn new(text: &str, element: &Element) -> Option<Self> {
+        let (root_start_end, spans) = scan_children(text)?;
+        if spans.len() != element.child_count() {
+            return None;
+        }
+        let mut children = Vec::with_capacity(spans.len());
+        let mut unchanged = HashMap::<_, VecDeque<_>>::with_capacity(spans.len());
+        let mut previous_end = root_start_end;
+        for (index, (child, span)) in element.children().zip(spans.iter()).enumerate() {
+            let serialized = serialize(child).ok()?;
+            unchanged.entry(serialized).or_default().push_back(index);
+            children.push(ChildSpan {
+                leading: previous_end..span.start,
+                end: span.end,
+            });
+            previous_end = span.end;
+        }
+        let indent = spans
+            .first()
+            .map(|span| &text[root_start_end..span.start])
+            .filter(|indent| indent.trim().is_empty())
+            .unwrap_or("\n  ");
+        Some(Layout {
+            root_attrs: attrs(element),
+            root_start_end,
+            last_child_end: previous_end,
+            indent: indent.to_owned(),
+            children,
+            unchanged,
+        })
+    }
+}
+
+fn attrs(element: &Element) -> Vec<(String, String)> {
+    element
+        .attrs()
+        .map(|(name, value)| (name.to_string(), value.to_owned()))
+        .collect()
+}
+
+/// Serialize the element alone, without its tail.
+fn serialize(element: &Element) -> Result<String> {
+    let mut buf = Vec::new();
+    element.to_writer_with_options(&mut buf, WriteOptions::new().set_xml_prolog(None))?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Find the end of the start tag of the root, and spans of children of the root.
+///
+/// Only elements are counted as children, while comments, processing instructions,
+/// and text between them are kept as part of the text before the next child.
+fn scan_children(text: &str) -> Option<(usize, Vec<Range<usize>>)> {
+    let bytes = text.as_bytes();
+    let find = |from: usize, pattern: &str| {
+        text[from..]
+            .find(pattern)
+            .map(|offset| from + offset + pattern.len())
+    };
+    let mut pos = 0;
+    let mut depth = 0_usize;
+    let mut root_start_end = None;
+    let mut child_start = 0;
+    let mut spans = Vec::new();
+    while let Some(offset) = text[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &text[start..];
+        if rest.starts_with("<?") {
+            pos = find(start, "?>")?;
+        } else if rest.starts_with("<!--") {
+            pos = find(start, "-->")?;
+        } else if rest.starts_with("<![CDATA[") {
+            pos = find(start, "]]>")?;
+        } else if rest.starts_with("<!") {
+            // Document type declarations with internal subsets are not supported.
+            let end = find(start, ">")?;
+            if text[start..end].contains('[') {
+                return None;
+            }
+            pos = end;
+        } else if rest.starts_with("</") {
+            pos = find(start, ">")?;
+            depth = depth.checked_sub(1)?;
+            match depth {
+                0 => return Some((root_start_end?, spans)),
+                1 => spans.push(child_start..pos),
+                _ => {}
+            }
+        } else {
+            // Find the end of the tag, skipping `>` in quoted attribute values.
+            let mut quote = None;
+            let mut end = start + 1;
+            loop {
+                match (bytes.get(end)?, quote) {
+                    (b'>', None) => break,
+                    (&c @ (b'"' | b'\''), None) => quote = Some(c),
+                    (&c, Some(q)) if c == q => quote = None,
+                    _ => {}
+                }
+                end += 1;
+            }
+            pos = end + 1;
+            let self_closing = bytes[end - 1] == b'/';
+            match depth {
+                0 if self_closing => return None,
+                0 => root_start_end = Some(pos),
+                1 if self_closing => spans.push(start..pos),
+                1 => child_start = start,
+                _ => {}
+            }
+            if !self_closing {
+                depth += 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "<?xml version=\"1.0\" standalone=\"yes\"?>\n\
+        <!-- comment -->\n\
+        <rhythmdb version=\"2.0\">\n  \
+        <entry type=\"song\"><title>Caf&#233; &amp; Bar</title></entry>\n  \
+        <!-- between -->\n  \
+        <entry type=\"song\"><title><![CDATA[<Raw>]]></title></entry>\n    \
+        <entry type=\"iradio\"><title>Radio</title></entry>\n\
+        </rhythmdb>\n";
+
+    /// Parse the text, modify the element, and write it back preserving the text.
+    fn rewrite(text: &str, modify: impl FnOnce(&mut Element)) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rhythmdb.xml");
+        let mut element = Element::from_reader(text.as_bytes()).unwrap();
+        let original = OriginalXml::new(text.to_owned(), &element);
+        modify(&mut element);
+        write_xml_preserving(&path, &element, &original).unwrap();
+        fs::read_to_string(&path).unwrap()
+    }
+
+    fn title(element: &mut Element, index: usize) -> &mut Element {
+        element
+            .get_child_mut(index)
+            .unwrap()
+            .find_mut("title")
+            .unwrap()
+    }
+
+    #[test]
+    fn keeps_unchanged_text() {
+        assert_eq!(rewrite(TEXT, |_| {}), TEXT);
+    }
+
+    #[test]
+    fn rewrites_changed_children_only() {
+        let result = rewrite(TEXT, |element| {
+            title(element, 1).set_text("Changed");
+        });
+        let expected = TEXT.replace("<title><![CDATA[<Raw>]]></title>", "<title>Changed</title>");
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn rewrites_moved_and_added_children() {
+        let mut added = Element::new("entry");
+        added.set_attr("type", "podcast-feed");
+        let serialized = serialize(&added).unwrap();
+        let result = rewrite(TEXT, |element| {
+            let first = element.remove_child(0).unwrap();
+            element.append_child(first);
+            element.append_child(added);
+        });
+        let expected = TEXT
+            .replace("\n  <entry type=\"song\"><title>Caf&#233; &amp; Bar</title></entry>", "")
+            .replace(
+                "\n</rhythmdb>",
+                &format!(
+                    "\n  <entry type=\"song\"><title>Caf&#233; &amp; Bar</title></entry>\n  {}\n</rhythmdb>",
+                    serialized
+                ),
+            );
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn rewrites_whole_file_when_root_changes() {
+        let result = rewrite(TEXT, |element| {
+            element.set_attr("version", "2.1");
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rhythmdb.xml");
+        let mut element = Element::from_reader(TEXT.as_bytes()).unwrap();
+        element.set_attr("version", "2.1");
+        write_xml(&path, &element).unwrap();
+        assert_eq!(result, fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn rewrites_whole_file_with_unsupported_layout() {
+        let text = TEXT.replace(
+            "<!-- comment -->",
+            "<!DOCTYPE rhythmdb [<!ENTITY x \"y\">]>",
+        );
+        let element = Element::from_reader(text.as_bytes()).unwrap();
+        assert!(OriginalXml::new(text.clone(), &element).layout.is_none());
+        assert!(!rewrite(&text, |_| {}).contains("<!DOCTYPE"));
+    }
+}
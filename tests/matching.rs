@@ -0,0 +1,117 @@
+use migrate_itunes_to_rhythmbox::itunes_library::{ItunesLibrary, Track};
+use migrate_itunes_to_rhythmbox::matching::{self, EntryMetadata, TrackMap};
+use migrate_itunes_to_rhythmbox::track_key::ArtistCanonicalizer;
+
+/// Build an iTunes library with tracks of the given string fields, with IDs from 1.
+fn library(tracks: &[&[(&str, &str)]]) -> ItunesLibrary {
+    let tracks = tracks
+        .iter()
+        .enumerate()
+        .map(|(i, fields)| {
+            let fields = fields
+                .iter()
+                .map(|(key, value)| format!("<key>{}</key><string>{}</string>", key, value))
+                .collect::<String>();
+            format!(
+                "<key>{id}</key><dict>\
+                 <key>Track ID</key><integer>{id}</integer>{fields}\
+                 <key>Date Modified</key><date>2019-01-01T00:00:00Z</date>\
+                 <key>Date Added</key><date>2019-01-01T00:00:00Z</date>\
+                 </dict>",
+                id = i + 1,
+                fields = fields,
+            )
+        })
+        .collect::<String>();
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <plist version=\"1.0\"><dict>\
+         <key>Tracks</key><dict>{}</dict>\
+         <key>Playlists</key><array/>\
+         </dict></plist>",
+        tracks,
+    );
+    plist::from_bytes(xml.as_bytes()).unwrap()
+}
+
+fn entry(name: &str, artist: &str) -> EntryMetadata {
+    EntryMetadata {
+        name: name.to_owned(),
+        artist: Some(artist.to_owned()),
+        album: None,
+        disc_number: None,
+        track_number: None,
+        location: format!("file:///home/me/{} - {}.mp3", artist, name),
+        duration: None,
+    }
+}
+
+/// Match the entries exactly and then with the given function,
+/// returning the IDs of the matched tracks.
+fn match_with<'a>(
+    library: &'a ItunesLibrary,
+    entries: &[EntryMetadata],
+    match_more: impl FnOnce(&[EntryMetadata], &mut [Option<&'a Track>], &TrackMap<'a>),
+) -> Vec<Option<u64>> {
+    let track_map = matching::build_track_map(library).unwrap();
+    let mut matched_tracks = matching::match_entries(entries, &track_map);
+    match_more(entries, &mut matched_tracks, &track_map);
+    matched_tracks
+        .iter()
+        .map(|track| track.map(|track| track.id.0))
+        .collect()
+}
+
+fn match_canonical(library: &ItunesLibrary, entries: &[EntryMetadata]) -> Vec<Option<u64>> {
+    match_with(library, entries, |entries, matched_tracks, track_map| {
+        let canonicalizer = ArtistCanonicalizer::default();
+        matching::match_canonical(entries, matched_tracks, track_map, &canonicalizer);
+    })
+}
+
+fn match_works(library: &ItunesLibrary, entries: &[EntryMetadata]) -> Vec<Option<u64>> {
+    match_with(library, entries, matching::match_works)
+}
+
+const BEATLES: &[(&str, &str)] = &[("Name", "Help!"), ("Artist", "The Beatles")];
+
+const MOVEMENT: &[(&str, &str)] = &[
+    ("Name", "Symphony No. 5: Allegro con brio"),
+    ("Artist", "Beethoven"),
+    ("Work", "Symphony No. 5"),
+    ("Movement Name", "Allegro con brio"),
+];
+
+#[test]
+fn matches_canonical_artist() {
+    let library = library(&[BEATLES]);
+    let entries = [entry("Help!", "Beatles, The")];
+    assert_eq!(match_canonical(&library, &entries), [Some(1)]);
+}
+
+#[test]
+fn keeps_exactly_matched_track_from_canonical_match() {
+    let library = library(&[BEATLES]);
+    let entries = [
+        entry("Help!", "Beatles, The"),
+        entry("Help!", "The Beatles"),
+    ];
+    assert_eq!(match_canonical(&library, &entries), [None, Some(1)]);
+}
+
+#[test]
+fn matches_movement_name() {
+    let library = library(&[MOVEMENT]);
+    let entries = [entry("Allegro con brio", "Beethoven")];
+    assert_eq!(match_works(&library, &entries), [Some(1)]);
+}
+
+#[test]
+fn keeps_exactly_matched_track_from_work_match() {
+    let library = library(&[MOVEMENT]);
+    let entries = [
+        entry("Allegro con brio", "Beethoven"),
+        entry("Symphony No. 5: Allegro con brio", "Beethoven"),
+    ];
+    assert_eq!(match_works(&library, &entries), [None, Some(1)]);
+}
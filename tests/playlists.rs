@@ -0,0 +1,309 @@
+use elementtree::Element;
+use glob::Pattern;
+use migrate_itunes_to_rhythmbox::itunes_library::{ItunesLibrary, TrackId};
+use migrate_itunes_to_rhythmbox::playlists::{self, DedupeMode, PlaylistFilter};
+use migrate_itunes_to_rhythmbox::verify;
+use std::collections::{BTreeSet, HashMap};
+
+const NAMES: [&str; 5] = ["Delta", "Alpha", "Charlie", "Bravo", "apple"];
+
+/// Build an iTunes library with tracks 1 to 5 and a playlist with the given items.
+fn library(items: &[u64], sort_field: Option<&str>) -> ItunesLibrary {
+    let tracks = NAMES
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            format!(
+                "<key>{id}</key><dict>\
+                 <key>Track ID</key><integer>{id}</integer>\
+                 <key>Name</key><string>{name}</string>\
+                 <key>Date Modified</key><date>2019-01-01T00:00:00Z</date>\
+                 <key>Date Added</key><date>2019-01-01T00:00:00Z</date>\
+                 <key>Location</key><string>file:///Music/{name}.mp3</string>\
+                 </dict>",
+                id = i + 1,
+                name = name,
+            )
+        })
+        .collect::<String>();
+    let items = items
+        .iter()
+        .map(|id| format!("<dict><key>Track ID</key><integer>{}</integer></dict>", id))
+        .collect::<String>();
+    let sort_field = sort_field
+        .map(|field| format!("<key>Sort Field</key><string>{}</string>", field))
+        .unwrap_or_default();
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <plist version=\"1.0\"><dict>\
+         <key>Tracks</key><dict>{}</dict>\
+         <key>Playlists</key><array><dict>\
+         <key>Name</key><string>Party</string>\
+         <key>Playlist ID</key><integer>1</integer>{}\
+         <key>Playlist Items</key><array>{}</array>\
+         </dict></array>\
+         </dict></plist>",
+        tracks, sort_field, items,
+    );
+    plist::from_bytes(xml.as_bytes()).unwrap()
+}
+
+/// Migrate the playlist with tracks at the given IDs found in Rhythmbox,
+/// returning the names of the migrated items.
+fn migrate(library: &ItunesLibrary, found: &[u64]) -> Vec<String> {
+    migrate_with_filter(library, found, &PlaylistFilter::default())
+}
+
+fn migrate_with_filter(
+    library: &ItunesLibrary,
+    found: &[u64],
+    filter: &PlaylistFilter,
+) -> Vec<String> {
+    let playlists = migrate_into_playlists(library, found, filter);
+    item_names(&playlists, "Party").unwrap()
+}
+
+/// Migrate like [`migrate_with_filter`], returning the Rhythmbox playlists,
+/// which initially have only an empty play queue.
+fn migrate_into_playlists(
+    library: &ItunesLibrary,
+    found: &[u64],
+    filter: &PlaylistFilter,
+) -> Element {
+    let track_locations = found
+        .iter()
+        .map(|&id| (TrackId(id), format!("file:///home/me/{}.mp3", id)))
+        .collect::<HashMap<_, _>>();
+    let mut playlists = Element::from_reader(
+        "<rhythmdb-playlists>\n  <playlist name=\"Play Queue\" type=\"queue\"/>\n</rhythmdb-playlists>"
+            .as_bytes(),
+    )
+    .unwrap();
+    playlists::migrate_playlists(
+        &mut playlists,
+        library,
+        &track_locations,
+        filter,
+        &mut BTreeSet::new(),
+    );
+    playlists
+}
+
+/// Names of items in the Rhythmbox playlist of the given name, if exists.
+fn item_names(playlists: &Element, name: &str) -> Option<Vec<String>> {
+    let playlist = playlists
+        .find_all("playlist")
+        .find(|playlist| playlist.get_attr("name") == Some(name))?;
+    let names = playlist
+        .find_all("location")
+        .map(|location| {
+            let id = location.text()[16..].trim_end_matches(".mp3");
+            NAMES[id.parse::<usize>().unwrap() - 1].to_owned()
+        })
+        .collect();
+    Some(names)
+}
+
+#[test]
+fn keeps_manual_order() {
+    let library = library(&[3, 1, 4, 2], None);
+    let names = migrate(&library, &[1, 2, 3, 4]);
+    assert_eq!(names, ["Charlie", "Delta", "Bravo", "Alpha"]);
+}
+
+#[test]
+fn keeps_repeated_items() {
+    let library = library(&[2, 1, 2, 2, 3], None);
+    let names = migrate(&library, &[1, 2, 3, 4]);
+    assert_eq!(names, ["Alpha", "Delta", "Alpha", "Alpha", "Charlie"]);
+}
+
+#[test]
+fn keeps_order_when_items_are_missing() {
+    let library = library(&[4, 3, 2, 1], None);
+    let names = migrate(&library, &[1, 2, 4]);
+    assert_eq!(names, ["Bravo", "Alpha", "Delta"]);
+}
+
+#[test]
+fn sorts_by_sort_field() {
+    let library = library(&[1, 3, 5, 2, 4], Some("Name"));
+    let names = migrate(&library, &[1, 2, 3, 4, 5]);
+    assert_eq!(names, ["Alpha", "apple", "Bravo", "Charlie", "Delta"]);
+}
+
+#[test]
+fn keeps_manual_order_for_unsupported_sort_field() {
+    let library = library(&[1, 3, 2, 4], Some("Beats Per Minute"));
+    let names = migrate(&library, &[1, 2, 3, 4]);
+    assert_eq!(names, ["Delta", "Charlie", "Alpha", "Bravo"]);
+}
+
+#[test]
+fn removes_consecutive_duplicates() {
+    let library = library(&[2, 2, 1, 2, 3, 3], None);
+    let filter = PlaylistFilter {
+        dedupe_items: Some(DedupeMode::Consecutive),
+        ..PlaylistFilter::default()
+    };
+    let names = migrate_with_filter(&library, &[1, 2, 3, 4], &filter);
+    assert_eq!(names, ["Alpha", "Delta", "Alpha", "Charlie"]);
+}
+
+#[test]
+fn removes_all_duplicates() {
+    let library = library(&[2, 2, 1, 2, 3, 1], None);
+    let filter = PlaylistFilter {
+        dedupe_items: Some(DedupeMode::All),
+        ..PlaylistFilter::default()
+    };
+    let names = migrate_with_filter(&library, &[1, 2, 3, 4], &filter);
+    assert_eq!(names, ["Alpha", "Delta", "Charlie"]);
+}
+
+/// Migrate the playlist with all tracks found in Rhythmbox,
+/// returning the number of problems found when verifying the result.
+fn migrate_and_verify(library: &ItunesLibrary, filter: &PlaylistFilter) -> usize {
+    let found = [1, 2, 3, 4];
+    let playlists = migrate_into_playlists(library, &found, filter);
+    let entries = found
+        .iter()
+        .map(|id| {
+            format!(
+                "<entry><location>file:///home/me/{}.mp3</location></entry>",
+                id
+            )
+        })
+        .collect::<String>();
+    let rhythmdb =
+        Element::from_reader(format!("<rhythmdb>{}</rhythmdb>", entries).as_bytes()).unwrap();
+    let track_locations = found
+        .iter()
+        .map(|&id| (TrackId(id), format!("file:///home/me/{}.mp3", id)))
+        .collect::<HashMap<_, _>>();
+    verify::verify_playlists(
+        &playlists,
+        &rhythmdb,
+        library,
+        &track_locations,
+        filter,
+        &BTreeSet::from(["Party".to_owned()]),
+    )
+}
+
+#[test]
+fn verifies_deduplicated_playlist() {
+    let library = library(&[2, 2, 1, 2, 3, 1], None);
+    let filter = PlaylistFilter {
+        dedupe_items: Some(DedupeMode::All),
+        ..PlaylistFilter::default()
+    };
+    assert_eq!(migrate_and_verify(&library, &filter), 0);
+}
+
+#[test]
+fn verifies_queue_playlist() {
+    let library = library(&[3, 1, 2], None);
+    let filter = PlaylistFilter {
+        queue_playlist: Some("Party".to_owned()),
+        ..PlaylistFilter::default()
+    };
+    assert_eq!(migrate_and_verify(&library, &filter), 0);
+}
+
+#[test]
+fn verifies_without_excluded_playlist() {
+    let library = library(&[3, 1, 2], None);
+    let filter = PlaylistFilter {
+        exclude: vec![Pattern::new("Par*").unwrap()],
+        ..PlaylistFilter::default()
+    };
+    assert_eq!(migrate_and_verify(&library, &filter), 0);
+}
+
+#[test]
+fn migrates_queue_playlist() {
+    let library = library(&[3, 1, 2], None);
+    let filter = PlaylistFilter {
+        queue_playlist: Some("Party".to_owned()),
+        ..PlaylistFilter::default()
+    };
+    let playlists = migrate_into_playlists(&library, &[1, 2, 3, 4], &filter);
+    assert_eq!(playlists.find_all("playlist").count(), 1);
+    let queue = playlists.find("playlist").unwrap();
+    assert_eq!(queue.get_attr("type"), Some("queue"));
+    let names = item_names(&playlists, "Play Queue").unwrap();
+    assert_eq!(names, ["Charlie", "Delta", "Alpha"]);
+}
+
+/// Migrate into Rhythmbox playlists which already have a static playlist `Party` with item 4,
+/// returning the Rhythmbox playlists and the names of those created by migration.
+fn migrate_into_existing(
+    library: &ItunesLibrary,
+    migrated_before: bool,
+) -> (Element, BTreeSet<String>) {
+    let mut playlists = Element::from_reader(
+        "<rhythmdb-playlists>\n  \
+         <playlist name=\"Party\" type=\"static\">\n    \
+         <location>file:///home/me/4.mp3</location>\n  \
+         </playlist>\n\
+         </rhythmdb-playlists>"
+            .as_bytes(),
+    )
+    .unwrap();
+    let mut migrated_playlists = BTreeSet::new();
+    if migrated_before {
+        migrated_playlists.insert("Party".to_owned());
+    }
+    let track_locations = (1..=4)
+        .map(|id| (TrackId(id), format!("file:///home/me/{}.mp3", id)))
+        .collect::<HashMap<_, _>>();
+    playlists::migrate_playlists(
+        &mut playlists,
+        library,
+        &track_locations,
+        &PlaylistFilter::default(),
+        &mut migrated_playlists,
+    );
+    (playlists, migrated_playlists)
+}
+
+#[test]
+fn replaces_playlist_migrated_before() {
+    let library = library(&[3, 1], None);
+    let (playlists, migrated_playlists) = migrate_into_existing(&library, true);
+    assert_eq!(playlists.find_all("playlist").count(), 1);
+    let names = item_names(&playlists, "Party").unwrap();
+    assert_eq!(names, ["Charlie", "Delta"]);
+    assert_eq!(migrated_playlists, BTreeSet::from(["Party".to_owned()]));
+}
+
+#[test]
+fn keeps_playlist_created_in_rhythmbox() {
+    let library = library(&[3, 1], None);
+    let (playlists, migrated_playlists) = migrate_into_existing(&library, false);
+    assert_eq!(playlists.find_all("playlist").count(), 2);
+    assert_eq!(item_names(&playlists, "Party").unwrap(), ["Bravo"]);
+    let names = item_names(&playlists, "Party (iTunes)").unwrap();
+    assert_eq!(names, ["Charlie", "Delta"]);
+    assert_eq!(
+        migrated_playlists,
+        BTreeSet::from(["Party (iTunes)".to_owned()])
+    );
+
+    // Migrating again replaces only the migrated playlist.
+    let mut playlists = playlists;
+    let mut migrated_playlists = migrated_playlists;
+    let track_locations = HashMap::from([(TrackId(2), "file:///home/me/2.mp3".to_owned())]);
+    let library = self::library(&[2], None);
+    playlists::migrate_playlists(
+        &mut playlists,
+        &library,
+        &track_locations,
+        &PlaylistFilter::default(),
+        &mut migrated_playlists,
+    );
+    assert_eq!(playlists.find_all("playlist").count(), 2);
+    assert_eq!(item_names(&playlists, "Party").unwrap(), ["Bravo"]);
+    assert_eq!(item_names(&playlists, "Party (iTunes)").unwrap(), ["Alpha"]);
+}